@@ -0,0 +1,211 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::io;
+use std::io::{Read, Write};
+
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const NONCE_SIZE: usize = 32;
+pub const TAG_SIZE: usize = 32;
+
+// Which end of a connection a session belongs to, each tags what it sends with its own label.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Role {
+	Client,
+	Server,
+}
+
+// A connection between two drones that proved to each other they know the swarm secret. The handshake
+// is a challenge/response in both directions:
+//
+//   server -> client   server nonce
+//   client -> server   client nonce, HMAC(secret, "client" | server nonce | client nonce)
+//   server -> client   HMAC(secret, "server" | client nonce | server nonce)
+//
+// Everything sent afterwards is prefixed with an HMAC over both nonces and the data (see seal), so
+// messages can't be forged, altered or replayed on another connection.
+pub struct Session {
+	client_nonce:					[u8; NONCE_SIZE],
+	role:							Role,
+	secret:							Vec<u8>,
+	server_nonce:					[u8; NONCE_SIZE],
+}
+
+impl Session {
+	// Authenticate to the drone at the other end of a freshly opened connection, and check that it
+	// knows the secret too.
+	pub fn client<S: Read + Write>(stream: &mut S, secret: &[u8]) -> io::Result<Self> {
+		let mut server_nonce = [0; NONCE_SIZE];
+		stream.read_exact(&mut server_nonce)?;
+
+		let session = Session {
+			client_nonce: nonce()?,
+			role: Role::Client,
+			secret: secret.to_vec(),
+			server_nonce,
+		};
+
+		let mut hello = session.client_nonce.to_vec();
+		hello.extend_from_slice(&session.tag(&session.hello_parts(Role::Client)));
+		stream.write_all(&hello)?;
+		stream.flush()?;
+
+		// A server that rejects the client closes the connection instead of answering.
+		let mut tag = [0; TAG_SIZE];
+		stream.read_exact(&mut tag).map_err(|err| match err.kind() {
+			io::ErrorKind::UnexpectedEof => denied("the peer closed the connection, it does not share the swarm secret"),
+			_ => err,
+		})?;
+		if !session.verify(&session.hello_parts(Role::Server), &tag) {
+			return Err(denied("the peer does not know the swarm secret"));
+		}
+
+		Ok(session)
+	}
+
+	// Challenge a drone that connected to this one, fails unless it knows the secret.
+	pub fn server<S: Read + Write>(stream: &mut S, secret: &[u8]) -> io::Result<Self> {
+		let server_nonce = nonce()?;
+		stream.write_all(&server_nonce)?;
+		stream.flush()?;
+
+		let mut hello = [0; NONCE_SIZE + TAG_SIZE];
+		stream.read_exact(&mut hello)?;
+
+		let mut client_nonce = [0; NONCE_SIZE];
+		client_nonce.copy_from_slice(&hello[..NONCE_SIZE]);
+
+		let session = Session {
+			client_nonce,
+			role: Role::Server,
+			secret: secret.to_vec(),
+			server_nonce,
+		};
+		if !session.verify(&session.hello_parts(Role::Client), &hello[NONCE_SIZE..]) {
+			return Err(denied("the peer does not know the swarm secret"));
+		}
+
+		stream.write_all(&session.tag(&session.hello_parts(Role::Server)))?;
+		stream.flush()?;
+
+		Ok(session)
+	}
+
+	// Data to send to the other end: its tag followed by the data.
+	pub fn seal(&self, data: &[u8]) -> Vec<u8> {
+		let mut sealed = self.tag(&self.data_parts(self.role, data)).to_vec();
+		sealed.extend_from_slice(data);
+
+		sealed
+	}
+
+	// The data in what the other end sent, fails if the tag doesn't match.
+	pub fn open<'a>(&self, sealed: &'a [u8]) -> io::Result<&'a [u8]> {
+		if sealed.len() < TAG_SIZE {
+			return Err(denied("the message carries no tag"));
+		}
+
+		let (tag, data) = sealed.split_at(TAG_SIZE);
+		let peer = if self.role == Role::Client { Role::Server } else { Role::Client };
+		if !self.verify(&self.data_parts(peer, data), tag) {
+			return Err(denied("the message tag does not match"));
+		}
+
+		Ok(data)
+	}
+
+	// What a side's handshake tag covers: the other side's challenge first.
+	fn hello_parts(&self, role: Role) -> [&[u8]; 3] {
+		match role {
+			Role::Client => [b"client", &self.server_nonce, &self.client_nonce],
+			Role::Server => [b"server", &self.client_nonce, &self.server_nonce],
+		}
+	}
+
+	fn data_parts<'a>(&'a self, role: Role, data: &'a [u8]) -> [&'a [u8]; 4] {
+		let label: &[u8] = if role == Role::Client { b"client data" } else { b"server data" };
+
+		[label, &self.client_nonce, &self.server_nonce, data]
+	}
+
+	fn tag(&self, parts: &[&[u8]]) -> [u8; TAG_SIZE] {
+		let mut tag = [0; TAG_SIZE];
+		tag.copy_from_slice(&self.mac(parts).finalize().into_bytes());
+
+		tag
+	}
+
+	// Compares in constant time.
+	fn verify(&self, parts: &[&[u8]], tag: &[u8]) -> bool {
+		self.mac(parts).verify_slice(tag).is_ok()
+	}
+
+	fn mac(&self, parts: &[&[u8]]) -> HmacSha256 {
+		// HMAC takes keys of any length.
+		let mut mac = HmacSha256::new_from_slice(&self.secret).unwrap();
+		for part in parts.iter() {
+			mac.update(part);
+		}
+
+		mac
+	}
+}
+
+fn denied(reason: &str) -> io::Error {
+	io::Error::new(io::ErrorKind::PermissionDenied, reason)
+}
+
+fn nonce() -> io::Result<[u8; NONCE_SIZE]> {
+	let mut nonce = [0; NONCE_SIZE];
+	getrandom::getrandom(&mut nonce).map_err(|err| io::Error::other(err.to_string()))?;
+
+	Ok(nonce)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::os::unix::net::UnixStream;
+	use std::thread;
+
+	#[test]
+	fn auth_handshake_test() {
+		// Both ends know the secret: data sealed by one opens at the other, in both directions.
+		let (mut client, mut server) = UnixStream::pair().unwrap();
+		let handle = thread::spawn(move || {
+			let session = Session::server(&mut server, b"secret").unwrap();
+			let sealed = session.seal(b"answer");
+			(session, sealed)
+		});
+		let client_session = Session::client(&mut client, b"secret").unwrap();
+		let (server_session, sealed) = handle.join().unwrap();
+
+		assert_eq!(client_session.open(&sealed).unwrap(), b"answer");
+		assert_eq!(server_session.open(&client_session.seal(b"question")).unwrap(), b"question");
+
+		// A session's own messages, altered ones and ones from another session don't open.
+		assert!(server_session.open(&server_session.seal(b"question")).is_err());
+		let mut altered = client_session.seal(b"question");
+		*altered.last_mut().unwrap() ^= 1;
+		assert!(server_session.open(&altered).is_err());
+		assert!(server_session.open(b"short").is_err());
+
+		let (mut client, mut server) = UnixStream::pair().unwrap();
+		let handle = thread::spawn(move || Session::server(&mut server, b"secret").unwrap());
+		let other_session = Session::client(&mut client, b"secret").unwrap();
+		handle.join().unwrap();
+		assert!(server_session.open(&other_session.seal(b"question")).is_err());
+	}
+
+	#[test]
+	fn auth_wrong_secret_test() {
+		// The server rejects a client with another secret, and the client sees the connection closed.
+		let (mut client, mut server) = UnixStream::pair().unwrap();
+		let handle = thread::spawn(move || Session::server(&mut server, b"secret").map(|_| ()));
+		let result = Session::client(&mut client, b"guess");
+
+		assert_eq!(handle.join().unwrap().unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+		assert_eq!(result.err().unwrap().kind(), io::ErrorKind::PermissionDenied);
+	}
+}
@@ -1,11 +1,180 @@
-use clap::{App, AppSettings, Arg};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use regex::Regex;
 use std::env;
+use std::fs::{File, OpenOptions};
 use std::io::prelude::*;
+use std::io::{self, BufReader, BufWriter};
+use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::net::UnixStream;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::str;
+use std::sync::OnceLock;
+use std::thread;
+use std::time::Duration;
+
+use uuid::Uuid;
+
+use swarm::config::{Config, MIN_SECRET_LEN};
+use swarm::control;
+use swarm::signing;
+use swarm::snapshot;
+use swarm::models::{parse_time, ArchiveQuery, CtlReply, Job, JobLogQuery, JobQuery, JobStatus, JobStream, LogQuery};
+
+// How often dronectl logs --follow asks for new output of a running job.
+const FOLLOW_INTERVAL: Duration = Duration::from_secs(1);
+
+// Set from --control-dir, for a drone started with a control_dir of its own, see control_dir().
+static CONTROL_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+fn archive_command(matches: &ArgMatches) {
+	if let Some(export) = matches.subcommand_matches("export") {
+		let mut query = ArchiveQuery::default();
+
+		let parse_time_arg = |name: &str| -> Option<String> {
+			export.value_of(name).map(|time| parse_time(time).unwrap_or_else(|err| {
+				println!("Invalid --{}: {}\n", name, err);
+				std::process::exit(0x0001);
+			}))
+		};
+		query.since = parse_time_arg("since");
+		query.until = parse_time_arg("until");
+
+		let file = export.value_of("file").unwrap();
+		let mut out = match File::create(file) {
+			Ok(out) => BufWriter::new(out),
+			Err(err) => {
+				println!("Could not create export file {}: {}\n", file, err);
+				std::process::exit(0x0001);
+			}
+		};
+
+		// Fetch the archive a page at a time, one job per line.
+		let mut exported = 0;
+		loop {
+			let jobs = match request("ARCHIVE_EXPORT", &serde_json::to_string(&query).unwrap()) {
+				Ok(CtlReply::Archive(jobs)) => jobs,
+				Ok(CtlReply::Error(err)) => {
+					println!("Archive export failed: {}", err);
+					std::process::exit(0x0001);
+				},
+				Ok(_) => {
+					println!("Unexpected reply from drone.");
+					std::process::exit(0x0001);
+				},
+				Err(err) => {
+					println!("{}", err);
+					std::process::exit(0x0001);
+				},
+			};
+
+			for job in jobs.iter() {
+				if let Err(err) = writeln!(out, "{}", serde_json::to_string(job).unwrap()) {
+					println!("Could not write export file {}: {}\n", file, err);
+					std::process::exit(0x0001);
+				}
+			}
+
+			exported += jobs.len();
+			if jobs.len() < query.limit {
+				break;
+			}
+			query.offset += query.limit;
+		}
+
+		if let Err(err) = out.flush() {
+			println!("Could not write export file {}: {}\n", file, err);
+			std::process::exit(0x0001);
+		}
+
+		println!("Exported {} archived jobs to {}.\n", exported, file);
+	}
+}
+
+fn config_command(matches: &ArgMatches) {
+	if let Some(show) = matches.subcommand_matches("show") {
+		let file = show.value_of("file").map(|file| file.to_string()).unwrap_or_else(Config::default_file);
+
+		if show.is_present("effective") {
+			// Layer the environment over the file the same way the drone does.
+			match Config::load(&file, &[]) {
+				Ok(config) => {
+					println!("# Effective config for {}", file);
+					println!("# (command line flags given to the drone process itself are not included)");
+					print!("{}", config.show_effective());
+				},
+				Err(problems) => {
+					println!("Config {} has {} problem(s):", file, problems.len());
+					for problem in problems {
+						println!("  {}", problem);
+					}
+					std::process::exit(0x0001);
+				}
+			}
+		} else {
+			match std::fs::read_to_string(&file) {
+				Ok(content) => print!("{}", content),
+				Err(err) => {
+					println!("Could not read config file {}: {}", file, err);
+					std::process::exit(0x0001);
+				}
+			}
+		}
+
+		println!();
+	}
+
+	if let Some(init) = matches.subcommand_matches("init") {
+		let file = init.value_of("file").unwrap();
+
+		if Path::new(file).exists() && !init.is_present("force") {
+			println!("Config file {} already exists, use --force to overwrite it.\n", file);
+			std::process::exit(0x0001);
+		}
+
+		let config = Config {
+			file: file.into(),
+			..Default::default()
+		};
+
+		match config.save() {
+			Ok(_) => {
+				println!("Wrote default config to {}.", file);
+				println!("Add a swarm_secret of at least {} characters, the same on every drone, before starting a drone.\n", MIN_SECRET_LEN);
+			},
+			Err(err) => {
+				println!("Could not write config file {}: {}\n", file, err);
+				std::process::exit(0x0001);
+			}
+		}
+	}
+
+	if let Some(check) = matches.subcommand_matches("check") {
+		let file = check.value_of("file").unwrap();
+
+		let content = match std::fs::read_to_string(file) {
+			Ok(content) => content,
+			Err(err) => {
+				println!("Could not read config file {}: {}\n", file, err);
+				std::process::exit(0x0001);
+			}
+		};
+
+		match Config::parse(&content) {
+			Ok(_) => {
+				println!("Config file {} is valid.\n", file);
+			},
+			Err(problems) => {
+				println!("Config file {} has {} problem(s):", file, problems.len());
+				for problem in problems {
+					println!("  {}", problem);
+				}
+				println!();
+				std::process::exit(0x0001);
+			}
+		}
+	}
+}
 
 fn get_pid() -> Option<String> {
 	let mut command = Command::new("ps");
@@ -15,50 +184,353 @@ fn get_pid() -> Option<String> {
 		.arg("pid=");
 
 	let output = command.output().expect("some error");
-	let mut output_str = str::from_utf8(&output.stdout).unwrap().to_string();
+	// ps right-aligns the pid column and ends with a newline.
+	let output_str = str::from_utf8(&output.stdout).unwrap().trim().to_string();
 
-	if 0 == output_str.len() {
+	if output_str.is_empty() {
 		return None;
 	}
 
-	output_str.pop();	// Gets rid of a newline char at the end of the string.
-
 	Some(output_str)
 }
 
+// The drone's control_dir: --control-dir if given, else from the same config file and SWARM_* environment
+// the drone reads.
+fn control_dir() -> PathBuf {
+	if let Some(dir) = CONTROL_DIR.get() {
+		return dir.clone();
+	}
+
+	let content = std::fs::read_to_string(Config::default_file()).ok();
+	let env: Vec<(String, String)> = env::vars().filter(|(var, _)| var.starts_with("SWARM_")).collect();
+
+	Config::layered(content.as_deref(), &env, &[]).map(|config| config.control_dir).unwrap_or_else(|_| Config::default().control_dir)
+}
+
 fn get_socket(pid: String) -> Option<String> {
-	let socket = format!("/tmp/swarm_drone_{}.sock", pid);
+	let socket = control::socket_path(&control_dir(), pid).display().to_string();
 
-	if Path::new(&socket).exists() {
-		println!("socket file {} is there", socket);
-	} else {
-		println!("socket file {} not found", socket);
+	// Diagnostics go to stderr, stdout is the command's output (e.g. a job's, with dronectl logs).
+	if !Path::new(&socket).exists() {
+		eprintln!("socket file {} not found", socket);
 		return None;
 	}
 
 	Some(socket)
 }
 
-fn get_sockets() -> Vec<String> {
+// Send a command to the running drone and wait for its (JSON) reply.
+fn request(command: &str, args: &str) -> Result<CtlReply, String> {
+	let pid = get_pid().ok_or_else(|| "Swarm drone is not running.".to_string())?;
+	let socket = get_socket(pid).ok_or_else(|| "Swarm drone socket file not found.".to_string())?;
 
-	let mut command = Command::new("ls");
-	command.arg("/tmp/");
+	request_at(&socket, command, args)
+}
 
-	let output = command.output().expect("some error");
-	let output = str::from_utf8(&output.stdout).unwrap().to_string();
+// Send a command to the drone listening on a known socket file, see request.
+fn request_at(socket: &str, command: &str, args: &str) -> Result<CtlReply, String> {
+	let mut stream = UnixStream::connect(socket).map_err(|err| format!("error opening socket: {}", err))?;
+	writeln!(stream, "{} {}", command, args).map_err(|err| format!("error writing to stream: {}", err))?;
+
+	let mut reply = String::new();
+	BufReader::new(stream).read_line(&mut reply).map_err(|err| format!("error reading from stream: {}", err))?;
+
+	serde_json::from_str(&reply).map_err(|err| format!("invalid reply from drone: {}", err))
+}
+
+fn job_command(matches: &ArgMatches) {
+	if let Some(key) = matches.subcommand_matches("key") {
+		let file = key.value_of("file").unwrap();
+
+		let (signing_key, public) = match signing::generate_key() {
+			Ok(generated) => generated,
+			Err(err) => {
+				println!("Could not generate a key: {}\n", err);
+				std::process::exit(0x0001);
+			}
+		};
+
+		// Only readable by its owner, and never replaces an existing key.
+		let written = OpenOptions::new().write(true).create_new(true).mode(0o600).open(file)
+			.and_then(|mut out| out.write_all(signing::key_file_content(&signing_key).as_bytes()));
+		if let Err(err) = written {
+			println!("Could not write key file {}: {}\n", file, err);
+			std::process::exit(0x0001);
+		}
+
+		println!("Wrote job signing key to {}. Add the submitter to job_submitters on every drone:\n", file);
+		println!("  <name>={}\n", public);
+	}
+
+	if let Some(sign) = matches.subcommand_matches("sign") {
+		let key_file = sign.value_of("key").unwrap();
+		let signing_key = match signing::read_key(Path::new(key_file)) {
+			Ok(signing_key) => signing_key,
+			Err(err) => {
+				println!("Could not read key file {}: {}\n", key_file, err);
+				std::process::exit(0x0001);
+			}
+		};
+
+		let mut job = Job::new();
+		job.command = sign.values_of("command").unwrap().map(String::from).collect();
+		job.tags = sign.values_of("tag").map(|tags| tags.map(String::from).collect()).unwrap_or_default();
+		signing::sign(&mut job, sign.value_of("submitter").unwrap(), &signing_key);
+
+		println!("{}", serde_json::to_string(&job).unwrap());
+	}
+}
+
+fn logs_command(matches: &ArgMatches) {
+	let job = matches.value_of("job").unwrap();
+	let job = Uuid::parse_str(job).unwrap_or_else(|err| {
+		eprintln!("Invalid job id {}: {}\n", job, err);
+		std::process::exit(0x0001);
+	});
+	let stream = if matches.is_present("stderr") { JobStream::Stderr } else { JobStream::Stdout };
+	let follow = matches.is_present("follow");
+
+	// Look the socket up once, a followed job asks the drone again every FOLLOW_INTERVAL.
+	let socket = match get_pid().and_then(get_socket) {
+		Some(socket) => socket,
+		None => {
+			eprintln!("Swarm drone is not running.\n");
+			std::process::exit(0x0001);
+		}
+	};
+
+	let mut query = JobLogQuery {
+		job,
+		stream,
+		..Default::default()
+	};
+	let mut source = None;
+	let out = io::stdout();
+
+	loop {
+		let chunk = match request_at(&socket, "JOB_LOG", &serde_json::to_string(&query).unwrap()) {
+			Ok(CtlReply::JobLog(chunk)) => chunk,
+			Ok(CtlReply::Error(err)) => {
+				eprintln!("Reading the job {} failed: {}\n", stream.as_str(), err);
+				std::process::exit(0x0001);
+			},
+			Ok(_) => {
+				eprintln!("Unexpected reply from drone.\n");
+				std::process::exit(0x0001);
+			},
+			Err(err) => {
+				eprintln!("{}\n", err);
+				std::process::exit(0x0001);
+			},
+		};
+
+		if source != Some(chunk.drone) {
+			eprintln!("# {} of job id = {} from drone id = {}", stream.as_str(), job, chunk.drone);
+			source = Some(chunk.drone);
+		}
+
+		let mut out = out.lock();
+		if out.write_all(chunk.data.as_bytes()).and_then(|_| out.flush()).is_err() {
+			// Nobody is reading anymore, e.g. piped into head.
+			std::process::exit(0x000);
+		}
+
+		query.offset = chunk.offset;
+		if chunk.data.is_empty() {
+			if chunk.done || !follow {
+				break;
+			}
+			thread::sleep(FOLLOW_INTERVAL);
+		}
+	}
+}
+
+fn search_command(matches: &ArgMatches) {
+	let mut query = JobQuery::default();
+
+	let parse_time_arg = |name: &str| -> Option<String> {
+		matches.value_of(name).map(|time| parse_time(time).unwrap_or_else(|err| {
+			println!("Invalid --{}: {}\n", name, err);
+			std::process::exit(0x0001);
+		}))
+	};
+	query.since = parse_time_arg("since");
+	query.until = parse_time_arg("until");
+
+	if let Some(status) = matches.value_of("status") {
+		query.status = Some(JobStatus::from_name(status).unwrap_or_else(|| {
+			println!("Invalid --status {}, expected one of Canceled, Error, Finished, New, Working.\n", status);
+			std::process::exit(0x0001);
+		}));
+	}
+
+	if let Some(drone) = matches.value_of("drone") {
+		query.drone = Some(Uuid::parse_str(drone).unwrap_or_else(|err| {
+			println!("Invalid --drone {}: {}\n", drone, err);
+			std::process::exit(0x0001);
+		}));
+	}
+
+	query.tag = matches.value_of("tag").map(|tag| tag.to_string());
+	query.text = matches.value_of("text").map(|text| text.to_string());
+
+	let limit = matches.value_of("limit").unwrap_or("20").parse::<usize>().unwrap_or(0);
+	let page = matches.value_of("page").unwrap_or("1").parse::<usize>().unwrap_or(0);
+	if limit == 0 || page == 0 {
+		println!("--limit and --page must be positive numbers.\n");
+		std::process::exit(0x0001);
+	}
+	query.limit = limit;
+	query.offset = (page - 1) * limit;
+
+	match request("SEARCH", &serde_json::to_string(&query).unwrap()) {
+		Ok(CtlReply::Search(results)) => {
+			for job in results.jobs.iter() {
+				println!("{}  {:<9} created {}  finished {}  tags [{}]  owners [{}]",
+					job.id,
+					job.status.as_str(),
+					job.created,
+					job.finished.as_deref().unwrap_or("-"),
+					job.tags.join(", "),
+					job.owners.iter().map(|owner| owner.to_string()).collect::<Vec<String>>().join(", "));
+			}
+
+			let pages = results.total.div_ceil(results.limit);
+			println!("\npage {} of {} ({} matching jobs)", page, pages.max(1), results.total);
+		},
+		Ok(CtlReply::Error(err)) => {
+			println!("Search failed: {}", err);
+			std::process::exit(0x0001);
+		},
+		Ok(_) => {
+			println!("Unexpected reply from drone.");
+			std::process::exit(0x0001);
+		},
+		Err(err) => {
+			println!("{}", err);
+			std::process::exit(0x0001);
+		},
+	}
+}
+
+fn snapshot_command(matches: &ArgMatches) {
+	if let Some(create) = matches.subcommand_matches("create") {
+		let dir = create.value_of("dir").unwrap();
+
+		// The drone process may run in another working directory, so pass it an absolute path.
+		let dir = match std::fs::create_dir_all(dir).and_then(|_| std::fs::canonicalize(dir)) {
+			Ok(dir) => dir,
+			Err(err) => {
+				println!("Could not create snapshot directory {}: {}\n", dir, err);
+				std::process::exit(0x0001);
+			}
+		};
+
+		match request("SNAPSHOT", &dir.display().to_string()) {
+			Ok(CtlReply::Snapshot(manifest)) => {
+				println!("Snapshot of drone id = {} (schema version {}) written to {}.\n", manifest.drone_id, manifest.schema_version, dir.display());
+			},
+			Ok(CtlReply::Error(err)) => {
+				println!("Snapshot failed: {}\n", err);
+				std::process::exit(0x0001);
+			},
+			Ok(_) => {
+				println!("Unexpected reply from drone.\n");
+				std::process::exit(0x0001);
+			},
+			Err(err) => {
+				println!("{}\n", err);
+				std::process::exit(0x0001);
+			},
+		}
+	}
+
+	if let Some(restore) = matches.subcommand_matches("restore") {
+		let dir = restore.value_of("dir").unwrap();
+		let config_file = restore.value_of("config").map(|file| file.to_string()).unwrap_or_else(Config::default_file);
+
+		if get_pid().is_some() {
+			println!("A swarm drone is running, stop it (dronectl --stop) before restoring a snapshot.\n");
+			std::process::exit(0x0001);
+		}
+
+		match snapshot::restore(Path::new(dir), Path::new(&config_file), restore.is_present("force")) {
+			Ok(manifest) => {
+				println!("Restored drone id = {} from the snapshot taken {} UTC, config written to {}.", manifest.drone_id, manifest.created, config_file);
+				println!("On its next start the drone re-announces its id and reconciles job ownership with its peers.\n");
+			},
+			Err(err) => {
+				println!("Restore failed: {}\n", err);
+				std::process::exit(0x0001);
+			},
+		}
+	}
+}
+
+fn swarm_logs_command(matches: &ArgMatches) {
+	let mut query = LogQuery::default();
+
+	if let Some(since) = matches.value_of("since") {
+		query.since = Some(parse_time(since).unwrap_or_else(|err| {
+			println!("Invalid --since: {}\n", err);
+			std::process::exit(0x0001);
+		}));
+	}
+	query.grep = matches.value_of("grep").map(|grep| grep.to_string());
+
+	if let Some(limit) = matches.value_of("limit") {
+		query.limit = limit.parse::<usize>().ok().filter(|limit| *limit > 0).unwrap_or_else(|| {
+			println!("--limit must be a positive number.\n");
+			std::process::exit(0x0001);
+		});
+	}
+
+	match request("SWARM_LOGS", &serde_json::to_string(&query).unwrap()) {
+		Ok(CtlReply::Logs(logs)) => {
+			for line in logs.lines.iter() {
+				println!("{} {} {}", line.timestamp, line.drone, line.line);
+			}
+
+			for failed in logs.failed.iter() {
+				println!("\nNo log lines from {}", failed);
+			}
+		},
+		Ok(CtlReply::Error(err)) => {
+			println!("Log search failed: {}", err);
+			std::process::exit(0x0001);
+		},
+		Ok(_) => {
+			println!("Unexpected reply from drone.");
+			std::process::exit(0x0001);
+		},
+		Err(err) => {
+			println!("{}", err);
+			std::process::exit(0x0001);
+		},
+	}
+}
+
+// Socket files in control_dir, with their paths.
+fn get_sockets() -> Vec<String> {
+	let entries = match std::fs::read_dir(control_dir()) {
+		Ok(entries) => entries,
+		Err(_) => return Vec::new(),
+	};
 
 	let mut sockets: Vec<String> = Vec::new();
 
-	let srx = Regex::new(r"swarm_drone_[0-9].*.sock").unwrap();
-	for cap in srx.captures_iter(&output) {
-		sockets.push(cap[0].to_string());
+	let srx = Regex::new(r"^swarm_drone_[0-9]+\.sock$").unwrap();
+	for entry in entries.flatten() {
+		if srx.is_match(&entry.file_name().to_string_lossy()) {
+			sockets.push(entry.path().display().to_string());
+		}
 	}
 
-	return sockets;
+	sockets
 }
 
 pub fn main() {
-	const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+	const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 	let matches = App::new("swarm dronectl utility")
 		.version(VERSION)
@@ -66,6 +538,11 @@ pub fn main() {
 		.setting(AppSettings::ArgRequiredElseHelp)
 		.setting(AppSettings::NextLineHelp)
 		.about("The command line control utility for a swarm drone.")
+		.arg(Arg::with_name("control-dir")
+			.short("c")
+			.long("control-dir")
+			.takes_value(true)
+			.help("The control_dir of the drone to talk to, for one started with --control-dir (Default: from SWARM_CONFIG or the default config file, and SWARM_CONTROL_DIR)."))
 		.arg(Arg::with_name("details")
 			.long("details")
 			.takes_value(false)
@@ -73,8 +550,12 @@ pub fn main() {
 		.arg(Arg::with_name("kill")
 			.long("kill")
 			.takes_value(false)
-			.conflicts_with_all(&["configure", "details", "restart", "status", "start", "stop"])
+			.conflicts_with_all(&["configure", "details", "reload", "restart", "status", "start", "stop"])
 			.help("Perform an immediate \"hard\" shutdown of the drone process.\nNOTE!: This command will preempt any other commands."))
+		.arg(Arg::with_name("reload")
+			.long("reload")
+			.takes_value(false)
+			.help("Reload the drone config file (same as sending SIGHUP to the drone process).\nChanges to address, port, db_dir, db_file and id require a restart."))
 		.arg(Arg::with_name("restart")
 			.long("restart")
 			.takes_value(false)
@@ -93,9 +574,198 @@ pub fn main() {
 			.takes_value(false)
 			.conflicts_with_all(&["restart", "start"])
 			.help("Perform a \"clean\" shutdown of the drone process."))
+		.subcommand(SubCommand::with_name("search")
+			.about("Search the running drone's local job archive.")
+			.arg(Arg::with_name("drone")
+				.long("drone")
+				.takes_value(true)
+				.help("Only jobs owned by this drone id."))
+			.arg(Arg::with_name("limit")
+				.long("limit")
+				.takes_value(true)
+				.help("Results per page (Default: 20)."))
+			.arg(Arg::with_name("page")
+				.long("page")
+				.takes_value(true)
+				.help("Page of results to show (Default: 1)."))
+			.arg(Arg::with_name("since")
+				.long("since")
+				.takes_value(true)
+				.help("Only jobs created since an age (10m, 2h, 7d) or UTC time (2020-10-10 10:10:10)."))
+			.arg(Arg::with_name("status")
+				.long("status")
+				.takes_value(true)
+				.help("Only jobs with this status (Canceled, Error, Finished, New, Working)."))
+			.arg(Arg::with_name("tag")
+				.long("tag")
+				.takes_value(true)
+				.help("Only jobs with this tag."))
+			.arg(Arg::with_name("text")
+				.long("text")
+				.takes_value(true)
+				.help("Only jobs whose output contains this text."))
+			.arg(Arg::with_name("until")
+				.long("until")
+				.takes_value(true)
+				.help("Only jobs created before an age or UTC time, see --since.")))
+		.subcommand(SubCommand::with_name("logs")
+			.about("Print a job's captured output, from whichever drone in the swarm ran it.")
+			.arg(Arg::with_name("follow")
+				.short("f")
+				.long("follow")
+				.takes_value(false)
+				.help("Keep printing new output until the job stops."))
+			.arg(Arg::with_name("stderr")
+				.long("stderr")
+				.takes_value(false)
+				.help("Print the job's stderr instead of its stdout."))
+			.arg(Arg::with_name("job")
+				.required(true)
+				.help("The job id.")))
+		.subcommand(SubCommand::with_name("swarm-logs")
+			.about("Search the log files of this drone and every drone it knows, merged by time (UTC).")
+			.arg(Arg::with_name("grep")
+				.long("grep")
+				.takes_value(true)
+				.help("Only lines matching this regular expression."))
+			.arg(Arg::with_name("limit")
+				.long("limit")
+				.takes_value(true)
+				.help("Most lines per drone, the newest are shown (Default: 1000, at most 10000)."))
+			.arg(Arg::with_name("since")
+				.long("since")
+				.takes_value(true)
+				.help("Only lines written since an age (10m, 2h, 7d) or UTC time (2020-10-10 10:10:10).")))
+		.subcommand(SubCommand::with_name("archive")
+			.about("Work with the running drone's archive of finished jobs.")
+			.setting(AppSettings::SubcommandRequiredElseHelp)
+			.subcommand(SubCommand::with_name("export")
+				.about("Write archived jobs to a JSON Lines file, one job per line.")
+				.arg(Arg::with_name("since")
+					.long("since")
+					.takes_value(true)
+					.help("Only jobs finished since an age (10m, 2h, 7d) or UTC time (2020-10-10 10:10:10)."))
+				.arg(Arg::with_name("until")
+					.long("until")
+					.takes_value(true)
+					.help("Only jobs finished before an age or UTC time, see --since."))
+				.arg(Arg::with_name("file")
+					.required(true)
+					.help("The file to write."))))
+		.subcommand(SubCommand::with_name("job")
+			.about("Sign jobs for drones that only run jobs from their job_submitters.")
+			.setting(AppSettings::SubcommandRequiredElseHelp)
+			.subcommand(SubCommand::with_name("key")
+				.about("Generate a job signing key, and print the public key for job_submitters.")
+				.arg(Arg::with_name("file")
+					.required(true)
+					.help("The key file to create, readable only by you.")))
+			.subcommand(SubCommand::with_name("sign")
+				.about("Print a job signed with a key, as JSON.")
+				.arg(Arg::with_name("key")
+					.long("key")
+					.takes_value(true)
+					.required(true)
+					.help("The job signing key file."))
+				.arg(Arg::with_name("submitter")
+					.long("submitter")
+					.takes_value(true)
+					.required(true)
+					.help("The submitter name the drones know the key by."))
+				.arg(Arg::with_name("tag")
+					.long("tag")
+					.takes_value(true)
+					.multiple(true)
+					.number_of_values(1)
+					.help("A tag for the job, can be repeated."))
+				.arg(Arg::with_name("command")
+					.required(true)
+					.multiple(true)
+					.last(true)
+					.help("The program to run and its arguments, after --."))))
+		.subcommand(SubCommand::with_name("snapshot")
+			.about("Disaster recovery: copy a drone's database and config, and rebuild a drone from such a copy.")
+			.setting(AppSettings::SubcommandRequiredElseHelp)
+			.subcommand(SubCommand::with_name("create")
+				.about("Write a consistent snapshot of the running drone's database, config and id to an empty directory.")
+				.arg(Arg::with_name("dir")
+					.required(true)
+					.help("The snapshot directory.")))
+			.subcommand(SubCommand::with_name("restore")
+				.about("Rebuild a stopped drone from a snapshot directory.")
+				.arg(Arg::with_name("config")
+					.short("c")
+					.long("config")
+					.takes_value(true)
+					.help("Where to write the restored config (Default: SWARM_CONFIG or the default config file)."))
+				.arg(Arg::with_name("force")
+					.long("force")
+					.takes_value(false)
+					.help("Replace an existing config file and database (the database is kept as a .bak file)."))
+				.arg(Arg::with_name("dir")
+					.required(true)
+					.help("The snapshot directory."))))
+		.subcommand(SubCommand::with_name("config")
+			.about("Inspect drone config files.")
+			.setting(AppSettings::SubcommandRequiredElseHelp)
+			.subcommand(SubCommand::with_name("show")
+				.about("Print a config file, or with --effective the merged defaults, file and SWARM_* environment.")
+				.arg(Arg::with_name("effective")
+					.long("effective")
+					.takes_value(false)
+					.help("Print the merged config and where each value came from."))
+				.arg(Arg::with_name("file")
+					.help("The config file to show (Default: SWARM_CONFIG or the default config file).")))
+			.subcommand(SubCommand::with_name("check")
+				.about("Validate a config file and report every problem found.")
+				.arg(Arg::with_name("file")
+					.required(true)
+					.help("The config file to check.")))
+			.subcommand(SubCommand::with_name("init")
+				.about("Write a config file with the default settings. The drone never writes its own config.")
+				.arg(Arg::with_name("force")
+					.long("force")
+					.takes_value(false)
+					.help("Overwrite the file if it already exists."))
+				.arg(Arg::with_name("file")
+					.required(true)
+					.help("The config file to create."))))
 		.get_matches();
 
-	println!();
+	if let Some(dir) = matches.value_of("control-dir") {
+		CONTROL_DIR.set(PathBuf::from(dir)).unwrap();
+	}
+
+	// The blank lines around a command's output are for the terminal, not for whoever reads stdout.
+	eprintln!();
+
+	if let Some(archive) = matches.subcommand_matches("archive") {
+		archive_command(archive);
+	}
+
+	if let Some(config) = matches.subcommand_matches("config") {
+		config_command(config);
+	}
+
+	if let Some(job) = matches.subcommand_matches("job") {
+		job_command(job);
+	}
+
+	if let Some(logs) = matches.subcommand_matches("logs") {
+		logs_command(logs);
+	}
+
+	if let Some(search) = matches.subcommand_matches("search") {
+		search_command(search);
+	}
+
+	if let Some(snapshot) = matches.subcommand_matches("snapshot") {
+		snapshot_command(snapshot);
+	}
+
+	if let Some(swarm_logs) = matches.subcommand_matches("swarm-logs") {
+		swarm_logs_command(swarm_logs);
+	}
 
 	if matches.is_present("kill") {
 		println!("Killing the drone process...");
@@ -104,7 +774,7 @@ pub fn main() {
 			Command::new("kill")
 				.arg("-9")
 				.arg(pid)
-				.spawn()
+				.status()
 				.expect("Failed to kill process");
 		} else {
 			println!("No active swarm drones found. Checking for abandoned drone sockets...");
@@ -114,8 +784,7 @@ pub fn main() {
 
 		for socket in sockets {
 			println!("Removing abandoned socket: {}", socket);
-			let file_path = format!("/tmp/{}", socket);
-			std::fs::remove_file(Path::new(&file_path)).unwrap();
+			std::fs::remove_file(Path::new(&socket)).unwrap();
 		}
 
 		println!("Done.");
@@ -135,7 +804,7 @@ pub fn main() {
 		 	println!("Swarm drone is not running.");
 
 		 	let sockets = get_sockets();
-		 	if sockets.len() > 0 {
+		 	if !sockets.is_empty() {
 			 	for socket in sockets {
 			 		println!("Found abandoned socket file: {}", socket);
 			 	}
@@ -156,6 +825,20 @@ pub fn main() {
 		}
 	}
 
+	if matches.is_present("reload") {
+		println!("Reloading the drone config...");
+
+		if let Some(pid) = get_pid() {
+			if let Some(socket) = get_socket(pid) {
+				let mut stream = UnixStream::connect(socket).expect("error opening socket...");
+				stream.write_all(b"RELOAD").expect("error writing to stream...");
+				println!("Applied and restart-required changes are reported in the drone system/error logs.");
+			}
+		} else {
+			println!("Swarm drone is not running.");
+		}
+	}
+
 	if matches.is_present("restart") {
 		println!("Restarting the drone process...");
 	}
@@ -172,11 +855,13 @@ pub fn main() {
 		// Clean up any "orphaned" socket files.
 		let sockets = get_sockets();
 		for socket in sockets {
-			let file_path = format!("/tmp/{}", socket);
-			std::fs::remove_file(Path::new(&file_path)).unwrap();
+			std::fs::remove_file(Path::new(&socket)).unwrap();
 		}
 
 		let mut command = Command::new("target/debug/swarm");
+		if let Some(dir) = matches.value_of("control-dir") {
+			command.arg("--control-dir").arg(dir);
+		}
 		if let Ok(child) = command.spawn() {
 			println!("swarm drone pid = {} is running", child.id());
 		} else {
@@ -196,6 +881,6 @@ pub fn main() {
 		}
 	}
 
-	println!();
+	eprintln!();
 
 }
@@ -0,0 +1,860 @@
+use log::info;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde::de::{self, Visitor};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::env;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use toml::Value;
+use uuid::Uuid;
+
+use crate::log::{LogFormatRule, LogOverflow, LogRotation, LogRoute, LogTarget};
+use crate::models::LogLevel;
+use crate::signing::{JobRule, JobSubmitter};
+
+
+pub const DEFAULT_CONFIG: &str = "data/etc/swarm/drone.cfg.toml";
+
+// Every drone listens for peers, so it needs a swarm_secret of at least this many characters.
+pub const MIN_SECRET_LEN: usize = 16;
+
+// Every layered setting: (config key, command line flag, help text). The environment variable for a
+// key is SWARM_ followed by the upper-cased key, e.g. SWARM_DB_DIR.
+pub const FIELDS: [(&str, &str, &str); 40] = [
+	("address", "address", "Address to listen on for inter-drone communications (Default: 0.0.0.0)."),
+	("control_dir", "control-dir", "Private directory for the dronectl control socket (Default: data/var/run/swarm)."),
+	("control_groups", "control-groups", "Comma separated groups (names or gids) whose members may use every dronectl command, besides the drone's user and root."),
+	("control_mode", "control-mode", "Octal file mode of the control socket, the peer credentials of every connection are checked too (Default: 0600)."),
+	("control_read_groups", "control-read-groups", "Comma separated groups (names or gids) whose members may use the read-only dronectl commands: search, logs, swarm-logs and archive export."),
+	("control_users", "control-users", "Comma separated users (names or uids) that may use every dronectl command, besides the drone's user and root."),
+	("db_dir", "db-dir", "Directory holding the drone database and id file (Default: data/usr/local/swarm)."),
+	("db_file", "db-file", "Database file name inside db_dir (Default: drone.db)."),
+	("error_log", "error-log", "Error log file name inside log_dir (Default: error.log)."),
+	("id", "id", "Pin the drone id instead of using the generated one in db_dir."),
+	("job_acl", "job-acl", "Comma separated <submitter>=command:<program> and <submitter>=tag:<tag> rules saying what each job submitter (* for all) may run, a program ending in * is a prefix."),
+	("job_archive_after", "job-archive-after", "Days after which finished jobs are moved to the job archive, 0 never archives (Default: 7)."),
+	("job_archive_max_age", "job-archive-max-age", "Days (since finishing) to keep archived jobs, 0 keeps them forever (Default: 365)."),
+	("job_archive_max_rows", "job-archive-max-rows", "Most archived jobs to keep, 0 for no limit (Default: 100000)."),
+	("job_log_max_size", "job-log-max-size", "Size in MiB at which a job's stdout or stderr log stops growing, 0 for no limit (Default: 10)."),
+	("job_submitters", "job-submitters", "Comma separated <name>=<hex ed25519 public key> list of who may submit jobs, see dronectl job key. Only signed jobs allowed by job_acl run, without submitters the drone runs no jobs."),
+	("log_compress", "log-compress", "Gzip rotated log files (Default: true)."),
+	("log_dir", "log-dir", "Directory for the drone log files (Default: data/var/log/swarm)."),
+	("log_formats", "log-formats", "Comma separated <destination>=<format> rules, json (JSON Lines) or text, e.g. system=json (Default: all text)."),
+	("log_level", "log-level", "Lowest level written to the logs: trace, debug, info, warn, error or fatal (Default: info)."),
+	("log_max_files", "log-max-files", "Rotated copies to keep of each log file, 0 keeps them all (Default: 7)."),
+	("log_max_size", "log-max-size", "Size in MiB at which a log file is rotated, 0 for no limit (Default: 10)."),
+	("log_overflow", "log-overflow", "When the log queue is full: drop new messages (counted and reported) or block until there is room (Default: drop)."),
+	("log_queue_size", "log-queue-size", "Messages that can wait to be written by the log process (Default: 10000)."),
+	("log_rotate", "log-rotate", "Also rotate log files every: never, hourly, daily or weekly (Default: daily)."),
+	("log_routes", "log-routes", "Comma separated <levels>=<destination> rules saying where messages go, e.g. warn+=error (Default: trace-info=system, warn+=error)."),
+	("log_targets", "log-targets", "Comma separated <module>=<level> overrides of log_level, e.g. swarm::db=debug."),
+	("message_archive", "message-archive", "Record sent and received inter-drone messages in the database (Default: false)."),
+	("message_archive_max_age", "message-archive-max-age", "Days to keep archived messages, 0 keeps them forever (Default: 7)."),
+	("message_archive_max_rows", "message-archive-max-rows", "Most archived messages to keep, 0 for no limit (Default: 100000)."),
+	("port", "port", "Port to listen on for inter-drone communications (Default: 9079)."),
+	("seeds", "seeds", "Comma separated address:port list of drones to contact on start."),
+	("store", "store", "Where the drone keeps its state: sqlite (db_dir/db_file) or memory, lost on exit (Default: sqlite)."),
+	("swarm_secret", "swarm-secret", "Secret of at least 16 characters shared by the drones of a swarm, peers that don't know it are rejected. Required, and only read from the config file or SWARM_SWARM_SECRET so that it doesn't show up in ps."),
+	("system_log", "system-log", "System log file name inside log_dir (Default: system.log)."),
+	("tags", "tags", "Comma separated list of tags describing the jobs this drone accepts."),
+	("threads", "threads", "Number of jobs to work on at once (Default: 1)."),
+	("tls_ca", "tls-ca", "PEM file with the CA certificate(s) that sign the swarm's drone certificates, turns on TLS between drones."),
+	("tls_cert", "tls-cert", "PEM file with this drone's certificate (chain), issued to <drone id>.drone.swarm."),
+	("tls_key", "tls-key", "PEM file with the private key of tls_cert."),
+];
+
+// The storage backend, see db::Store.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreKind {
+	Memory,
+	Sqlite,
+}
+
+// Where the value of a setting came from, in increasing order of precedence (IdFile only applies to id).
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigSource {
+	Default,
+	File,
+	IdFile,
+	Env(String),
+	Cli(String),
+}
+
+impl fmt::Display for ConfigSource {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			ConfigSource::Default => write!(f, "default"),
+			ConfigSource::File => write!(f, "config file"),
+			ConfigSource::IdFile => write!(f, "id file"),
+			ConfigSource::Env(var) => write!(f, "environment {}", var),
+			ConfigSource::Cli(flag) => write!(f, "command line --{}", flag),
+		}
+	}
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+	pub address:						IpAddr,
+	// CLI overrides, kept so that a reload layers them over the file again.
+	#[serde(skip)]
+	pub cli:							Vec<(String, String)>,
+	pub control_dir:					PathBuf,
+	pub control_groups:					Vec<String>,
+	pub control_mode:					String,
+	pub control_read_groups:			Vec<String>,
+	pub control_users:					Vec<String>,
+	pub db_dir:							PathBuf,
+	pub db_file:						PathBuf,
+	pub error_log:						PathBuf,
+	#[serde(skip_serializing)]
+	pub file:							PathBuf,
+	#[serde(skip_serializing_if = "Uuid::is_nil")]
+	pub id:								Uuid,
+	pub job_acl:						Vec<JobRule>,
+	pub job_archive_after:				u64,
+	pub job_archive_max_age:			u64,
+	pub job_archive_max_rows:			usize,
+	pub job_log_max_size:				u64,
+	pub job_submitters:					Vec<JobSubmitter>,
+	pub log_compress:					bool,
+	pub log_dir:						PathBuf,
+	pub log_formats:					Vec<LogFormatRule>,
+	pub log_level:						LogLevel,
+	pub log_max_files:					usize,
+	pub log_max_size:					u64,
+	pub log_overflow:					LogOverflow,
+	pub log_queue_size:					usize,
+	pub log_rotate:						LogRotation,
+	pub log_routes:						Vec<LogRoute>,
+	pub log_targets:					Vec<LogTarget>,
+	pub message_archive:				bool,
+	pub message_archive_max_age:		u64,
+	pub message_archive_max_rows:		usize,
+	#[serde(deserialize_with = "Config::deserialize_port")]
+	pub port:							u16,
+	pub seeds:							Vec<SocketAddr>,
+	#[serde(skip)]
+	pub sources:						BTreeMap<String, ConfigSource>,
+	pub store:							StoreKind,
+	// Never shown by show_effective.
+	#[serde(skip_serializing_if = "String::is_empty")]
+	pub swarm_secret:					String,
+	pub system_log:						PathBuf,
+	pub tags:							Vec<String>,
+	pub threads:						usize,
+	pub tls_ca:							PathBuf,
+	pub tls_cert:						PathBuf,
+	pub tls_key:						PathBuf,
+}
+
+impl Default for Config {
+	fn default() -> Self {
+		Config {
+			address: IpAddr::from([0, 0, 0, 0]),
+			cli: Vec::new(),
+			control_dir: PathBuf::from("data/var/run/swarm"),
+			control_groups: Vec::new(),
+			control_mode: String::from("0600"),
+			control_read_groups: Vec::new(),
+			control_users: Vec::new(),
+			db_dir: PathBuf::from("data/usr/local/swarm"),
+			db_file: PathBuf::from("drone.db"),
+			error_log: PathBuf::from("error.log"),
+			file: PathBuf::new(),
+			id: Uuid::nil(),
+			job_acl: Vec::new(),
+			job_archive_after: 7,
+			job_archive_max_age: 365,
+			job_archive_max_rows: 100_000,
+			job_log_max_size: 10,
+			job_submitters: Vec::new(),
+			log_compress: true,
+			log_dir: PathBuf::from("data/var/log/swarm"),
+			log_formats: Vec::new(),
+			log_level: LogLevel::Info,
+			log_max_files: 7,
+			log_max_size: 10,
+			log_overflow: LogOverflow::Drop,
+			log_queue_size: 10_000,
+			log_rotate: LogRotation::Daily,
+			log_routes: LogRoute::defaults(),
+			log_targets: Vec::new(),
+			message_archive: false,
+			message_archive_max_age: 7,
+			message_archive_max_rows: 100_000,
+			port: 9079,
+			seeds: Vec::new(),
+			sources: BTreeMap::new(),
+			store: StoreKind::Sqlite,
+			swarm_secret: String::new(),
+			system_log: PathBuf::from("system.log"),
+			tags: Vec::new(),
+			threads: 1,
+			tls_ca: PathBuf::new(),
+			tls_cert: PathBuf::new(),
+			tls_key: PathBuf::new(),
+		}
+	}
+}
+
+impl Config {
+	// Load (but never write) the layered config: defaults, then the config file (if it exists), then
+	// SWARM_* environment variables, then command line overrides. Unless one of those pins an id, the
+	// drone id is read from the id file in db_dir (see load_or_create_id).
+	pub fn load<P: AsRef<Path>>(file: P, cli: &[(String, String)]) -> Result<Self, Vec<ConfigProblem>> {
+		let file = file.as_ref();
+		let content = match fs::read_to_string(file) {
+			Ok(content) => Some(content),
+			Err(content_err) => {
+				if io::ErrorKind::NotFound != content_err.kind() {
+					return Err(vec![ConfigProblem::new(None, &content_err.to_string())]);
+				}
+
+				info!("Config file {} not found, using defaults.", file.display());
+				None
+			}
+		};
+
+		let env: Vec<(String, String)> = env::vars().filter(|(var, _)| var.starts_with("SWARM_")).collect();
+		let mut config = Config::layered(content.as_deref(), &env, cli)?;
+
+		// Unlike a single file (see parse), the config a drone runs with must have a secret from some layer.
+		if config.swarm_secret.is_empty() {
+			return Err(vec![ConfigProblem::new(Some("swarm_secret"), "swarm_secret is not set, every drone listens for peers and needs one")]);
+		}
+
+		config.cli = cli.to_vec();
+		config.file = file.to_path_buf();
+
+		if config.id.is_nil() {
+			if let Ok(content) = fs::read_to_string(config.id_file()) {
+				config.id = Uuid::parse_str(content.trim()).map_err(|err| {
+					vec![ConfigProblem::new(Some("id"), &format!("invalid drone id file {}: {}", config.id_file().display(), err))]
+				})?;
+				config.sources.insert("id".to_string(), ConfigSource::IdFile);
+			}
+		}
+
+		Ok(config)
+	}
+
+	// Validate the full contents of a config file on its own, without environment or command line.
+	pub fn parse(content: &str) -> Result<Self, Vec<ConfigProblem>> {
+		Config::layered(Some(content), &[], &[])
+	}
+
+	// Merge the layers into one toml table and validate it. Every key is checked on its own so that one
+	// bad value doesn't hide the rest, and each problem points at the file line, variable or flag it came from.
+	pub fn layered(content: Option<&str>, env: &[(String, String)], cli: &[(String, String)]) -> Result<Self, Vec<ConfigProblem>> {
+		let mut table = toml::map::Map::new();
+		let mut sources: BTreeMap<String, ConfigSource> = FIELDS.iter().map(|(key, _, _)| (key.to_string(), ConfigSource::Default)).collect();
+		let mut problems = Vec::new();
+
+		if let Some(content) = content {
+			let config_value: Value = match toml::from_str(content) {
+				Ok(config_value) => config_value,
+				Err(err) => {
+					let mut problem = ConfigProblem::new(None, &err.to_string());
+					problem.line = err.line_col().map(|(line, _)| line + 1);
+					problem.context = problem.line.and_then(|line| content.lines().nth(line - 1)).map(|l| l.trim().to_string());
+					return Err(vec![problem]);
+				}
+			};
+
+			match config_value.get("swarm").and_then(|swarm| swarm.as_table()) {
+				Some(swarm) => {
+					for (k, v) in swarm.iter() {
+						table.insert(k.clone(), v.clone());
+						sources.insert(k.clone(), ConfigSource::File);
+					}
+				},
+				None => {
+					return Err(vec![ConfigProblem::new(None, "missing [swarm] table")]);
+				}
+			}
+		}
+
+		for (var, raw) in env.iter() {
+			if let Some((key, _, _)) = FIELDS.iter().find(|(key, _, _)| Config::env_var(key) == *var) {
+				table.insert(key.to_string(), Config::raw_value(key, raw));
+				sources.insert(key.to_string(), ConfigSource::Env(var.clone()));
+			}
+		}
+
+		for (key, raw) in cli.iter() {
+			match FIELDS.iter().find(|(k, _, _)| k == key) {
+				Some((key, flag, _)) => {
+					table.insert(key.to_string(), Config::raw_value(key, raw));
+					sources.insert(key.to_string(), ConfigSource::Cli(flag.to_string()));
+				},
+				None => {
+					problems.push(ConfigProblem::new(Some(key), "not a config setting"));
+				},
+			}
+		}
+
+		let locate = |key: &str, message: &str| -> ConfigProblem {
+			match (sources.get(key), content) {
+				(Some(ConfigSource::Env(var)), _) => {
+					let mut problem = ConfigProblem::new(Some(key), message);
+					problem.context = env.iter().find(|(v, _)| v == var).map(|(v, raw)| format!("{}={}", v, raw));
+					problem
+				},
+				(Some(ConfigSource::Cli(flag)), _) => {
+					let mut problem = ConfigProblem::new(Some(key), message);
+					problem.context = cli.iter().find(|(k, _)| k == key).map(|(_, raw)| format!("--{} {}", flag, raw));
+					problem
+				},
+				(_, Some(content)) => ConfigProblem::at(content, key, message),
+				(_, None) => ConfigProblem::new(Some(key), message),
+			}
+		};
+
+		for (k, v) in table.iter() {
+			let mut single = toml::map::Map::new();
+			single.insert(k.clone(), v.clone());
+
+			match Value::Table(single).try_into::<Config>() {
+				Ok(single_config) => {
+					if let Some(message) = single_config.check_value(k) {
+						problems.push(locate(k, message));
+					}
+				},
+				Err(err) => {
+					// The key is already reported separately, drop toml's " for key `...`" suffix.
+					let err = err.to_string();
+					let message = err.split(" for key `").next().unwrap_or(&err);
+					problems.push(locate(k, message));
+				},
+			}
+		}
+
+		if problems.is_empty() {
+			let mut config: Config = Value::Table(table).try_into().map_err(|err: toml::de::Error| vec![ConfigProblem::new(None, &err.to_string())])?;
+
+			if config.error_log == config.system_log {
+				problems.push(locate("system_log", "error_log and system_log must be different files"));
+			}
+
+			if let Some(rule) = config.job_acl.iter().find(|rule| rule.submitter != "*" && !config.job_submitters.iter().any(|submitter| submitter.name == rule.submitter)) {
+				problems.push(locate("job_acl", &format!("job rule for \"{}\", who is not in job_submitters", rule.submitter)));
+			}
+
+			let tls = [("tls_ca", &config.tls_ca), ("tls_cert", &config.tls_cert), ("tls_key", &config.tls_key)];
+			if tls.iter().any(|(_, path)| path.as_os_str().is_empty()) {
+				for (key, _) in tls.iter().filter(|(_, path)| !path.as_os_str().is_empty()) {
+					problems.push(locate(key, "tls_ca, tls_cert and tls_key are needed together"));
+				}
+			}
+
+			if problems.is_empty() {
+				config.sources = sources;
+				return Ok(config);
+			}
+		}
+
+		problems.sort_by_key(|problem| problem.line);
+
+		Err(problems)
+	}
+
+	// The config file to use when none is given on the command line.
+	pub fn default_file() -> String {
+		env::var("SWARM_CONFIG").unwrap_or_else(|_| DEFAULT_CONFIG.to_string())
+	}
+
+	pub fn env_var(key: &str) -> String {
+		format!("SWARM_{}", key.to_uppercase())
+	}
+
+	// Environment and command line values are plain strings, turn them into the toml value the
+	// field expects. List settings are comma separated.
+	fn raw_value(key: &str, raw: &str) -> Value {
+		match key {
+			"control_groups" | "control_read_groups" | "control_users" | "job_acl" | "job_submitters" | "log_formats" | "log_routes" | "log_targets" | "seeds" | "tags" => {
+				Value::Array(raw.split(',').map(str::trim).filter(|item| !item.is_empty()).map(|item| Value::String(item.to_string())).collect())
+			},
+			"log_compress" | "message_archive" => {
+				raw.parse::<bool>().map(Value::Boolean).unwrap_or_else(|_| Value::String(raw.to_string()))
+			},
+			"job_archive_after" | "job_archive_max_age" | "job_archive_max_rows" | "job_log_max_size" | "log_max_files" | "log_max_size" | "log_queue_size" | "message_archive_max_age" | "message_archive_max_rows" | "threads" => {
+				raw.parse::<i64>().map(Value::Integer).unwrap_or_else(|_| Value::String(raw.to_string()))
+			},
+			_ => Value::String(raw.to_string()),
+		}
+	}
+
+	// Render the config as toml, with a comment after each setting saying where its value came from.
+	pub fn show_effective(&self) -> String {
+		let values = Value::try_from(self).unwrap();
+		let mut out = String::from("[swarm]\n");
+
+		for (key, _, _) in FIELDS.iter() {
+			let source = self.sources.get(*key).cloned().unwrap_or(ConfigSource::Default);
+			let line = match values.get(key) {
+				Some(Value::String(secret)) if *key == "swarm_secret" && !secret.is_empty() => format!("{} = \"<hidden>\"", key),
+				Some(value) => format!("{} = {}", key, value),
+				None if *key == "id" => format!("# {} is generated on first start", key),
+				None => format!("# {} is not set", key),
+			};
+
+			out.push_str(&format!("{:<48} # {}\n", line, source));
+		}
+
+		out
+	}
+
+	// control_mode as a number, validation makes sure it parses.
+	pub fn control_mode(&self) -> u32 {
+		u32::from_str_radix(&self.control_mode, 8).unwrap_or(0o600)
+	}
+
+	pub fn id_file(&self) -> PathBuf {
+		self.db_dir.join("drone.id")
+	}
+
+	// Called once on drone start: generate an id and store it in db_dir if nothing provided one.
+	pub fn load_or_create_id(&mut self) -> io::Result<()> {
+		if !self.id.is_nil() {
+			return Ok(());
+		}
+
+		self.id = Uuid::new_v4();
+		fs::create_dir_all(&self.db_dir)?;
+		fs::write(self.id_file(), format!("{}\n", self.id))?;
+		self.sources.insert("id".to_string(), ConfigSource::IdFile);
+
+		Ok(())
+	}
+
+	// Range checks for values that deserialize fine but can't be used.
+	fn check_value(&self, key: &str) -> Option<&'static str> {
+		match key {
+			"control_mode" if !matches!(u32::from_str_radix(&self.control_mode, 8), Ok(mode) if mode <= 0o777 && mode & 0o600 == 0o600) => {
+				Some("control_mode must be an octal file mode that lets the owner read and write, e.g. 0660")
+			},
+			"log_queue_size" if self.log_queue_size == 0 => Some("log_queue_size must be at least 1"),
+			"port" if self.port == 0 => Some("port must not be 0"),
+			"swarm_secret" if self.swarm_secret.chars().count() < MIN_SECRET_LEN => Some("swarm_secret must be at least 16 characters"),
+			"threads" if self.threads == 0 => Some("threads must be at least 1"),
+			_ => None,
+		}
+	}
+
+	// Compare against a freshly loaded config and split the changed settings into those that can be
+	// applied to a running drone and those that only take effect after a restart.
+	pub fn changes(&self, new: &Config) -> (Vec<&'static str>, Vec<&'static str>) {
+		let mut live = Vec::new();
+		let mut restart = Vec::new();
+
+		if self.error_log != new.error_log {
+			live.push("error_log");
+		}
+		if self.job_acl != new.job_acl {
+			live.push("job_acl");
+		}
+		if self.job_archive_after != new.job_archive_after {
+			live.push("job_archive_after");
+		}
+		if self.job_archive_max_age != new.job_archive_max_age {
+			live.push("job_archive_max_age");
+		}
+		if self.job_archive_max_rows != new.job_archive_max_rows {
+			live.push("job_archive_max_rows");
+		}
+		if self.job_log_max_size != new.job_log_max_size {
+			live.push("job_log_max_size");
+		}
+		if self.job_submitters != new.job_submitters {
+			live.push("job_submitters");
+		}
+		if self.log_compress != new.log_compress {
+			live.push("log_compress");
+		}
+		if self.log_dir != new.log_dir {
+			live.push("log_dir");
+		}
+		if self.log_formats != new.log_formats {
+			live.push("log_formats");
+		}
+		if self.log_level != new.log_level {
+			live.push("log_level");
+		}
+		if self.log_max_files != new.log_max_files {
+			live.push("log_max_files");
+		}
+		if self.log_max_size != new.log_max_size {
+			live.push("log_max_size");
+		}
+		if self.log_rotate != new.log_rotate {
+			live.push("log_rotate");
+		}
+		if self.log_routes != new.log_routes {
+			live.push("log_routes");
+		}
+		if self.log_targets != new.log_targets {
+			live.push("log_targets");
+		}
+		if self.message_archive != new.message_archive {
+			live.push("message_archive");
+		}
+		if self.message_archive_max_age != new.message_archive_max_age {
+			live.push("message_archive_max_age");
+		}
+		if self.message_archive_max_rows != new.message_archive_max_rows {
+			live.push("message_archive_max_rows");
+		}
+		if self.seeds != new.seeds {
+			live.push("seeds");
+		}
+		if self.system_log != new.system_log {
+			live.push("system_log");
+		}
+		if self.tags != new.tags {
+			live.push("tags");
+		}
+		if self.threads != new.threads {
+			live.push("threads");
+		}
+
+		if self.address != new.address {
+			restart.push("address");
+		}
+		if self.control_dir != new.control_dir {
+			restart.push("control_dir");
+		}
+		if self.control_groups != new.control_groups {
+			restart.push("control_groups");
+		}
+		if self.control_mode != new.control_mode {
+			restart.push("control_mode");
+		}
+		if self.control_read_groups != new.control_read_groups {
+			restart.push("control_read_groups");
+		}
+		if self.control_users != new.control_users {
+			restart.push("control_users");
+		}
+		if self.db_dir != new.db_dir {
+			restart.push("db_dir");
+		}
+		if self.db_file != new.db_file {
+			restart.push("db_file");
+		}
+		if self.id != new.id {
+			restart.push("id");
+		}
+		if self.log_overflow != new.log_overflow {
+			restart.push("log_overflow");
+		}
+		if self.log_queue_size != new.log_queue_size {
+			restart.push("log_queue_size");
+		}
+		if self.port != new.port {
+			restart.push("port");
+		}
+		if self.store != new.store {
+			restart.push("store");
+		}
+		if self.swarm_secret != new.swarm_secret {
+			restart.push("swarm_secret");
+		}
+		if self.tls_ca != new.tls_ca {
+			restart.push("tls_ca");
+		}
+		if self.tls_cert != new.tls_cert {
+			restart.push("tls_cert");
+		}
+		if self.tls_key != new.tls_key {
+			restart.push("tls_key");
+		}
+
+		(live, restart)
+	}
+
+	// The config a running drone goes on with after a reload: the settings that can change live from new,
+	// everything else (see changes) from the config it was started with, so that it still describes the drone.
+	pub fn reloaded(&self, new: Config) -> Config {
+		let mut sources = self.sources.clone();
+		for key in self.changes(&new).0 {
+			if let Some(source) = new.sources.get(key) {
+				sources.insert(key.to_string(), source.clone());
+			}
+		}
+
+		Config {
+			error_log: new.error_log,
+			job_acl: new.job_acl,
+			job_archive_after: new.job_archive_after,
+			job_archive_max_age: new.job_archive_max_age,
+			job_archive_max_rows: new.job_archive_max_rows,
+			job_log_max_size: new.job_log_max_size,
+			job_submitters: new.job_submitters,
+			log_compress: new.log_compress,
+			log_dir: new.log_dir,
+			log_formats: new.log_formats,
+			log_level: new.log_level,
+			log_max_files: new.log_max_files,
+			log_max_size: new.log_max_size,
+			log_rotate: new.log_rotate,
+			log_routes: new.log_routes,
+			log_targets: new.log_targets,
+			message_archive: new.message_archive,
+			message_archive_max_age: new.message_archive_max_age,
+			message_archive_max_rows: new.message_archive_max_rows,
+			seeds: new.seeds,
+			sources,
+			system_log: new.system_log,
+			tags: new.tags,
+			threads: new.threads,
+			..self.clone()
+		}
+	}
+
+	// Only used by dronectl config init, the drone itself never writes its config file.
+	pub fn save(&self) -> io::Result<()> {
+		let mut config_toml = String::from("[swarm]");
+		config_toml.push('\n');
+		config_toml = config_toml + &toml::to_string(&self).unwrap();
+
+		// The file holds the swarm_secret, keep it to the owner.
+		let mut file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&self.file)?;
+		file.set_permissions(fs::Permissions::from_mode(0o600))?;
+		file.write_all(config_toml.as_bytes())
+	}
+
+	// Ports were written as strings by earlier versions, so accept either form.
+	fn deserialize_port<'de, D>(deserializer: D) -> Result<u16, D::Error> where D: Deserializer<'de> {
+		struct PortVisitor;
+
+		impl<'de> Visitor<'de> for PortVisitor {
+			type Value = u16;
+
+			fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+				formatter.write_str("a port number between 0 and 65535")
+			}
+
+			fn visit_i64<E>(self, v: i64) -> Result<u16, E> where E: de::Error {
+				u16::try_from(v).map_err(|_| E::custom(format!("invalid port {}, expected a port number between 0 and 65535", v)))
+			}
+
+			fn visit_str<E>(self, v: &str) -> Result<u16, E> where E: de::Error {
+				v.parse::<u16>().map_err(|_| E::custom(format!("invalid port \"{}\", expected a port number between 0 and 65535", v)))
+			}
+		}
+
+		deserializer.deserialize_any(PortVisitor)
+	}
+}
+
+// A single problem found while validating a config file.
+#[derive(Clone, Debug)]
+pub struct ConfigProblem {
+	pub context:					Option<String>,
+	pub key:						Option<String>,
+	pub line:						Option<usize>,
+	pub message:					String,
+}
+
+impl ConfigProblem {
+	pub fn new(key: Option<&str>, message: &str) -> Self {
+		ConfigProblem {
+			context: None,
+			key: key.map(|k| k.to_string()),
+			line: None,
+			message: message.to_string(),
+		}
+	}
+
+	// Attach the line (in the [swarm] table) where key is set.
+	fn at(content: &str, key: &str, message: &str) -> Self {
+		let mut problem = ConfigProblem::new(Some(key), message);
+		let mut in_swarm = false;
+
+		for (i, line) in content.lines().enumerate() {
+			let trimmed = line.trim();
+			if trimmed.starts_with('[') {
+				in_swarm = trimmed == "[swarm]";
+			} else if in_swarm && trimmed.split('=').next().map(|k| k.trim().trim_matches('"')) == Some(key) {
+				problem.line = Some(i + 1);
+				problem.context = Some(trimmed.to_string());
+				break;
+			}
+		}
+
+		problem
+	}
+}
+
+impl fmt::Display for ConfigProblem {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		if let Some(line) = self.line {
+			write!(f, "line {}: ", line)?;
+		}
+		if let Some(key) = &self.key {
+			write!(f, "{}: ", key)?;
+		}
+		write!(f, "{}", self.message)?;
+		if let Some(context) = &self.context {
+			write!(f, "\n\t{}", context)?;
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn config_changes_test() {
+		let old = Config::default();
+		let mut new = old.clone();
+		new.port = 9080;
+		new.tags = vec![String::from("gpu")];
+		new.threads = 4;
+
+		let (live, restart) = old.changes(&new);
+		assert_eq!(live, vec!["tags", "threads"]);
+		assert_eq!(restart, vec!["port"]);
+	}
+
+	#[test]
+	fn config_reloaded_test() {
+		let old = Config::default();
+		let mut new = old.clone();
+		new.control_dir = PathBuf::from("/run/elsewhere");
+		new.log_queue_size = 10;
+		new.port = 9080;
+		new.store = StoreKind::Memory;
+		new.swarm_secret = String::from("0123456789abcdef");
+		new.tags = vec![String::from("gpu")];
+		new.threads = 4;
+		new.tls_ca = PathBuf::from("ca.pem");
+		new.sources.insert(String::from("threads"), ConfigSource::File);
+		new.sources.insert(String::from("port"), ConfigSource::File);
+
+		// The live settings are taken, the ones that need a restart stay what the drone runs with.
+		let reloaded = old.reloaded(new);
+		assert_eq!(reloaded.tags, vec![String::from("gpu")]);
+		assert_eq!(reloaded.threads, 4);
+		assert_eq!(reloaded.sources.get("threads"), Some(&ConfigSource::File));
+		assert_eq!(reloaded.control_dir, old.control_dir);
+		assert_eq!(reloaded.log_queue_size, old.log_queue_size);
+		assert_eq!(reloaded.port, old.port);
+		assert_eq!(reloaded.sources.get("port"), old.sources.get("port"));
+		assert_eq!(reloaded.store, old.store);
+		assert_eq!(reloaded.swarm_secret, old.swarm_secret);
+		assert_eq!(reloaded.tls_ca, old.tls_ca);
+
+		let (live, restart) = old.changes(&reloaded);
+		assert_eq!(live, vec!["tags", "threads"]);
+		assert!(restart.is_empty());
+	}
+
+	#[test]
+	fn config_parse_test() {
+		let config = Config::parse("[swarm]\nport = 9080\nseeds = [\"10.0.0.2:9079\"]\n").unwrap();
+		assert_eq!(config.port, 9080);
+		assert_eq!(config.seeds, vec!["10.0.0.2:9079".parse::<SocketAddr>().unwrap()]);
+
+		// Ports written as strings by older versions still load.
+		assert_eq!(Config::parse("[swarm]\nport = \"9081\"\n").unwrap().port, 9081);
+
+		assert_eq!(Config::parse("[swarm]\nstore = \"memory\"\n").unwrap().store, StoreKind::Memory);
+
+		let config = Config::parse("[swarm]\nlog_level = \"debug\"\nlog_routes = [\"warn+=error\", \"debug=debug.log\"]\n").unwrap();
+		assert_eq!(config.log_level, LogLevel::Debug);
+		assert_eq!(config.log_routes.len(), 2);
+		assert!(Config::parse("[swarm]\nlog_level = \"loud\"\n").is_err());
+		assert!(Config::parse("[swarm]\nlog_routes = [\"warn+\"]\n").is_err());
+
+		assert_eq!(Config::parse("[swarm]\nlog_overflow = \"block\"\n").unwrap().log_overflow, LogOverflow::Block);
+		assert!(Config::parse("[swarm]\nlog_queue_size = 0\n").is_err());
+
+		assert!(Config::parse("[swarm]\ncontrol_mode = \"0660\"\n").is_ok());
+		assert!(Config::parse("[swarm]\ncontrol_mode = \"0066\"\n").is_err());
+		assert!(Config::parse("[swarm]\ncontrol_mode = \"rw\"\n").is_err());
+
+		// An empty or short secret is refused wherever it comes from.
+		assert!(Config::parse("[swarm]\nswarm_secret = \"\"\n").is_err());
+		assert!(Config::parse("[swarm]\nswarm_secret = \"short\"\n").is_err());
+		assert!(Config::parse("[swarm]\nswarm_secret = \"0123456789abcdef\"\n").is_ok());
+
+		// ACL rules name known submitters.
+		let problems = Config::parse("[swarm]\njob_acl = [\"bob=tag:gpu\"]\n").unwrap_err();
+		assert_eq!(problems[0].key.as_deref(), Some("job_acl"));
+		assert!(Config::parse("[swarm]\njob_acl = [\"*=command:/usr/bin/*\"]\n").is_ok());
+
+		// TLS needs the CA, the certificate and its key.
+		let problems = Config::parse("[swarm]\ntls_cert = \"drone.pem\"\n").unwrap_err();
+		assert_eq!(problems[0].key.as_deref(), Some("tls_cert"));
+		assert!(Config::parse("[swarm]\ntls_ca = \"ca.pem\"\ntls_cert = \"drone.pem\"\ntls_key = \"drone.key\"\n").is_ok());
+
+		let problems = Config::parse("[swarm]\nid = \"not-a-uuid\"\nport = 70000\ncolour = \"blue\"\n").unwrap_err();
+		let lines: Vec<Option<usize>> = problems.iter().map(|p| p.line).collect();
+		assert_eq!(problems.len(), 3);
+		assert!(lines.contains(&Some(2)) && lines.contains(&Some(3)) && lines.contains(&Some(4)));
+	}
+
+	#[test]
+	fn config_layered_test() {
+		let env = vec![
+			(String::from("SWARM_LOG_TARGETS"), String::from("swarm::db=debug, swarm::drone=warn")),
+			(String::from("SWARM_MESSAGE_ARCHIVE"), String::from("true")),
+			(String::from("SWARM_PORT"), String::from("9090")),
+			(String::from("SWARM_TAGS"), String::from("gpu, cuda")),
+		];
+		let cli = vec![(String::from("port"), String::from("9091"))];
+
+		let config = Config::layered(Some("[swarm]\nport = 9080\nthreads = 2\n"), &env, &cli).unwrap();
+		assert_eq!(config.log_targets.len(), 2);
+		assert_eq!(config.log_targets[1].level, LogLevel::Warn);
+		assert!(config.message_archive);
+		assert_eq!(config.store, StoreKind::Sqlite);
+		assert_eq!(config.port, 9091);
+		assert_eq!(config.tags, vec![String::from("gpu"), String::from("cuda")]);
+		assert_eq!(config.threads, 2);
+		assert_eq!(config.sources["address"], ConfigSource::Default);
+		assert_eq!(config.sources["port"], ConfigSource::Cli(String::from("port")));
+		assert_eq!(config.sources["tags"], ConfigSource::Env(String::from("SWARM_TAGS")));
+		assert_eq!(config.sources["threads"], ConfigSource::File);
+
+		let problems = Config::layered(None, &[(String::from("SWARM_THREADS"), String::from("many"))], &[]).unwrap_err();
+		assert_eq!(problems[0].context, Some(String::from("SWARM_THREADS=many")));
+	}
+
+	#[test]
+	fn config_load_test() {
+		let dir = std::env::temp_dir().join(format!("swarm_config_{}", Uuid::new_v4()));
+		let file = dir.join("drone.cfg.toml");
+		fs::create_dir_all(&dir).unwrap();
+		// A drone can't run without a secret.
+		fs::write(&file, format!("[swarm]\ndb_dir = \"{}\"\n", dir.display())).unwrap();
+		let problems = Config::load(&file, &[]).unwrap_err();
+		assert_eq!(problems[0].key.as_deref(), Some("swarm_secret"));
+
+		fs::write(&file, format!("[swarm]\n# operator comment\ndb_dir = \"{}\"\nswarm_secret = \"0123456789abcdef\"\n", dir.display())).unwrap();
+		let content = fs::read_to_string(&file).unwrap();
+
+		// The generated id is kept in db_dir and reused, the config file is left untouched.
+		let mut first = Config::load(&file, &[]).unwrap();
+		assert!(first.id.is_nil());
+		first.load_or_create_id().unwrap();
+		let second = Config::load(&file, &[]).unwrap();
+		assert!(!first.id.is_nil());
+		assert_eq!(first.id, second.id);
+		assert_eq!(fs::read_to_string(first.id_file()).unwrap().trim(), first.id.to_string());
+		assert_eq!(fs::read_to_string(&file).unwrap(), content);
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -0,0 +1,282 @@
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+
+
+// dronectl commands that only read, see control_read_groups. Everything else changes the drone.
+pub const READ_ONLY_COMMANDS: [&str; 4] = ["ARCHIVE_EXPORT", "JOB_LOG", "SEARCH", "SWARM_LOGS"];
+
+// Where the drone with this pid listens for dronectl.
+pub fn socket_path(dir: &Path, pid: impl std::fmt::Display) -> PathBuf {
+	dir.join(format!("swarm_drone_{}.sock", pid))
+}
+
+// Create the control socket in control_dir. A directory created here is made private to the classes
+// control_mode lets use the socket (search permission only, so it can't be listed). One that already
+// exists is left as it is, but has to belong to the drone's user and be writable by nobody else, so
+// that the socket is bound before anyone else can get to it.
+pub fn bind(config: &Config, pid: u32) -> io::Result<UnixListener> {
+	let mode = config.control_mode();
+	let dir_mode = 0o700 | (if mode & 0o070 != 0 { 0o010 } else { 0 }) | (if mode & 0o007 != 0 { 0o001 } else { 0 });
+
+	if let Some(parent) = config.control_dir.parent() {
+		fs::create_dir_all(parent)?;
+	}
+	match fs::DirBuilder::new().mode(dir_mode).create(&config.control_dir) {
+		// The umask may have taken bits away.
+		Ok(()) => fs::set_permissions(&config.control_dir, fs::Permissions::from_mode(dir_mode))?,
+		Err(err) if err.kind() == io::ErrorKind::AlreadyExists => check_private(&config.control_dir)?,
+		Err(err) => return Err(err),
+	}
+
+	let path = socket_path(&config.control_dir, pid);
+	if path.exists() {
+		fs::remove_file(&path)?;
+	}
+	let listener = UnixListener::bind(&path)?;
+	fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
+
+	Ok(listener)
+}
+
+// An existing control_dir has to be a directory (not a link to one) of the drone's effective user, with
+// no group or other write permission.
+fn check_private(dir: &Path) -> io::Result<()> {
+	let metadata = fs::symlink_metadata(dir)?;
+	// Safe: geteuid can't fail.
+	let euid = unsafe { libc::geteuid() };
+
+	if !metadata.is_dir() {
+		return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("control_dir {} is not a directory", dir.display())));
+	}
+	if metadata.uid() != euid {
+		return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("control_dir {} belongs to uid {}, not to the drone's uid {}", dir.display(), metadata.uid(), euid)));
+	}
+	if metadata.mode() & 0o022 != 0 {
+		return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("control_dir {} is writable by group or others (mode {:o})", dir.display(), metadata.mode() & 0o777)));
+	}
+
+	Ok(())
+}
+
+// Who is at the other end of a control socket connection, from the kernel (SO_PEERCRED), not from
+// anything the client says.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerCred {
+	pub gid:						u32,
+	// Supplementary groups, the primary group is gid.
+	pub groups:						Vec<u32>,
+	pub pid:						i32,
+	pub uid:						u32,
+}
+
+impl PeerCred {
+	pub fn of(stream: &UnixStream) -> io::Result<Self> {
+		let fd = stream.as_raw_fd();
+
+		let mut cred = libc::ucred { pid: 0, uid: 0, gid: 0 };
+		let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+		// Safe: cred and len describe a buffer of the size the kernel writes for SO_PEERCRED.
+		if unsafe { libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_PEERCRED, &mut cred as *mut _ as *mut libc::c_void, &mut len) } != 0 {
+			return Err(io::Error::last_os_error());
+		}
+
+		// SO_PEERGROUPS says how much room it needs when the buffer is too small.
+		let mut groups: Vec<libc::gid_t> = vec![0; 64];
+		loop {
+			let mut len = (groups.len() * mem::size_of::<libc::gid_t>()) as libc::socklen_t;
+			// Safe: groups and len describe the buffer, the kernel never writes past len.
+			let result = unsafe { libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_PEERGROUPS, groups.as_mut_ptr() as *mut libc::c_void, &mut len) };
+			let count = len as usize / mem::size_of::<libc::gid_t>();
+
+			if result == 0 {
+				groups.truncate(count);
+				break;
+			}
+			let err = io::Error::last_os_error();
+			if err.raw_os_error() != Some(libc::ERANGE) || count <= groups.len() {
+				return Err(err);
+			}
+			groups.resize(count, 0);
+		}
+
+		Ok(PeerCred {
+			gid: cred.gid,
+			groups,
+			pid: cred.pid,
+			uid: cred.uid,
+		})
+	}
+
+	fn in_any(&self, gids: &[u32]) -> bool {
+		gids.contains(&self.gid) || self.groups.iter().any(|group| gids.contains(group))
+	}
+}
+
+// What a control socket client may do.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+	All,
+	Denied,
+	ReadOnly,
+}
+
+impl Access {
+	pub fn allows(&self, command: &str) -> bool {
+		match self {
+			Access::All => true,
+			Access::Denied => false,
+			Access::ReadOnly => READ_ONLY_COMMANDS.contains(&command),
+		}
+	}
+}
+
+// control_users, control_groups and control_read_groups with names resolved to ids. The drone's own
+// user and root can always use the control socket.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ControlAccess {
+	groups:							Vec<u32>,
+	owner:							u32,
+	read_groups:					Vec<u32>,
+	users:							Vec<u32>,
+}
+
+impl ControlAccess {
+	pub fn from_config(config: &Config) -> Result<Self, String> {
+		let users = config.control_users.iter().map(|user| resolve_user(user)).collect::<Result<Vec<u32>, String>>()?;
+		let groups = config.control_groups.iter().map(|group| resolve_group(group)).collect::<Result<Vec<u32>, String>>()?;
+		let read_groups = config.control_read_groups.iter().map(|group| resolve_group(group)).collect::<Result<Vec<u32>, String>>()?;
+
+		Ok(ControlAccess {
+			groups,
+			// Safe: geteuid can't fail.
+			owner: unsafe { libc::geteuid() },
+			read_groups,
+			users,
+		})
+	}
+
+	pub fn access(&self, peer: &PeerCred) -> Access {
+		if peer.uid == 0 || peer.uid == self.owner || self.users.contains(&peer.uid) || peer.in_any(&self.groups) {
+			Access::All
+		} else if peer.in_any(&self.read_groups) {
+			Access::ReadOnly
+		} else {
+			Access::Denied
+		}
+	}
+}
+
+// A user name or numeric uid.
+fn resolve_user(user: &str) -> Result<u32, String> {
+	if let Ok(uid) = user.parse::<u32>() {
+		return Ok(uid);
+	}
+
+	let name = CString::new(user).map_err(|_| format!("invalid user name \"{}\"", user))?;
+	let mut buffer = vec![0 as libc::c_char; 4096];
+	// Safe: every pointer refers to a live local of the right type, and buffer's length is passed along.
+	let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+	let mut result = std::ptr::null_mut();
+	let err = unsafe { libc::getpwnam_r(name.as_ptr(), &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+
+	if err != 0 || result.is_null() {
+		return Err(format!("unknown user \"{}\"", user));
+	}
+
+	Ok(passwd.pw_uid)
+}
+
+// A group name or numeric gid.
+fn resolve_group(group: &str) -> Result<u32, String> {
+	if let Ok(gid) = group.parse::<u32>() {
+		return Ok(gid);
+	}
+
+	let name = CString::new(group).map_err(|_| format!("invalid group name \"{}\"", group))?;
+	let mut buffer = vec![0 as libc::c_char; 16384];
+	// Safe: as in resolve_user.
+	let mut entry: libc::group = unsafe { mem::zeroed() };
+	let mut result = std::ptr::null_mut();
+	let err = unsafe { libc::getgrnam_r(name.as_ptr(), &mut entry, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+
+	if err != 0 || result.is_null() {
+		return Err(format!("unknown group \"{}\"", group));
+	}
+
+	Ok(entry.gr_gid)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use uuid::Uuid;
+
+	#[test]
+	fn control_access_test() {
+		let access = ControlAccess {
+			groups: vec![100],
+			owner: 1000,
+			read_groups: vec![200],
+			users: vec![1001],
+		};
+		let peer = |uid, gid, groups: &[u32]| PeerCred { gid, groups: groups.to_vec(), pid: 1, uid };
+
+		assert_eq!(access.access(&peer(0, 0, &[])), Access::All);
+		assert_eq!(access.access(&peer(1000, 1000, &[])), Access::All);
+		assert_eq!(access.access(&peer(1001, 1001, &[])), Access::All);
+		assert_eq!(access.access(&peer(1002, 100, &[])), Access::All);
+		assert_eq!(access.access(&peer(1002, 1002, &[5, 200])), Access::ReadOnly);
+		assert_eq!(access.access(&peer(1002, 1002, &[5])), Access::Denied);
+
+		assert!(Access::ReadOnly.allows("SEARCH"));
+		assert!(!Access::ReadOnly.allows("SHUTDOWN"));
+		assert!(!Access::Denied.allows("SEARCH"));
+
+		assert_eq!(resolve_user("root"), Ok(0));
+		assert_eq!(resolve_group("4242"), Ok(4242));
+		assert!(resolve_user("no-such-user-here").is_err());
+	}
+
+	#[test]
+	fn control_socket_test() {
+		let dir = std::env::temp_dir().join(format!("swarm_control_{}", Uuid::new_v4()));
+		let config = Config {
+			control_dir: dir.join("run"),
+			..Config::default()
+		};
+
+		let listener = bind(&config, 42).unwrap();
+		let path = socket_path(&config.control_dir, 42);
+		assert_eq!(fs::metadata(&config.control_dir).unwrap().permissions().mode() & 0o777, 0o700);
+		assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+		// The kernel vouches for the client.
+		let client = UnixStream::connect(&path).unwrap();
+		let (server, _) = listener.accept().unwrap();
+		let cred = PeerCred::of(&server).unwrap();
+		assert_eq!(cred.uid, unsafe { libc::geteuid() });
+		assert_eq!(cred.pid, std::process::id() as i32);
+		assert_eq!(ControlAccess::from_config(&config).unwrap().access(&cred), Access::All);
+		drop(client);
+		drop(listener);
+
+		// An existing directory keeps its mode, and is only used if nobody else can write to it.
+		fs::set_permissions(&config.control_dir, fs::Permissions::from_mode(0o750)).unwrap();
+		bind(&config, 43).unwrap();
+		assert_eq!(fs::metadata(&config.control_dir).unwrap().permissions().mode() & 0o777, 0o750);
+
+		fs::set_permissions(&config.control_dir, fs::Permissions::from_mode(0o777)).unwrap();
+		assert_eq!(bind(&config, 44).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+		assert!(!socket_path(&config.control_dir, 44).exists());
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -0,0 +1,57 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum DatabaseError {
+	// A reference to an unknown drone or job (what a foreign key catches in sqlite).
+	Constraint(String),
+	// The table layout doesn't match any known schema version (e.g. a partially created database).
+	Corruption(String),
+	Io(io::Error),
+	// The database was migrated by a newer drone than this one.
+	SchemaMismatch {
+		found:						u32,
+		supported:					u32,
+	},
+	Sqlite(rusqlite::Error),
+	// The store doesn't support the operation, e.g. backing up an in-memory store.
+	Unsupported(String),
+}
+
+impl fmt::Display for DatabaseError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			DatabaseError::Constraint(msg) => write!(f, "database constraint failed: {}", msg),
+			DatabaseError::Corruption(msg) => write!(f, "database corruption: {}", msg),
+			DatabaseError::Io(err) => write!(f, "database io error: {}", err),
+			DatabaseError::SchemaMismatch { found, supported } => {
+				write!(f, "database schema version {} is newer than the {} supported by this drone", found, supported)
+			},
+			DatabaseError::Sqlite(err) => write!(f, "sqlite error: {}", err),
+			DatabaseError::Unsupported(msg) => write!(f, "not supported: {}", msg),
+		}
+	}
+}
+
+impl Error for DatabaseError {
+	fn source(&self) -> Option<&(dyn Error + 'static)> {
+		match self {
+			DatabaseError::Io(err) => Some(err),
+			DatabaseError::Sqlite(err) => Some(err),
+			_ => None,
+		}
+	}
+}
+
+impl From<io::Error> for DatabaseError {
+	fn from(err: io::Error) -> Self {
+		DatabaseError::Io(err)
+	}
+}
+
+impl From<rusqlite::Error> for DatabaseError {
+	fn from(err: rusqlite::Error) -> Self {
+		DatabaseError::Sqlite(err)
+	}
+}
@@ -0,0 +1,265 @@
+use chrono::{Duration, Utc};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use uuid::Uuid;
+
+use crate::models::{ArchiveQuery, ArchivedJob, Host, JobQuery, JobRecord, JobStatus, Job, MessageRecord, SearchPage};
+use super::{DatabaseError, Result, Store};
+
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+struct MemoryJob {
+	active:							bool,
+	created:						String,
+	finished:						Option<String>,
+	output:							Option<String>,
+	// Insertion order, breaks ties between jobs created in the same second.
+	seq:							u64,
+	status:							JobStatus,
+	tags:							BTreeSet<String>,
+}
+
+// A store that lives and dies with the drone process, for tests and ephemeral drones. It follows the
+// sqlite store's rules, including rejecting ownership of unknown drones or jobs.
+#[derive(Default)]
+pub struct MemoryStore {
+	archive:						Vec<ArchivedJob>,
+	hosts:							BTreeMap<Uuid, Host>,
+	jobs:							BTreeMap<Uuid, MemoryJob>,
+	messages:						Vec<MessageRecord>,
+	// (drone id, job id)
+	ownership:						BTreeSet<(Uuid, Uuid)>,
+	seq:							u64,
+}
+
+impl MemoryStore {
+	pub fn new() -> Self {
+		MemoryStore::default()
+	}
+
+	fn now() -> String {
+		Utc::now().format(TIME_FORMAT).to_string()
+	}
+
+	fn days_ago(days: u64) -> String {
+		(Utc::now() - Duration::days(days as i64)).format(TIME_FORMAT).to_string()
+	}
+
+	fn job_mut(&mut self, job_id: Uuid) -> Option<&mut MemoryJob> {
+		self.jobs.get_mut(&job_id)
+	}
+}
+
+impl Store for MemoryStore {
+	fn get_host(&self, id: Uuid) -> Result<Option<Host>> {
+		Ok(self.hosts.get(&id).cloned())
+	}
+
+	fn get_hosts(&self) -> Result<Vec<Host>> {
+		Ok(self.hosts.values().cloned().collect())
+	}
+
+	fn update_host(&mut self, host: &Host) -> Result<()> {
+		self.hosts.insert(host.id, host.clone());
+
+		Ok(())
+	}
+
+	fn update_hosts(&mut self, hosts: &[Host]) -> Result<()> {
+		for host in hosts {
+			self.update_host(host)?;
+		}
+
+		Ok(())
+	}
+
+	fn get_job_status(&self, job_id: Uuid) -> Result<Option<JobStatus>> {
+		Ok(self.jobs.get(&job_id).map(|job| job.status))
+	}
+
+	fn insert_job(&mut self, job: &Job) -> Result<()> {
+		if self.jobs.contains_key(&job.id) {
+			return Err(DatabaseError::Constraint(format!("job {} already exists", job.id)));
+		}
+
+		self.seq += 1;
+		self.jobs.insert(job.id, MemoryJob {
+			active: true,
+			created: MemoryStore::now(),
+			finished: None,
+			output: None,
+			seq: self.seq,
+			status: JobStatus::New,
+			tags: job.tags.iter().cloned().collect(),
+		});
+
+		Ok(())
+	}
+
+	fn job_tags(&self, job_id: Uuid) -> Result<Vec<String>> {
+		Ok(self.jobs.get(&job_id).map(|job| job.tags.iter().cloned().collect()).unwrap_or_default())
+	}
+
+	fn search_jobs(&self, query: &JobQuery) -> Result<SearchPage> {
+		let mut matches: Vec<(&Uuid, &MemoryJob)> = self.jobs.iter()
+			.filter(|(_, job)| query.since.as_ref().is_none_or(|since| job.created >= *since))
+			.filter(|(_, job)| query.until.as_ref().is_none_or(|until| job.created < *until))
+			.filter(|(_, job)| query.status.is_none_or(|status| job.status == status))
+			.filter(|(_, job)| query.tag.as_ref().is_none_or(|tag| job.tags.contains(tag)))
+			.filter(|(id, _)| query.drone.is_none_or(|drone| self.ownership.contains(&(drone, **id))))
+			.filter(|(_, job)| query.text.as_ref().is_none_or(|text| job.output.as_ref().is_some_and(|output| output.contains(text.as_str()))))
+			.collect();
+
+		// Newest first, like the sqlite store.
+		matches.sort_by(|(a_id, a), (b_id, b)| b.created.cmp(&a.created).then(b.seq.cmp(&a.seq)).then(a_id.cmp(b_id)));
+
+		let mut jobs = Vec::new();
+		for (id, job) in matches.iter().skip(query.offset).take(query.limit) {
+			jobs.push(JobRecord {
+				created: job.created.clone(),
+				finished: job.finished.clone(),
+				id: **id,
+				owners: self.job_owners(**id)?,
+				status: job.status,
+				tags: job.tags.iter().cloned().collect(),
+			});
+		}
+
+		Ok(SearchPage {
+			jobs,
+			limit: query.limit,
+			offset: query.offset,
+			total: matches.len(),
+		})
+	}
+
+	fn update_job_output(&mut self, job_id: Uuid, output: &str) -> Result<()> {
+		if let Some(job) = self.job_mut(job_id) {
+			job.output = Some(output.to_string());
+		}
+
+		Ok(())
+	}
+
+	fn update_job_status(&mut self, job_id: Uuid, status: JobStatus) -> Result<()> {
+		if let Some(job) = self.job_mut(job_id) {
+			job.status = status;
+			job.active = !status.is_done();
+			job.finished = if status.is_done() { Some(MemoryStore::now()) } else { None };
+		}
+
+		Ok(())
+	}
+
+	fn job_owners(&self, job_id: Uuid) -> Result<Vec<Uuid>> {
+		Ok(self.ownership.iter().filter(|(_, job)| *job == job_id).map(|(drone, _)| *drone).collect())
+	}
+
+	fn owned_jobs(&self, drone_id: Uuid) -> Result<Vec<Uuid>> {
+		Ok(self.ownership.iter().filter(|(drone, _)| *drone == drone_id).map(|(_, job)| *job).collect())
+	}
+
+	fn remove_job_owner(&mut self, drone_id: Uuid, job_id: Uuid) -> Result<()> {
+		self.ownership.remove(&(drone_id, job_id));
+
+		Ok(())
+	}
+
+	fn set_job_owner(&mut self, drone_id: Uuid, job_id: Uuid) -> Result<()> {
+		if !self.hosts.contains_key(&drone_id) {
+			return Err(DatabaseError::Constraint(format!("unknown drone {}", drone_id)));
+		}
+		if !self.jobs.contains_key(&job_id) {
+			return Err(DatabaseError::Constraint(format!("unknown job {}", job_id)));
+		}
+
+		self.ownership.insert((drone_id, job_id));
+
+		Ok(())
+	}
+
+	fn archive_jobs(&mut self, job_id: Option<Uuid>, finished_before: Option<&str>) -> Result<usize> {
+		let archive_ids: Vec<Uuid> = self.jobs.iter()
+			.filter(|(_, job)| !job.active)
+			.filter(|(id, _)| job_id.is_none_or(|job_id| **id == job_id))
+			.filter(|(_, job)| finished_before.is_none_or(|before| job.finished.as_deref().is_some_and(|finished| finished < before)))
+			.map(|(id, _)| *id)
+			.collect();
+
+		let archived = MemoryStore::now();
+		for id in archive_ids.iter() {
+			let owners = self.job_owners(*id)?;
+			let job = self.jobs.remove(id).unwrap();
+
+			self.ownership.retain(|(_, job_id)| job_id != id);
+			self.archive.push(ArchivedJob {
+				archived: archived.clone(),
+				created: job.created,
+				finished: job.finished,
+				id: *id,
+				output: job.output,
+				owners,
+				status: job.status,
+				tags: job.tags.into_iter().collect(),
+			});
+		}
+
+		Ok(archive_ids.len())
+	}
+
+	fn archived_jobs(&self, query: &ArchiveQuery) -> Result<Vec<ArchivedJob>> {
+		let mut jobs: Vec<&ArchivedJob> = self.archive.iter()
+			.filter(|job| query.since.as_ref().is_none_or(|since| job.finished.as_ref().is_some_and(|finished| finished >= since)))
+			.filter(|job| query.until.as_ref().is_none_or(|until| job.finished.as_ref().is_some_and(|finished| finished < until)))
+			.collect();
+
+		jobs.sort_by(|a, b| a.finished.cmp(&b.finished).then(a.id.cmp(&b.id)));
+
+		Ok(jobs.into_iter().skip(query.offset).take(query.limit).cloned().collect())
+	}
+
+	fn prune_job_archive(&mut self, max_age_days: u64, max_rows: usize) -> Result<usize> {
+		let before = self.archive.len();
+
+		if max_age_days > 0 {
+			let oldest = MemoryStore::days_ago(max_age_days);
+			self.archive.retain(|job| job.finished.as_ref().is_none_or(|finished| *finished >= oldest));
+		}
+		if max_rows > 0 && self.archive.len() > max_rows {
+			// Keep the most recently finished jobs.
+			self.archive.sort_by(|a, b| b.finished.cmp(&a.finished));
+			self.archive.truncate(max_rows);
+		}
+
+		Ok(before - self.archive.len())
+	}
+
+	fn archive_message(&mut self, record: &MessageRecord) -> Result<()> {
+		self.messages.push(record.clone());
+
+		Ok(())
+	}
+
+	fn archived_messages(&self, limit: usize) -> Result<Vec<MessageRecord>> {
+		Ok(self.messages.iter().rev().take(limit).cloned().collect())
+	}
+
+	fn prune_messages(&mut self, max_age_days: u64, max_rows: usize) -> Result<usize> {
+		let before = self.messages.len();
+
+		if max_age_days > 0 {
+			let oldest = MemoryStore::days_ago(max_age_days);
+			self.messages.retain(|record| record.timestamp >= oldest);
+		}
+		if max_rows > 0 && self.messages.len() > max_rows {
+			let overflow = self.messages.len() - max_rows;
+			self.messages.drain(..overflow);
+		}
+
+		Ok(before - self.messages.len())
+	}
+
+	fn backup_to(&self, _path: &Path) -> Result<()> {
+		Err(DatabaseError::Unsupported("the memory store has no database to back up".to_string()))
+	}
+}
@@ -0,0 +1,117 @@
+use rusqlite::{Transaction, NO_PARAMS, Result};
+
+use super::sql;
+
+// A schema change, applied in a single transaction together with bumping the schema version
+// (sqlite's user_version). Migrations are never edited once released, add a new one instead.
+pub struct Migration {
+	pub version:					u32,
+	pub description:				&'static str,
+	pub up:							fn(&Transaction) -> Result<()>,
+}
+
+pub const MIGRATIONS: [Migration; 6] = [
+	Migration {
+		version: 1,
+		description: "Initial drone, job and ownership schema.",
+		up: initial_schema,
+	},
+	Migration {
+		version: 2,
+		description: "Job status references job_status_enum, fix job created timestamp default.",
+		up: job_status_reference,
+	},
+	Migration {
+		version: 3,
+		description: "Job tags and output for searching the local archive.",
+		up: job_search,
+	},
+	Migration {
+		version: 4,
+		description: "Archive of sent and received inter-drone messages.",
+		up: message_archive,
+	},
+	Migration {
+		version: 5,
+		description: "Archive of finished jobs, job.active cleared once a job is done.",
+		up: job_archive,
+	},
+	Migration {
+		version: 6,
+		description: "Archived job owners and tags as JSON arrays.",
+		up: job_archive_json,
+	},
+];
+
+pub fn latest_version() -> u32 {
+	MIGRATIONS[MIGRATIONS.len() - 1].version
+}
+
+fn initial_schema(tx: &Transaction) -> Result<()> {
+	for create_table_stmt in sql::CREATE_TABLES.iter() {
+		tx.execute(create_table_stmt, NO_PARAMS)?;
+	}
+
+	for job_status in sql::JOB_STATUS_VALUES.iter() {
+		tx.execute(sql::INSERT_JOB_STATUS_VALUES, &[job_status])?;
+	}
+
+	Ok(())
+}
+
+// SQLite can't change a column's type or constraints in place, so the job table is rebuilt.
+// Foreign keys are only switched on after migrating, so dropping the old table leaves ownership rows alone.
+fn job_status_reference(tx: &Transaction) -> Result<()> {
+	tx.execute(sql::CREATE_TABLE_JOB_V2, NO_PARAMS)?;
+
+	for stmt in sql::MIGRATE_JOB_V2.iter() {
+		tx.execute(stmt, NO_PARAMS)?;
+	}
+
+	Ok(())
+}
+
+fn job_search(tx: &Transaction) -> Result<()> {
+	for stmt in sql::MIGRATE_JOB_SEARCH.iter() {
+		tx.execute(stmt, NO_PARAMS)?;
+	}
+
+	Ok(())
+}
+
+fn message_archive(tx: &Transaction) -> Result<()> {
+	for stmt in sql::MIGRATE_MESSAGE_ARCHIVE.iter() {
+		tx.execute(stmt, NO_PARAMS)?;
+	}
+
+	Ok(())
+}
+
+fn job_archive(tx: &Transaction) -> Result<()> {
+	for stmt in sql::MIGRATE_JOB_ARCHIVE.iter() {
+		tx.execute(stmt, NO_PARAMS)?;
+	}
+
+	Ok(())
+}
+
+fn job_archive_json(tx: &Transaction) -> Result<()> {
+	for stmt in sql::MIGRATE_JOB_ARCHIVE_JSON.iter() {
+		tx.execute(stmt, NO_PARAMS)?;
+	}
+
+	Ok(())
+}
+
+/* Tests */
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn migration_order_test() {
+		for (i, migration) in MIGRATIONS.iter().enumerate() {
+			assert_eq!(migration.version as usize, i + 1);
+		}
+	}
+}
@@ -1,131 +1,83 @@
-//use fallible_iterator::FallibleIterator;
-use rusqlite::{Connection, NO_PARAMS, Result};
-use std::fs;
-use std::sync::mpsc::Sender;
+use std::path::Path;
 use uuid::Uuid;
 
-use crate::models::{Host, LogType, LogMessage};
+use crate::config::{Config, StoreKind};
+use crate::models::{ArchiveQuery, ArchivedJob, Host, JobQuery, JobStatus, Job, MessageRecord, SearchPage};
 
+pub mod error;
+pub mod memory;
+pub mod migrations;
 pub mod sql;
+pub mod sqlite;
 
-pub struct Database {
-	pub db_dir:						String,
-	pub db_file:					String,
-	pub db_path:					String,
-	pub id:							Uuid,
-	pub log_tx:						Sender<LogMessage>,
-}
+pub use error::DatabaseError;
+pub use memory::MemoryStore;
+pub use sqlite::Database;
 
-impl Database {
-	pub fn update_host( &self, host: &Host) -> Result<()> {
-		let conn = Connection::open(&self.db_path)?;
-		let mut stmt = conn.prepare(sql::INSERT_OR_UPDATE_DRONE)?;
-		stmt.execute(&[host.address.to_owned(), host.id.to_string(), host.online.to_string(), host.port.to_owned()])?;
+pub type Result<T> = std::result::Result<T, DatabaseError>;
 
-		Ok(())
-	}
+// Everything a drone keeps: known hosts, jobs and their ownership, and the job and message history.
+// Times are UTC strings in sqlite's "YYYY-MM-DD HH:MM:SS" format for every backend.
+pub trait Store: Send {
+	/* Hosts */
+	fn get_host(&self, id: Uuid) -> Result<Option<Host>>;
+
+	// Every drone this drone has heard of.
+	fn get_hosts(&self) -> Result<Vec<Host>>;
+
+	fn update_host(&mut self, host: &Host) -> Result<()>;
+
+	fn update_hosts(&mut self, hosts: &[Host]) -> Result<()>;
+
+	/* Jobs */
+	fn get_job_status(&self, job_id: Uuid) -> Result<Option<JobStatus>>;
+
+	fn insert_job(&mut self, job: &Job) -> Result<()>;
+
+	fn job_tags(&self, job_id: Uuid) -> Result<Vec<String>>;
+
+	fn search_jobs(&self, query: &JobQuery) -> Result<SearchPage>;
+
+	fn update_job_output(&mut self, job_id: Uuid, output: &str) -> Result<()>;
+
+	// Done statuses (see JobStatus::is_done) set the finished time and make the job inactive.
+	fn update_job_status(&mut self, job_id: Uuid, status: JobStatus) -> Result<()>;
+
+	/* Ownership, both the drone and the job must be known. */
+	fn job_owners(&self, job_id: Uuid) -> Result<Vec<Uuid>>;
+
+	fn owned_jobs(&self, drone_id: Uuid) -> Result<Vec<Uuid>>;
+
+	fn remove_job_owner(&mut self, drone_id: Uuid, job_id: Uuid) -> Result<()>;
+
+	fn set_job_owner(&mut self, drone_id: Uuid, job_id: Uuid) -> Result<()>;
+
+	/* History */
+	// Move finished jobs to the job archive: a single job, or every job finished before a UTC time.
+	// Jobs that aren't done yet are left alone. Returns the number of jobs archived.
+	fn archive_jobs(&mut self, job_id: Option<Uuid>, finished_before: Option<&str>) -> Result<usize>;
+
+	fn archived_jobs(&self, query: &ArchiveQuery) -> Result<Vec<ArchivedJob>>;
+
+	// Apply the job archive retention limits, 0 disables a limit. Returns the number of jobs removed.
+	fn prune_job_archive(&mut self, max_age_days: u64, max_rows: usize) -> Result<usize>;
+
+	fn archive_message(&mut self, record: &MessageRecord) -> Result<()>;
+
+	// The newest archived messages first.
+	fn archived_messages(&self, limit: usize) -> Result<Vec<MessageRecord>>;
+
+	// Apply the message archive retention limits, 0 disables a limit. Returns the number of messages removed.
+	fn prune_messages(&mut self, max_age_days: u64, max_rows: usize) -> Result<usize>;
+
+	// Write a consistent copy of the store as a sqlite database, e.g. for a snapshot.
+	fn backup_to(&self, path: &Path) -> Result<()>;
+}
 
-	pub fn verify_or_init(id: Uuid, db_dir: String, db_file: String, log_tx: Sender<LogMessage>) -> Result<Self, rusqlite::Error> {
-		const DATABASE_VERSION: &'static str = env!("CARGO_PKG_VERSION");
-
-		match fs::create_dir_all(&db_dir) {
-			Err(err) => {
-				log_tx.send(LogMessage::new(
-					LogType::ErrorLog,
-					format!("Failed to create database dir. Exit from fatal error: {:?}", err)
-				)).unwrap();
-
-				log_tx.send(LogMessage::new(
-					LogType::SystemLog,
-					"Database creation failed. See error log.".to_string()
-				)).unwrap();
-
-				println!("Database validation error: could not run fs::create_dir_all(db_dir). Exit from fata error: {:?}", err);
-				std::thread::sleep(std::time::Duration::from_secs(2));
-				std::process::exit(0x0102);
-			},
-			_ => {},
-		}
-
-		let mut db_path: String = db_dir.to_owned();
-		db_path.push('/');
-		db_path.push_str(&db_file);
-
-		let conn = Connection::open(&db_path)?;
-
-		let mut stmt = conn.prepare(sql::SELECT_TABLE_COUNT)?;
-		let count: i32 = stmt.query_row(NO_PARAMS, |row| row.get(0))?;
-		let count = count as usize;
-
-		if count != sql::TABLE_COUNT && count != 0 {
-			log_tx.send(LogMessage::new(
-				LogType::ErrorLog,
-				format!("Database validation error: TABLE_COUNT should be 0 (empty database) or {} (fully initialized database). Found {}", sql::TABLE_COUNT, count)
-			)).unwrap();
-
-			log_tx.send(LogMessage::new(
-				LogType::SystemLog,
-				"Database validation failed. See error log.".to_string()
-			)).unwrap();
-
-			println!("Database validation error: TABLE_COUNT should be 0 (empty database) or {} (fully initialized database). Found {}. Exit from fatal error.", sql::TABLE_COUNT, count);
-			std::thread::sleep(std::time::Duration::from_secs(2));
-			std::process::exit(0x0100);
-		} else if count == 0 {
-			 log_tx.send(LogMessage::new(
-			 	LogType::SystemLog,
-			 	"Initializing database from empty state...".to_string()
-			 )).unwrap();
-
-			 println!("Inilizing {} database tables...", sql::CREATE_TABLES.len());
-			 for create_table_stmt in sql::CREATE_TABLES.iter() {
-			 	conn.execute(create_table_stmt, NO_PARAMS)?;
-			 }
-
-			 for job_status in sql::JOB_STATUS_VALUES.iter() {
-			 	conn.execute(sql::INSERT_JOB_STATUS_VALUES, &[job_status])?;
-			 }
-
-			 conn.execute(sql::INSERT_DATABASE_VERSION, &[DATABASE_VERSION])?;
-
-			 log_tx.send(LogMessage::new(
-			 	LogType::SystemLog,
-			 	format!("Database initialization complete: {} tables.", sql::TABLE_COUNT)
-			 )).unwrap();
-			 
-			 println!(" finished.");
-		}
-
-		let mut stmt = conn.prepare(sql::SELECT_DATABASE_VERSION)?;
-		let database_version: String = stmt.query_row(NO_PARAMS, |row| row.get(0))?;
-
-		if database_version != DATABASE_VERSION {
-			log_tx.send(LogMessage::new(
-				LogType::ErrorLog,
-				format!("Database validation error: DATABASE_VERSION ({}) expected to be {}. Exit from fatal error.", database_version, DATABASE_VERSION)
-			)).unwrap();
-
-			log_tx.send(LogMessage::new(
-				LogType::SystemLog,
-				"Database validation failed. See error log.".to_string()
-			)).unwrap();
-
-			println!("Database validation error: DATABASE_VERSION ({}) expected to be {}. Exit from fatal error.", database_version, DATABASE_VERSION);
-			std::thread::sleep(std::time::Duration::from_secs(2));
-			std::process::exit(0x0101);
-		}
-
-		log_tx.send(LogMessage::new(
-			LogType::SystemLog,
-			format!("Database v.{} validated: {} of {} tables.", DATABASE_VERSION, count, sql::TABLE_COUNT)
-		)).unwrap();
-
-		Ok(Database {
-			db_dir,
-			db_file,
-			db_path,
-			id,
-			log_tx,
-		})
+// Open the store selected in config, creating or migrating the sqlite database as needed.
+pub fn open(config: &Config) -> Result<Box<dyn Store>> {
+	match config.store {
+		StoreKind::Memory => Ok(Box::new(MemoryStore::new())),
+		StoreKind::Sqlite => Ok(Box::new(Database::verify_or_init(config.id, config.db_dir.clone(), config.db_file.clone())?)),
 	}
 }
@@ -1,4 +1,5 @@
 
+// The version 1 schema (see migrations.rs), also used to recognise databases created before schema versioning.
 pub const TABLE_COUNT: usize = 5;
 
 pub const CREATE_TABLES: [&str; 5] = [
@@ -35,6 +36,7 @@ pub const CREATE_TABLE_DRONE_OWNERSHIP: &str = "
 	);
 ";
 
+// Version 1 only, replaced by CREATE_TABLE_JOB_V2 in migration 2.
 pub const CREATE_TABLE_JOB: &str = "
 	CREATE TABLE job (
 		active bool NOT NULL DEFAULT true,
@@ -45,6 +47,16 @@ pub const CREATE_TABLE_JOB: &str = "
 	);
 ";
 
+pub const CREATE_TABLE_JOB_V2: &str = "
+	CREATE TABLE job_v2 (
+		active bool NOT NULL DEFAULT true,
+		created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+		finished TIMESTAMP DEFAULT NULL,
+		id Uuid PRIMARY KEY NOT NULL,
+		status INTEGER NOT NULL REFERENCES job_status_enum(id)
+	);
+";
+
 pub const CREATE_TABLE_JOB_STATUS: &str = "
 	CREATE TABLE job_status_enum (
 		id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
@@ -60,27 +72,244 @@ pub const JOB_STATUS_VALUES: [&str; 5] = [
 	"Working"
 ];
 
+/* Migration 2: fix the job table (created default, status references job_status_enum). */
+pub const MIGRATE_JOB_V2: [&str; 4] = [
+	"CREATE UNIQUE INDEX job_status_enum_job_status ON job_status_enum (job_status);",
+	"INSERT INTO job_v2 (active, created, finished, id, status)
+		SELECT
+			active,
+			CASE WHEN created IS NULL OR created = 'CURRENT_TIMMESTAMP' THEN CURRENT_TIMESTAMP ELSE created END,
+			finished,
+			id,
+			COALESCE(
+				(SELECT job_status_enum.id FROM job_status_enum WHERE job_status_enum.job_status = job.status),
+				(SELECT job_status_enum.id FROM job_status_enum WHERE job_status_enum.job_status = 'New')
+			)
+		FROM job;",
+	"DROP TABLE job;",
+	"ALTER TABLE job_v2 RENAME TO job;",
+];
+
+/* Migration 3: job tags and output, for searching the local archive. */
+pub const MIGRATE_JOB_SEARCH: [&str; 5] = [
+	"ALTER TABLE job ADD COLUMN output TEXT DEFAULT NULL;",
+	"CREATE TABLE job_tag (
+		job_id Uuid NOT NULL REFERENCES job(id),
+		tag VARCHAR(64) NOT NULL,
+		PRIMARY KEY (job_id, tag)
+	);",
+	"CREATE INDEX job_created ON job (created);",
+	"CREATE INDEX job_tag_tag ON job_tag (tag);",
+	"CREATE INDEX drone_ownership_job_id ON drone_ownership (job_id);",
+];
+
+/* Migration 4: archive of inter-drone messages, for auditing and replaying traffic. */
+pub const MIGRATE_MESSAGE_ARCHIVE: [&str; 2] = [
+	"CREATE TABLE message_archive (
+		seq INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+		direction VARCHAR(8) NOT NULL,
+		id Uuid DEFAULT NULL,
+		message_type VARCHAR(32) DEFAULT NULL,
+		outcome VARCHAR(16) NOT NULL,
+		payload BLOB NOT NULL,
+		peer VARCHAR(64) NOT NULL,
+		size INTEGER NOT NULL,
+		timestamp TIMESTAMP NOT NULL
+	);",
+	"CREATE INDEX message_archive_timestamp ON message_archive (timestamp);",
+];
+
+/* Migration 5: job archive. Owners and tags are kept as comma separated lists so archived jobs don't
+ * hold on to drone_ownership and job_tag rows. */
+pub const MIGRATE_JOB_ARCHIVE: [&str; 4] = [
+	"CREATE TABLE job_archive (
+		archived TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+		created TIMESTAMP NOT NULL,
+		finished TIMESTAMP DEFAULT NULL,
+		id Uuid PRIMARY KEY NOT NULL,
+		output TEXT DEFAULT NULL,
+		owners TEXT NOT NULL DEFAULT '',
+		status VARCHAR(32) NOT NULL,
+		tags TEXT NOT NULL DEFAULT ''
+	);",
+	"CREATE INDEX job_archive_finished ON job_archive (finished);",
+	"CREATE INDEX job_active_finished ON job (active, finished);",
+	// Jobs finished before the active flag was maintained.
+	"UPDATE job SET active = 0 WHERE finished IS NOT NULL;",
+];
+
+/* Migration 6: archived owners and tags as JSON arrays, a comma separated list splits tags holding a comma.
+ * Rows archived before are converted as they were split. */
+pub const MIGRATE_JOB_ARCHIVE_JSON: [&str; 1] = [
+	"UPDATE job_archive SET
+		owners = CASE owners WHEN '' THEN '[]' ELSE '[\"' || replace(owners, ',', '\",\"') || '\"]' END,
+		tags = CASE tags WHEN '' THEN '[]' ELSE '[\"' || replace(replace(replace(tags, '\\', '\\\\'), '\"', '\\\"'), ',', '\",\"') || '\"]' END;",
+];
+
 /* INSERT sql statements */
 pub const INSERT_JOB_STATUS_VALUES: &str = "INSERT INTO job_status_enum (job_status) VALUES(?1);";
 
 pub const INSERT_DATABASE_VERSION: &str = "INSERT INTO database_version (version) VALUES(?1);";
 
+pub const UPDATE_DATABASE_VERSION: &str = "UPDATE database_version SET version = ?1;";
+
+pub const INSERT_MESSAGE_ARCHIVE: &str = "
+	INSERT INTO message_archive (direction, id, message_type, outcome, payload, peer, size, timestamp)
+	VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8);
+";
+
+/* DELETE sql statements */
+pub const DELETE_DRONE_OWNERSHIP: &str = "DELETE FROM drone_ownership WHERE drone_id = ?1 AND job_id = ?2;";
+
+pub const DELETE_MESSAGE_ARCHIVE_BEFORE: &str = "DELETE FROM message_archive WHERE timestamp < datetime('now', ?1);";
+
+// Keeps the newest ?1 rows.
+pub const DELETE_MESSAGE_ARCHIVE_OVERFLOW: &str = "
+	DELETE FROM message_archive
+	WHERE seq <= (SELECT seq FROM message_archive ORDER BY seq DESC LIMIT 1 OFFSET ?1);
+";
+
 pub const INSERT_OR_UPDATE_DRONE: &str = "
 	INSERT INTO drone (address, id, online, port)
 	VALUES(?1, ?2, ?3, ?4)
 	ON CONFLICT (id)
 	DO
-		UPDATE
+		UPDATE SET
 			address = ?1, online = ?3, port = ?4;
 ";
 
+pub const INSERT_DRONE_OWNERSHIP: &str = "INSERT OR IGNORE INTO drone_ownership (drone_id, job_id) VALUES(?1, ?2);";
 
+pub const INSERT_JOB_TAG: &str = "INSERT OR IGNORE INTO job_tag (job_id, tag) VALUES(?1, ?2);";
+
+pub const INSERT_JOB: &str = "
+	INSERT INTO job (id, status)
+	VALUES(?1, (SELECT id FROM job_status_enum WHERE job_status = ?2));
+";
+
+/* UPDATE sql statements */
+pub const UPDATE_JOB_OUTPUT: &str = "UPDATE job SET output = ?2 WHERE id = ?1;";
+
+pub const UPDATE_JOB_STATUS: &str = "
+	UPDATE job
+	SET
+		status = (SELECT id FROM job_status_enum WHERE job_status = ?2),
+		finished = CASE WHEN ?3 THEN CURRENT_TIMESTAMP ELSE NULL END,
+		active = NOT ?3
+	WHERE id = ?1;
+";
+
+
+
+/* PRAGMA statements */
+pub const PRAGMA_FOREIGN_KEYS_ON: &str = "PRAGMA foreign_keys = ON;";
+
+pub const PRAGMA_JOURNAL_MODE_WAL: &str = "PRAGMA journal_mode = WAL;";
+
+pub const PRAGMA_SYNCHRONOUS_NORMAL: &str = "PRAGMA synchronous = NORMAL;";
 
 /* SELECT sql statements */
 pub const SELECT_TABLE_COUNT: &str = "SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name != 'sqlite_sequence';";
 
 pub const SELECT_DATABASE_VERSION: &str = "SELECT version FROM database_version LIMIT 1;";
 
+pub const SELECT_SCHEMA_VERSION: &str = "PRAGMA user_version;";
+
+pub const SELECT_DRONE: &str = "SELECT address, id, online, port FROM drone WHERE id = ?1;";
+
+pub const SELECT_DRONES: &str = "SELECT address, id, online, port FROM drone ORDER BY id;";
+
+pub const SELECT_OWNED_JOBS: &str = "SELECT job_id FROM drone_ownership WHERE drone_id = ?1 ORDER BY job_id;";
+
+pub const SELECT_JOB_OWNERS: &str = "SELECT drone_id FROM drone_ownership WHERE job_id = ?1 ORDER BY drone_id;";
+
+pub const SELECT_JOB_STATUS: &str = "
+	SELECT job_status_enum.job_status
+	FROM job
+	JOIN job_status_enum ON job_status_enum.id = job.status
+	WHERE job.id = ?1;
+";
+
+/* Search: every filter is optional, a NULL parameter matches everything. */
+const SEARCH_JOBS_WHERE: &str = "
+	FROM job
+	JOIN job_status_enum ON job_status_enum.id = job.status
+	WHERE (?1 IS NULL OR job.created >= ?1)
+		AND (?2 IS NULL OR job.created < ?2)
+		AND (?3 IS NULL OR job_status_enum.job_status = ?3)
+		AND (?4 IS NULL OR EXISTS (SELECT 1 FROM job_tag WHERE job_tag.job_id = job.id AND job_tag.tag = ?4))
+		AND (?5 IS NULL OR EXISTS (SELECT 1 FROM drone_ownership WHERE drone_ownership.job_id = job.id AND drone_ownership.drone_id = ?5))
+		AND (?6 IS NULL OR instr(job.output, ?6) > 0)
+";
+
+pub fn search_jobs() -> String {
+	format!("
+		SELECT job.id, job_status_enum.job_status, job.created, job.finished
+		{}
+		ORDER BY job.created DESC, job.id
+		LIMIT ?7 OFFSET ?8;
+	", SEARCH_JOBS_WHERE)
+}
+
+pub fn count_jobs() -> String {
+	format!("SELECT count(*) {};", SEARCH_JOBS_WHERE)
+}
+
+pub const SELECT_MESSAGE_ARCHIVE: &str = "
+	SELECT direction, id, message_type, outcome, payload, peer, size, timestamp
+	FROM message_archive
+	ORDER BY seq DESC
+	LIMIT ?1;
+";
+
+/* Archival: moves finished (inactive) jobs, either one job (?1) or all finished before ?2, to job_archive. */
+const ARCHIVE_JOBS_WHERE: &str = "
+	job.active = 0
+		AND (?1 IS NULL OR job.id = ?1)
+		AND (?2 IS NULL OR job.finished < ?2)
+";
+
+pub fn archive_jobs() -> [String; 4] {
+	[
+		format!("
+			INSERT INTO job_archive (created, finished, id, output, owners, status, tags)
+			SELECT
+				job.created,
+				job.finished,
+				job.id,
+				job.output,
+				(SELECT json_group_array(drone_id) FROM drone_ownership WHERE drone_ownership.job_id = job.id),
+				job_status_enum.job_status,
+				(SELECT json_group_array(tag) FROM job_tag WHERE job_tag.job_id = job.id)
+			FROM job
+			JOIN job_status_enum ON job_status_enum.id = job.status
+			WHERE {};
+		", ARCHIVE_JOBS_WHERE),
+		format!("DELETE FROM job_tag WHERE job_id IN (SELECT job.id FROM job WHERE {});", ARCHIVE_JOBS_WHERE),
+		format!("DELETE FROM drone_ownership WHERE job_id IN (SELECT job.id FROM job WHERE {});", ARCHIVE_JOBS_WHERE),
+		format!("DELETE FROM job WHERE {};", ARCHIVE_JOBS_WHERE),
+	]
+}
+
+pub const DELETE_JOB_ARCHIVE_BEFORE: &str = "DELETE FROM job_archive WHERE finished < datetime('now', ?1);";
+
+// Keeps the ?1 most recently finished jobs.
+pub const DELETE_JOB_ARCHIVE_OVERFLOW: &str = "
+	DELETE FROM job_archive
+	WHERE rowid IN (SELECT rowid FROM job_archive ORDER BY finished DESC, rowid DESC LIMIT -1 OFFSET ?1);
+";
+
+pub const SELECT_JOB_ARCHIVE: &str = "
+	SELECT archived, created, finished, id, output, owners, status, tags
+	FROM job_archive
+	WHERE (?1 IS NULL OR finished >= ?1)
+		AND (?2 IS NULL OR finished < ?2)
+	ORDER BY finished, id
+	LIMIT ?3 OFFSET ?4;
+";
+
+pub const SELECT_JOB_TAGS: &str = "SELECT tag FROM job_tag WHERE job_id = ?1 ORDER BY tag;";
+
 /* Tests */
 #[cfg(test)]
 mod tests {
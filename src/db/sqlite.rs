@@ -0,0 +1,449 @@
+//use fallible_iterator::FallibleIterator;
+use chrono::Local;
+use log::info;
+use rusqlite::types::Type;
+use rusqlite::{params, Connection, DatabaseName, OptionalExtension, Row, ToSql, Transaction, NO_PARAMS};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::models::{ArchiveQuery, ArchivedJob, Host, HostStatus, JobQuery, JobRecord, JobStatus, Job, MessageDirection, MessageOutcome, MessageRecord, SearchPage};
+use super::{migrations, sql, DatabaseError, Result, Store};
+
+const BUSY_TIMEOUT_MS: u64 = 5000;
+const STATEMENT_CACHE_CAPACITY: usize = 32;
+
+// The default store, a sqlite database in db_dir.
+pub struct Database {
+	conn:							Connection,
+	pub db_dir:						PathBuf,
+	pub db_file:					PathBuf,
+	pub db_path:					PathBuf,
+	pub id:							Uuid,
+}
+
+impl Database {
+	fn read_host(row: &Row) -> rusqlite::Result<Host> {
+		let address: String = row.get(0)?;
+		let id: String = row.get(1)?;
+		let online: bool = row.get(2)?;
+		let port: i64 = row.get(3)?;
+
+		let id = Uuid::parse_str(&id).map_err(|err| rusqlite::Error::FromSqlConversionFailure(1, Type::Text, Box::new(err)))?;
+		let mut host = Host::new(id, address, port.to_string());
+		if online {
+			host.online();
+			host.status = HostStatus::Online;
+		}
+
+		Ok(host)
+	}
+
+	// Run a group of writes in a single transaction, rolled back if f returns an error.
+	pub fn batch<T, F>(&mut self, f: F) -> Result<T> where F: FnOnce(&Transaction) -> Result<T> {
+		let tx = self.conn.transaction()?;
+		let result = f(&tx)?;
+		tx.commit()?;
+
+		Ok(result)
+	}
+
+	fn write_host(conn: &Connection, host: &Host) -> Result<()> {
+		let mut stmt = conn.prepare_cached(sql::INSERT_OR_UPDATE_DRONE)?;
+		stmt.execute(params![host.address, host.id.to_string(), host.online, host.port])?;
+
+		Ok(())
+	}
+
+	// The connection is kept for the life of the drone: WAL so readers don't block the writer, a busy
+	// timeout instead of failing straight away on a locked database, and a cache of prepared statements.
+	fn open(db_path: &Path) -> Result<Connection> {
+		let conn = Connection::open(db_path)?;
+
+		let _: String = conn.query_row(sql::PRAGMA_JOURNAL_MODE_WAL, NO_PARAMS, |row| row.get(0))?;
+		conn.execute_batch(sql::PRAGMA_SYNCHRONOUS_NORMAL)?;
+		conn.busy_timeout(Duration::from_millis(BUSY_TIMEOUT_MS))?;
+		conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
+
+		Ok(conn)
+	}
+
+	// Backups are named after the schema version they hold, e.g. drone.db.v1-20201010T101010.bak
+	fn backup_path(db_path: &Path, schema_version: u32) -> PathBuf {
+		let mut file_name = db_path.file_name().unwrap_or_default().to_os_string();
+		file_name.push(format!(".v{}-{}.bak", schema_version, Local::now().format("%Y%m%dT%H%M%S")));
+
+		db_path.with_file_name(file_name)
+	}
+
+	pub fn verify_or_init(id: Uuid, db_dir: PathBuf, db_file: PathBuf) -> Result<Self> {
+		const DATABASE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+		fs::create_dir_all(&db_dir)?;
+
+		let db_path = db_dir.join(&db_file);
+
+		let mut conn = Database::open(&db_path)?;
+
+		let mut stmt = conn.prepare(sql::SELECT_TABLE_COUNT)?;
+		let count: i32 = stmt.query_row(NO_PARAMS, |row| row.get(0))?;
+		let count = count as usize;
+		drop(stmt);
+
+		let mut schema_version: u32 = conn.query_row(sql::SELECT_SCHEMA_VERSION, NO_PARAMS, |row| row.get(0))?;
+
+		if schema_version == 0 && count == sql::TABLE_COUNT {
+			// Databases created before migrations existed have the full version 1 schema but no schema version.
+			conn.execute_batch(&format!("PRAGMA user_version = {};", 1))?;
+			schema_version = 1;
+
+			info!("Database created before schema versioning, marked as schema version 1.");
+		} else if schema_version == 0 && count != 0 {
+			return Err(DatabaseError::Corruption(format!(
+				"unversioned database should have 0 (empty database) or {} (fully initialized database) tables, found {}", sql::TABLE_COUNT, count
+			)));
+		}
+
+		let latest_version = migrations::latest_version();
+
+		if schema_version > latest_version {
+			return Err(DatabaseError::SchemaMismatch {
+				found: schema_version,
+				supported: latest_version,
+			});
+		}
+
+		if schema_version < latest_version {
+			if schema_version > 0 {
+				let backup_path = Database::backup_path(&db_path, schema_version);
+				conn.backup(DatabaseName::Main, &backup_path, None)?;
+
+				info!("Database backed up to {} before migrating.", backup_path.display());
+			}
+
+			info!("Migrating database from schema version {} to {}.", schema_version, latest_version);
+			for migration in migrations::MIGRATIONS.iter().filter(|m| m.version > schema_version) {
+				let tx = conn.transaction()?;
+				(migration.up)(&tx)?;
+				tx.execute_batch(&format!("PRAGMA user_version = {};", migration.version))?;
+				tx.commit()?;
+
+				info!("Database migrated to schema version {}: {}", migration.version, migration.description);
+			}
+		}
+
+		// Enabled after migrating, migrations that rebuild tables rely on it being off.
+		conn.execute_batch(sql::PRAGMA_FOREIGN_KEYS_ON)?;
+
+		// Informational only, the software version that last opened this database.
+		if conn.execute(sql::UPDATE_DATABASE_VERSION, &[DATABASE_VERSION])? == 0 {
+			conn.execute(sql::INSERT_DATABASE_VERSION, &[DATABASE_VERSION])?;
+		}
+
+		info!("Database validated: schema version {}, drone v.{}.", latest_version, DATABASE_VERSION);
+
+		Ok(Database {
+			conn,
+			db_dir,
+			db_file,
+			db_path,
+			id,
+		})
+	}
+}
+
+impl Store for Database {
+	fn archive_jobs(&mut self, job_id: Option<Uuid>, finished_before: Option<&str>) -> Result<usize> {
+		let job_id = job_id.map(|job_id| job_id.to_string());
+
+		self.batch(|tx| {
+			let [archive, delete_tags, delete_owners, delete_jobs] = sql::archive_jobs();
+
+			let archived = tx.execute(&archive, params![job_id, finished_before])?;
+			tx.execute(&delete_tags, params![job_id, finished_before])?;
+			tx.execute(&delete_owners, params![job_id, finished_before])?;
+			tx.execute(&delete_jobs, params![job_id, finished_before])?;
+
+			Ok(archived)
+		})
+	}
+
+	fn archived_jobs(&self, query: &ArchiveQuery) -> Result<Vec<ArchivedJob>> {
+		let mut stmt = self.conn.prepare_cached(sql::SELECT_JOB_ARCHIVE)?;
+		let mut rows = stmt.query(params![query.since, query.until, query.limit as i64, query.offset as i64])?;
+
+		let mut jobs = Vec::new();
+		while let Some(row) = rows.next()? {
+			let id: String = row.get(3)?;
+			let owners: String = row.get(5)?;
+			let status: String = row.get(6)?;
+			let tags: String = row.get(7)?;
+
+			let owners: Vec<Uuid> = serde_json::from_str(&owners).map_err(|err| DatabaseError::Corruption(format!("invalid owners {} in job_archive: {}", owners, err)))?;
+			let tags: Vec<String> = serde_json::from_str(&tags).map_err(|err| DatabaseError::Corruption(format!("invalid tags {} in job_archive: {}", tags, err)))?;
+
+			jobs.push(ArchivedJob {
+				archived: row.get(0)?,
+				created: row.get(1)?,
+				finished: row.get(2)?,
+				id: Uuid::parse_str(&id).map_err(|err| DatabaseError::Corruption(format!("invalid job id {} in job_archive: {}", id, err)))?,
+				output: row.get(4)?,
+				owners,
+				status: JobStatus::from_name(&status).ok_or_else(|| DatabaseError::Corruption(format!("unknown job status {}", status)))?,
+				tags,
+			});
+		}
+
+		Ok(jobs)
+	}
+
+	fn prune_job_archive(&mut self, max_age_days: u64, max_rows: usize) -> Result<usize> {
+		self.batch(|tx| {
+			let mut removed = 0;
+
+			if max_age_days > 0 {
+				removed += tx.execute(sql::DELETE_JOB_ARCHIVE_BEFORE, &[format!("-{} days", max_age_days)])?;
+			}
+			if max_rows > 0 {
+				removed += tx.execute(sql::DELETE_JOB_ARCHIVE_OVERFLOW, [max_rows as i64])?;
+			}
+
+			Ok(removed)
+		})
+	}
+
+	fn archive_message(&mut self, record: &MessageRecord) -> Result<()> {
+		let mut stmt = self.conn.prepare_cached(sql::INSERT_MESSAGE_ARCHIVE)?;
+		stmt.execute(params![
+			record.direction.as_str(),
+			record.id.map(|id| id.to_string()),
+			record.message_type,
+			record.outcome.as_str(),
+			record.payload,
+			record.peer,
+			record.size as i64,
+			record.timestamp,
+		])?;
+
+		Ok(())
+	}
+
+	fn archived_messages(&self, limit: usize) -> Result<Vec<MessageRecord>> {
+		let mut stmt = self.conn.prepare_cached(sql::SELECT_MESSAGE_ARCHIVE)?;
+		let mut rows = stmt.query([limit as i64])?;
+
+		let mut records = Vec::new();
+		while let Some(row) = rows.next()? {
+			let direction: String = row.get(0)?;
+			let id: Option<String> = row.get(1)?;
+			let outcome: String = row.get(3)?;
+			let size: i64 = row.get(6)?;
+
+			records.push(MessageRecord {
+				direction: MessageDirection::from_name(&direction).ok_or_else(|| DatabaseError::Corruption(format!("unknown message direction {}", direction)))?,
+				id: match id {
+					Some(id) => Some(Uuid::parse_str(&id).map_err(|err| DatabaseError::Corruption(format!("invalid message id {}: {}", id, err)))?),
+					None => None,
+				},
+				message_type: row.get(2)?,
+				outcome: MessageOutcome::from_name(&outcome).ok_or_else(|| DatabaseError::Corruption(format!("unknown message outcome {}", outcome)))?,
+				payload: row.get(4)?,
+				peer: row.get(5)?,
+				size: size as usize,
+				timestamp: row.get(7)?,
+			});
+		}
+
+		Ok(records)
+	}
+
+	fn prune_messages(&mut self, max_age_days: u64, max_rows: usize) -> Result<usize> {
+		self.batch(|tx| {
+			let mut removed = 0;
+
+			if max_age_days > 0 {
+				removed += tx.execute(sql::DELETE_MESSAGE_ARCHIVE_BEFORE, &[format!("-{} days", max_age_days)])?;
+			}
+			if max_rows > 0 {
+				removed += tx.execute(sql::DELETE_MESSAGE_ARCHIVE_OVERFLOW, [max_rows as i64])?;
+			}
+
+			Ok(removed)
+		})
+	}
+
+	fn get_host(&self, id: Uuid) -> Result<Option<Host>> {
+		let mut stmt = self.conn.prepare_cached(sql::SELECT_DRONE)?;
+		let host = stmt.query_row(&[id.to_string()], Database::read_host).optional()?;
+
+		Ok(host)
+	}
+
+	fn get_hosts(&self) -> Result<Vec<Host>> {
+		let mut stmt = self.conn.prepare_cached(sql::SELECT_DRONES)?;
+		let rows = stmt.query_map(NO_PARAMS, Database::read_host)?;
+
+		let mut hosts = Vec::new();
+		for host in rows {
+			hosts.push(host?);
+		}
+
+		Ok(hosts)
+	}
+
+	fn get_job_status(&self, job_id: Uuid) -> Result<Option<JobStatus>> {
+		let mut stmt = self.conn.prepare_cached(sql::SELECT_JOB_STATUS)?;
+		let status: Option<String> = stmt.query_row(&[job_id.to_string()], |row| row.get(0)).optional()?;
+
+		Ok(status.and_then(|status| JobStatus::from_name(&status)))
+	}
+
+	fn insert_job(&mut self, job: &Job) -> Result<()> {
+		self.batch(|tx| {
+			let mut stmt = tx.prepare_cached(sql::INSERT_JOB)?;
+			stmt.execute(&[job.id.to_string(), JobStatus::New.as_str().to_string()])?;
+
+			let mut stmt = tx.prepare_cached(sql::INSERT_JOB_TAG)?;
+			for tag in job.tags.iter() {
+				stmt.execute(&[job.id.to_string(), tag.to_owned()])?;
+			}
+
+			Ok(())
+		})
+	}
+
+	fn job_owners(&self, job_id: Uuid) -> Result<Vec<Uuid>> {
+		let mut stmt = self.conn.prepare_cached(sql::SELECT_JOB_OWNERS)?;
+		let rows = stmt.query_map(&[job_id.to_string()], |row| row.get::<_, String>(0))?;
+
+		let mut owners = Vec::new();
+		for drone_id in rows {
+			let drone_id = drone_id?;
+			owners.push(Uuid::parse_str(&drone_id).map_err(|err| DatabaseError::Corruption(format!("invalid drone id {} in drone_ownership: {}", drone_id, err)))?);
+		}
+
+		Ok(owners)
+	}
+
+	fn owned_jobs(&self, drone_id: Uuid) -> Result<Vec<Uuid>> {
+		let mut stmt = self.conn.prepare_cached(sql::SELECT_OWNED_JOBS)?;
+		let rows = stmt.query_map(&[drone_id.to_string()], |row| row.get::<_, String>(0))?;
+
+		let mut jobs = Vec::new();
+		for job_id in rows {
+			let job_id = job_id?;
+			jobs.push(Uuid::parse_str(&job_id).map_err(|err| DatabaseError::Corruption(format!("invalid job id {} in drone_ownership: {}", job_id, err)))?);
+		}
+
+		Ok(jobs)
+	}
+
+	fn search_jobs(&self, query: &JobQuery) -> Result<SearchPage> {
+		let status = query.status.map(|status| status.as_str());
+		let drone = query.drone.map(|drone| drone.to_string());
+		let limit = query.limit as i64;
+		let offset = query.offset as i64;
+		let search_params: [&dyn ToSql; 8] = [&query.since, &query.until, &status, &query.tag, &drone, &query.text, &limit, &offset];
+
+		// The count takes the filters only, not the limit/offset.
+		let mut stmt = self.conn.prepare_cached(&sql::count_jobs())?;
+		let total: i64 = stmt.query_row(&search_params[..6], |row| row.get(0))?;
+
+		let mut page = Vec::new();
+		let mut stmt = self.conn.prepare_cached(&sql::search_jobs())?;
+		let mut rows = stmt.query(&search_params)?;
+
+		while let Some(row) = rows.next()? {
+			let id: String = row.get(0)?;
+			let status: String = row.get(1)?;
+
+			let id = Uuid::parse_str(&id).map_err(|err| DatabaseError::Corruption(format!("invalid job id {}: {}", id, err)))?;
+			let status = JobStatus::from_name(&status).ok_or_else(|| DatabaseError::Corruption(format!("unknown job status {}", status)))?;
+
+			page.push((id, status, row.get(2)?, row.get(3)?));
+		}
+		drop(rows);
+
+		let mut jobs = Vec::new();
+		for (id, status, created, finished) in page {
+			jobs.push(JobRecord {
+				created,
+				finished,
+				id,
+				owners: self.job_owners(id)?,
+				status,
+				tags: self.job_tags(id)?,
+			});
+		}
+
+		Ok(SearchPage {
+			jobs,
+			limit: query.limit,
+			offset: query.offset,
+			total: total as usize,
+		})
+	}
+
+	fn job_tags(&self, job_id: Uuid) -> Result<Vec<String>> {
+		let mut stmt = self.conn.prepare_cached(sql::SELECT_JOB_TAGS)?;
+		let rows = stmt.query_map(&[job_id.to_string()], |row| row.get::<_, String>(0))?;
+
+		let mut tags = Vec::new();
+		for tag in rows {
+			tags.push(tag?);
+		}
+
+		Ok(tags)
+	}
+
+	fn update_job_output(&mut self, job_id: Uuid, output: &str) -> Result<()> {
+		let mut stmt = self.conn.prepare_cached(sql::UPDATE_JOB_OUTPUT)?;
+		stmt.execute(&[job_id.to_string(), output.to_string()])?;
+
+		Ok(())
+	}
+
+	fn remove_job_owner(&mut self, drone_id: Uuid, job_id: Uuid) -> Result<()> {
+		let mut stmt = self.conn.prepare_cached(sql::DELETE_DRONE_OWNERSHIP)?;
+		stmt.execute(&[drone_id.to_string(), job_id.to_string()])?;
+
+		Ok(())
+	}
+
+	fn set_job_owner(&mut self, drone_id: Uuid, job_id: Uuid) -> Result<()> {
+		let mut stmt = self.conn.prepare_cached(sql::INSERT_DRONE_OWNERSHIP)?;
+		stmt.execute(&[drone_id.to_string(), job_id.to_string()])?;
+
+		Ok(())
+	}
+
+	fn update_job_status(&mut self, job_id: Uuid, status: JobStatus) -> Result<()> {
+		let mut stmt = self.conn.prepare_cached(sql::UPDATE_JOB_STATUS)?;
+		stmt.execute(params![job_id.to_string(), status.as_str(), status.is_done()])?;
+
+		Ok(())
+	}
+
+	fn update_host(&mut self, host: &Host) -> Result<()> {
+		Database::write_host(&self.conn, host)
+	}
+
+	// Record several hosts in one transaction (a single fsync instead of one per host).
+	fn update_hosts(&mut self, hosts: &[Host]) -> Result<()> {
+		self.batch(|tx| {
+			for host in hosts {
+				Database::write_host(tx, host)?;
+			}
+
+			Ok(())
+		})
+	}
+
+	// sqlite's online backup API, consistent while the drone keeps writing.
+	fn backup_to(&self, path: &Path) -> Result<()> {
+		self.conn.backup(DatabaseName::Main, path, None)?;
+
+		Ok(())
+	}
+}
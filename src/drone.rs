@@ -1,45 +1,312 @@
+use chrono::Utc;
+use log::{debug, error, info, warn};
 use std::collections::HashMap; 
-use std::sync::mpsc::{Receiver, Sender};
+use std::io::{self, Read, Write};
+use std::fs;
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 
+use crate::auth::{Session, TAG_SIZE};
+use crate::config::Config;
 use crate::db;
+use crate::joblog::{self, JobProcess};
 use crate::models::*;
+use crate::signing;
+use crate::snapshot;
+use crate::tls::{PeerStream, Tls};
+
+// Job archival and archive retention run on start and then at this interval.
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(3600);
+
+// How long to wait for another drone to accept a connection.
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long to wait for another drone to answer a question (see ask).
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Largest answer read from another drone.
+const MAX_ANSWER_SIZE: u64 = 1024 * 1024;
+
+// How often running jobs are checked for having stopped.
+const JOB_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// Retention limits are applied once every this many archived messages (and on start).
+const MESSAGE_PRUNE_INTERVAL: usize = 1000;
 
 pub struct Drone {
-	pub db:						db::Database,
+	// Set by main: the work loop's own queue, for threads to archive the messages they sent.
+	pub archive_tx:				Option<Sender<DroneCtl>>,
+	pub archived:				usize,
+	pub config:					Config,
+	pub db:						Box<dyn db::Store>,
+	pub housekeeping:			Instant,
 	pub id:						Uuid,
-	pub log_tx:					Sender<LogMessage>,
 	pub online:					bool,
+	// Started from a restored snapshot: job ownership is taken from the peers' answers.
+	pub restored:				bool,
+	pub running:				HashMap<Uuid, JobProcess>,
+	pub seeds:					Vec<SocketAddr>,
 	pub swarm:					HashMap<Uuid, Host>,
 	pub tags:					Vec<String>,
 	pub threads:				usize,
+	// Set by main when TLS between drones is configured.
+	pub tls:					Option<Arc<Tls>>,
 	pub workload:				Vec<Job>,
 }
 
 impl Drone {
-	pub fn new(db: db::Database, log_tx: Sender<LogMessage>) -> Self {
-		let id = Uuid::new_v4();
+	pub fn new(config: Config, db: Box<dyn db::Store>) -> Self {
+		let archive_tx = None;
+		let archived = 0;
+		let housekeeping = Instant::now();
+		let id = config.id;
 		let online = false;
+		let restored = false;
+		let running = HashMap::new();
+		let seeds = config.seeds.clone();
 		let swarm = HashMap::new();
-		let tags = Vec::new();
-		let threads = 1usize;
+		let tags = config.tags.clone();
+		let threads = config.threads;
+		let tls = None;
 		let workload = Vec::new();
 
 		Drone {
+			archive_tx,
+			archived,
+			config,
 			db,
+			housekeeping,
 			id,
-			log_tx,
 			online,
+			restored,
+			running,
+			seeds,
 			swarm,
 			tags,
 			threads,
+			tls,
 			workload,
 		}
 	}
 
 	/** swarm related functions */
-	pub fn search(&mut self) {
-		// Search local archives (read: sqlite db) for info about messages/host(s)/jobs/etc.
+	// Record a sent or received message, if the message archive is switched on.
+	fn archive_message(&mut self, record: MessageRecord) {
+		if !self.config.message_archive {
+			return;
+		}
+
+		if let Err(err) = self.db.archive_message(&record) {
+			error!(peer_address:% = record.peer; "Failed to archive message from/to {}: {}", record.peer, err);
+			return;
+		}
+
+		self.archived += 1;
+		if self.archived % MESSAGE_PRUNE_INTERVAL == 0 {
+			self.prune_messages();
+		}
+	}
+
+	// Periodic database upkeep: archive old finished jobs and apply the archive retention limits.
+	fn housekeeping(&mut self) {
+		self.housekeeping = Instant::now();
+
+		self.archive_jobs();
+		self.prune_messages();
+	}
+
+	fn prune_messages(&mut self) {
+		match self.db.prune_messages(self.config.message_archive_max_age, self.config.message_archive_max_rows) {
+			Ok(0) => {},
+			Ok(removed) => {
+				info!("Pruned {} messages from the message archive.", removed);
+			},
+			Err(err) => {
+				error!("Failed to prune the message archive: {}", err);
+			},
+		}
+	}
+
+
+	// Search the local archive (read: sqlite db) for jobs.
+	pub fn search(&self, query: &JobQuery) -> db::Result<SearchPage> {
+		self.db.search_jobs(query)
+	}
+
+	// What a thread needs to ask other drones questions away from the work loop.
+	fn asker(&self) -> Asker {
+		Asker {
+			archive_tx: self.archive_tx.clone(),
+			secret: self.config.swarm_secret.clone(),
+			tls: self.tls.clone(),
+		}
+	}
+
+	// Send a message to another drone's external listener, expected is the drone's id if it's known.
+	pub fn send(&mut self, peer: SocketAddr, expected: Option<Uuid>, message: &Message) -> io::Result<()> {
+		let payload = bincode::serialize(message).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+		let result = connect(peer, expected, self.tls.as_deref(), self.config.swarm_secret.as_bytes()).and_then(|(mut stream, session)| {
+			stream.write_all(&session.seal(&payload))?;
+			stream.finish()
+		});
+
+		let outcome = if result.is_ok() { MessageOutcome::Sent } else { MessageOutcome::SendFailed };
+		self.archive_message(MessageRecord::new(MessageDirection::Sent, peer.to_string(), &payload, outcome));
+
+		result
+	}
+
+	// Search this drone's log files and, unless the query was passed on by another drone, every peer's,
+	// merged by time. Peers that can't be asked are listed rather than failing the query. They are asked
+	// in a thread that replies to msg once all have answered, so the work loop doesn't wait on them.
+	fn logs(&mut self, query: LogQuery, msg: DroneCtl) {
+		let mut logs = match crate::log::search(&self.config, &query) {
+			Ok(lines) => SwarmLogs {
+				failed: Vec::new(),
+				lines,
+			},
+			Err(err) => {
+				msg.reply(CtlReply::Error(err));
+				return;
+			},
+		};
+
+		if query.forwarded {
+			msg.reply(CtlReply::Logs(logs));
+			return;
+		}
+
+		let forwarded = LogQuery { forwarded: true, ..query };
+		let message = Message::with_payload(MessageType::Logs, &forwarded);
+		let peers = self.peers();
+		let asker = self.asker();
+
+		thread::spawn(move || {
+			for peer in peers {
+				let answer = asker.ask(peer, None, &message).map_err(|err| err.to_string())
+					.and_then(|answer| answer.payload::<CtlReply>().map_err(|err| err.to_string()));
+
+				match answer {
+					Ok(CtlReply::Logs(answer)) => logs.lines.extend(answer.lines),
+					Ok(CtlReply::Error(err)) => logs.failed.push(format!("{}: {}", peer, err)),
+					Ok(_) => logs.failed.push(format!("{}: unexpected answer", peer)),
+					Err(err) => logs.failed.push(format!("{}: {}", peer, err)),
+				}
+			}
+
+			// Stable, so each drone's lines keep their order within a timestamp.
+			logs.lines.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+
+			msg.reply(CtlReply::Logs(logs));
+		});
+	}
+
+	// This drone, as other drones see it.
+	fn host(&self) -> Host {
+		let mut host = Host::new(self.id, self.config.address.to_string(), self.config.port.to_string());
+		host.online();
+
+		host
+	}
+
+	// Every drone to tell about changes to this one: the seeds and all drones in the database.
+	fn peers(&self) -> Vec<SocketAddr> {
+		let mut peers = self.seeds.clone();
+
+		match self.db.get_hosts() {
+			Ok(hosts) => {
+				for host in hosts.iter().filter(|host| host.id != self.id) {
+					match format!("{}:{}", host.address, host.port).parse::<SocketAddr>() {
+						Ok(peer) if !peers.contains(&peer) => peers.push(peer),
+						Ok(_) => {},
+						Err(err) => {
+							warn!(peer_id:% = host.id; "Remote drone id = {} has an invalid address {}:{}: {}", host.id, host.address, host.port, err);
+						},
+					}
+				}
+			},
+			Err(err) => {
+				error!("Failed to read known drones: {}", err);
+			},
+		}
+
+		peers
+	}
+
+	// After a restore, re-announce this drone's id and ask the peers which jobs it owns (see reconcile).
+	fn announce_restore(&mut self) {
+		self.restored = true;
+
+		let message = Message::with_payload(MessageType::Restored, &self.host());
+
+		for peer in self.peers() {
+			if let Err(err) = self.send(peer, None, &message) {
+				error!(message_type:? = MessageType::Restored, peer_address:% = peer; "Failed to announce restored drone id = {} to {}: {}", self.id, peer, err);
+			}
+		}
+
+		info!("Drone id = {} restored from a snapshot, announced to peers.", self.id);
+	}
+
+	// A restored drone announced itself: record it as online and tell it which jobs it owns as far as we know.
+	fn answer_restore(&mut self, host: Host) {
+		let peer = format!("{}:{}", host.address, host.port).parse::<SocketAddr>();
+		let report = self.db.owned_jobs(host.id).map(|jobs| OwnershipReport { drone: host.id, jobs, reporter: self.id });
+		let host_id = host.id;
+
+		self.online(host);
+
+		let result = match (peer, report) {
+			(Ok(peer), Ok(report)) => self.send(peer, Some(host_id), &Message::with_payload(MessageType::Ownership, &report)).map_err(|err| err.to_string()),
+			(Err(err), _) => Err(err.to_string()),
+			(_, Err(err)) => Err(err.to_string()),
+		};
+
+		if let Err(err) = result {
+			error!(peer_id:% = host_id, message_type:? = MessageType::Ownership; "Failed to send job ownership to restored drone id = {}: {}", host_id, err);
+		}
+	}
+
+	// The peers' records are newer than the snapshot, so a restored drone takes on the jobs (it knows
+	// about) that a peer says it owns. Ownership is never dropped on a report: each peer only knows of
+	// some jobs, and one that never saw a job would strip it. Only drones this one knows may report.
+	fn reconcile(&mut self, report: OwnershipReport) {
+		if !self.restored || report.drone != self.id {
+			return;
+		}
+		if report.reporter == self.id || !matches!(self.db.get_host(report.reporter), Ok(Some(_))) {
+			warn!(peer_id:% = report.reporter, message_type:? = MessageType::Ownership; "Ignored job ownership reported by drone id = {}, which this drone doesn't know.", report.reporter);
+			return;
+		}
+
+		// Ownership references the drone table, which doesn't necessarily hold this drone yet.
+		let host = self.host();
+		let result = self.db.update_host(&host).and_then(|_| self.db.owned_jobs(self.id)).and_then(|owned| {
+			let mut changes = 0;
+
+			for job_id in report.jobs.iter().filter(|job_id| !owned.contains(job_id)) {
+				if self.db.get_job_status(*job_id)?.is_some() {
+					self.db.set_job_owner(self.id, *job_id)?;
+					changes += 1;
+				}
+			}
+
+			Ok(changes)
+		});
+
+		match result {
+			Ok(changes) => {
+				info!("Reconciled job ownership with a peer, {} changes.", changes);
+			},
+			Err(err) => {
+				error!("Failed to reconcile job ownership: {}", err);
+			},
+		}
 	}
 
 	pub fn sync(&mut self) {
@@ -47,40 +314,80 @@ impl Drone {
 	}
 
 	fn online(&mut self, host: Host) {
-		let host_id = host.id.clone();
+		let host_id = host.id;
 
-		self.db.update_host(&host).unwrap();
+		if let Err(err) = self.db.update_host(&host) {
+			error!(peer_id:% = host_id; "Failed to record remote drone id = {} as online: {}", host_id, err);
+		}
 		self.swarm.insert(host.id, host);
 
-		self.log_tx.send(LogMessage::new(
-			LogType::SystemLog,
-			format!("Remote drone id = {} has gone online.", host_id)
-		)).unwrap();
+		info!(peer_id:% = host_id; "Remote drone id = {} has gone online.", host_id);
 	}
 	
 	fn offline(&mut self, host: Host) {
-		let host_id = host.id.clone();
+		let host_id = host.id;
 
-		self.db.update_host(&host).unwrap();
+		if let Err(err) = self.db.update_host(&host) {
+			error!(peer_id:% = host_id; "Failed to record remote drone id = {} as offline: {}", host_id, err);
+		}
 		self.swarm.insert(host.id, host);
 
-		self.log_tx.send(LogMessage::new(
-			LogType::SystemLog,
-			format!("Remote drone id = {} has gone offline.", host_id)
-		)).unwrap();
+		info!(peer_id:% = host_id; "Remote drone id = {} has gone offline.", host_id);
 	}
 	
 	pub fn run(&mut self, rx: Receiver<DroneCtl>) {
-		self.log_tx.send(LogMessage::new(
-			LogType::SystemLog,
-			format!("Swarm drone id = {} running.", self.id)
-		)).unwrap();
+		info!("Swarm drone id = {} running.", self.id);
 
-		println!("drone entering work loop...");
+		debug!("Drone entering work loop.");
 		while self.online {
-			let msg = rx.recv().unwrap();
+			let timeout = if self.running.is_empty() { HOUSEKEEPING_INTERVAL } else { JOB_POLL_INTERVAL };
+			let msg = match rx.recv_timeout(timeout) {
+				Ok(msg) => Some(msg),
+				Err(RecvTimeoutError::Timeout) => None,
+				Err(RecvTimeoutError::Disconnected) => break,
+			};
+
+			if self.housekeeping.elapsed() >= HOUSEKEEPING_INTERVAL {
+				self.housekeeping();
+			}
+
+			if !self.running.is_empty() {
+				self.finish_jobs();
+			}
+
+			let msg = match msg {
+				Some(msg) => msg,
+				None => continue,
+			};
 
 			match msg.dronectl_type {
+				DroneCtlType::ArchiveExport => {
+					let reply = match serde_json::from_str::<ArchiveQuery>(msg.msg.as_deref().unwrap_or("{}")) {
+						Ok(query) => match self.db.archived_jobs(&query) {
+							Ok(jobs) => CtlReply::Archive(jobs),
+							Err(err) => CtlReply::Error(format!("archive export failed: {}", err)),
+						},
+						Err(err) => CtlReply::Error(format!("invalid archive query: {}", err)),
+					};
+					msg.reply(reply);
+				},
+				DroneCtlType::ArchiveMessage => {
+					if let Some(message_data) = msg.message_data {
+						self.archive_message(message_data);
+					}
+				},
+				DroneCtlType::JobLog => {
+					match serde_json::from_str::<JobLogQuery>(msg.msg.as_deref().unwrap_or("{}")) {
+						Ok(query) => self.job_log(query, msg),
+						Err(err) => msg.reply(CtlReply::Error(format!("invalid job log query: {}", err))),
+					}
+				},
+				DroneCtlType::Logs => {
+					match serde_json::from_str::<LogQuery>(msg.msg.as_deref().unwrap_or("{}")) {
+						Ok(query) => self.logs(query, msg),
+						Err(err) => msg.reply(CtlReply::Error(format!("invalid log query: {}", err))),
+					}
+				},
 				DroneCtlType::Offline => {
 					if let Some(host_data) = msg.host_data {
 						self.offline(host_data);
@@ -91,6 +398,47 @@ impl Drone {
 						self.online(host_data);
 					}
 				}
+				DroneCtlType::Ownership => {
+					if let Some(report) = msg.msg.as_deref().and_then(|report| serde_json::from_str::<OwnershipReport>(report).ok()) {
+						self.reconcile(report);
+					}
+				},
+				DroneCtlType::QueueJob => {
+					if let Some(job) = msg.job_data {
+						self.queue(job, msg.sender);
+					}
+				},
+				DroneCtlType::Reload => {
+					self.reload();
+				},
+				DroneCtlType::Restored => {
+					if let Some(host_data) = msg.host_data {
+						self.answer_restore(host_data);
+					}
+				},
+				DroneCtlType::Search => {
+					let reply = match serde_json::from_str::<JobQuery>(msg.msg.as_deref().unwrap_or("{}")) {
+						Ok(query) => match self.search(&query) {
+							Ok(page) => CtlReply::Search(page),
+							Err(err) => CtlReply::Error(format!("search failed: {}", err)),
+						},
+						Err(err) => CtlReply::Error(format!("invalid search query: {}", err)),
+					};
+					msg.reply(reply);
+				},
+				DroneCtlType::Snapshot => {
+					let reply = match msg.msg.as_deref() {
+						Some(dir) if !dir.is_empty() => match snapshot::create(self.db.as_ref(), &self.config, Path::new(dir)) {
+							Ok(manifest) => {
+								info!("Snapshot written to {}.", dir);
+								CtlReply::Snapshot(manifest)
+							},
+							Err(err) => CtlReply::Error(format!("snapshot failed: {}", err)),
+						},
+						_ => CtlReply::Error("no snapshot directory given".to_string()),
+					};
+					msg.reply(reply);
+				},
 				DroneCtlType::Stop => {
 					self.stop();			
 				},
@@ -99,36 +447,231 @@ impl Drone {
 		}
 
 		// Finish shutdown.
-		self.log_tx.send(LogMessage::new(
-			LogType::SystemLog,
-			format!("Swarm drone id = {} shutdown.", self.id)
-		)).unwrap();
+		info!("Swarm drone id = {} shutdown.", self.id);
 		std::thread::sleep(std::time::Duration::from_secs(2));	
 		std::process::exit(0x000);
 	}
 
+	// Re-read the config file and apply whatever can be changed on a running drone. Settings that
+	// need a restart (listener address/port, database location, id) are reported and left as they were.
+	fn reload(&mut self) {
+		let new_config = match Config::load(&self.config.file, &self.config.cli) {
+			Ok(new_config) => new_config,
+			Err(problems) => {
+				for problem in problems {
+					error!("Config reload from {} failed: {}", self.config.file.display(), problem);
+				}
+
+				return;
+			}
+		};
+		let (live, restart) = self.config.changes(&new_config);
+		let new_config = self.config.reloaded(new_config);
+
+		self.seeds = new_config.seeds.clone();
+		self.tags = new_config.tags.clone();
+		self.threads = new_config.threads;
+
+		crate::log::reload(&new_config);
+		self.config = new_config;
+
+		info!("Config reloaded from {}, applied changes: {:?}.", self.config.file.display(), live);
+
+		if !restart.is_empty() {
+			warn!("Config reloaded from {}, changes requiring a restart were not applied: {:?}.", self.config.file.display(), restart);
+		}
+	}
+
 	pub fn report(&mut self) {
 		// Send a message to all "online" hosts that we know about.
 	}
 
 	pub fn start(&mut self) {
+		if self.config.job_submitters.is_empty() {
+			warn!("No job_submitters are set, jobs sent to this drone are refused.");
+		}
+
+		// Retention also applies to messages archived before the message archive was switched off.
+		self.housekeeping();
+
+		let marker = self.config.db_dir.join(snapshot::RESTORED_MARKER);
+		if marker.exists() {
+			if let Err(err) = fs::remove_file(&marker) {
+				warn!("Failed to remove restore marker {}: {}", marker.display(), err);
+			}
+			self.announce_restore();
+		}
+
 		self.online = true;
 	}
 
 	fn stop (&mut self) {
-		self.log_tx.send(LogMessage::new(
-			LogType::SystemLog,
-			format!("Swarm drone id = {} received shutdown message from dronectl.", self.id)
-		)).unwrap();
+		info!("Swarm drone id = {} received shutdown message.", self.id);
 
 		self.online = false;
 	}
 
 	/** Job related functions */
-	fn _archive_job(&mut self, _job_id: Uuid) {}
+	// Move a single finished job to the job archive, jobs that aren't done are left alone.
+	pub fn archive_job(&mut self, job_id: Uuid) -> db::Result<bool> {
+		let archived = self.db.archive_jobs(Some(job_id), None)? > 0;
+		if archived {
+			self.remove_job_logs();
+		}
+
+		Ok(archived)
+	}
+
+	// Delete the output files of jobs that are no longer in the job table: the archive keeps the end of
+	// their output (see finish_jobs), and the files would otherwise never go away.
+	fn remove_job_logs(&mut self) {
+		let job_ids = match joblog::job_ids(&self.config.log_dir) {
+			Ok(job_ids) => job_ids,
+			Err(err) => {
+				error!("Failed to list job output files in {}: {}", self.config.log_dir.display(), err);
+				return;
+			},
+		};
+
+		for job_id in job_ids.into_iter().filter(|job_id| !self.running.contains_key(job_id)) {
+			if !matches!(self.db.get_job_status(job_id), Ok(None)) {
+				continue;
+			}
+			if let Err(err) = joblog::remove(&self.config.log_dir, job_id) {
+				error!(job_id:% = job_id; "Failed to delete the output files of job id = {}: {}", job_id, err);
+			}
+		}
+	}
+
+	// Move jobs finished more than job_archive_after days ago to the job archive, then prune it.
+	fn archive_jobs(&mut self) {
+		if self.config.job_archive_after > 0 {
+			let finished_before = (Utc::now() - chrono::Duration::days(self.config.job_archive_after as i64)).format("%Y-%m-%d %H:%M:%S").to_string();
+
+			match self.db.archive_jobs(None, Some(&finished_before)) {
+				Ok(0) => {},
+				Ok(archived) => {
+					info!("Archived {} jobs finished before {}.", archived, finished_before);
+					self.remove_job_logs();
+				},
+				Err(err) => {
+					error!("Failed to archive finished jobs: {}", err);
+				},
+			}
+		}
+
+		match self.db.prune_job_archive(self.config.job_archive_max_age, self.config.job_archive_max_rows) {
+			Ok(0) => {},
+			Ok(removed) => {
+				info!("Pruned {} jobs from the job archive.", removed);
+			},
+			Err(err) => {
+				error!("Failed to prune the job archive: {}", err);
+			},
+		}
+	}
 	
-	fn _finish_job(&mut self) {
+	// Record the outcome of every running job that has stopped, and start waiting jobs in their place.
+	fn finish_jobs(&mut self) {
+		let mut stopped = Vec::new();
+
+		for (job_id, process) in self.running.iter_mut() {
+			match process.try_finish() {
+				Ok(Some(status)) => stopped.push((*job_id, if status.success() { JobStatus::Finished } else { JobStatus::Error }, status.to_string())),
+				Ok(None) => {},
+				Err(err) => stopped.push((*job_id, JobStatus::Error, err.to_string())),
+			}
+		}
+
+		for (job_id, status, outcome) in stopped {
+			self.running.remove(&job_id);
+
+			// The end of the output goes with the job into search and the job archive.
+			let result = joblog::output(&self.config.log_dir, job_id).map_err(db::DatabaseError::Io)
+				.and_then(|output| self.db.update_job_output(job_id, &output));
+			if let Err(err) = result {
+				error!(job_id:% = job_id; "Failed to record the output of job id = {}: {}", job_id, err);
+			}
+			if let Err(err) = self.db.update_job_status(job_id, status) {
+				error!(job_id:% = job_id; "Failed to record job id = {} as {}: {}", job_id, status.as_str(), err);
+			}
+			info!(job_id:% = job_id; "Job id = {} stopped ({}), {}.", job_id, outcome, status.as_str());
+		}
 
+		self.start_jobs();
+	}
+
+	// Read part of a job's output: from this drone's log files if it ran the job, otherwise from a drone
+	// that owns it. Owners are asked in a thread that replies to msg, see logs.
+	fn job_log(&mut self, query: JobLogQuery, msg: DroneCtl) {
+		let path = joblog::path(&self.config.log_dir, query.job, query.stream);
+		let active = self.running.contains_key(&query.job) || self.workload.iter().any(|job| job.id == query.job);
+
+		if active || path.exists() {
+			let reply = match joblog::read(&path, query.offset, query.limit) {
+				Ok((data, offset)) => CtlReply::JobLog(JobLogChunk {
+					data,
+					done: !active,
+					drone: self.id,
+					offset,
+				}),
+				Err(err) => CtlReply::Error(format!("failed to read {}: {}", path.display(), err)),
+			};
+
+			msg.reply(reply);
+			return;
+		}
+
+		if query.forwarded {
+			msg.reply(CtlReply::Error(format!("drone id = {} has no output for job id = {}", self.id, query.job)));
+			return;
+		}
+
+		let owners = match self.db.job_owners(query.job) {
+			Ok(owners) => owners,
+			Err(err) => {
+				msg.reply(CtlReply::Error(format!("failed to look up the owners of job id = {}: {}", query.job, err)));
+				return;
+			},
+		};
+		let id = self.id;
+		let peers: Vec<(Uuid, Result<SocketAddr, String>)> = owners.into_iter().filter(|owner| *owner != id).map(|owner| {
+			let peer = match self.db.get_host(owner) {
+				Ok(Some(host)) => format!("{}:{}", host.address, host.port).parse::<SocketAddr>().map_err(|err| err.to_string()),
+				Ok(None) => Err(String::from("unknown drone")),
+				Err(err) => Err(err.to_string()),
+			};
+			(owner, peer)
+		}).collect();
+
+		let forwarded = JobLogQuery { forwarded: true, ..query };
+		let message = Message::with_payload(MessageType::JobLog, &forwarded);
+		let asker = self.asker();
+
+		thread::spawn(move || {
+			let mut problems = Vec::new();
+
+			for (owner, peer) in peers {
+				let answer = peer.and_then(|peer| asker.ask(peer, Some(owner), &message).map_err(|err| err.to_string()))
+					.and_then(|answer| answer.payload::<CtlReply>().map_err(|err| err.to_string()));
+
+				match answer {
+					Ok(CtlReply::JobLog(chunk)) => {
+						msg.reply(CtlReply::JobLog(chunk));
+						return;
+					},
+					Ok(CtlReply::Error(err)) => problems.push(format!("{}: {}", owner, err)),
+					Ok(_) => problems.push(format!("{}: unexpected answer", owner)),
+					Err(err) => problems.push(format!("{}: {}", owner, err)),
+				}
+			}
+
+			if problems.is_empty() {
+				msg.reply(CtlReply::Error(format!("job id = {} has no output on this drone and no other owner is known", forwarded.job)));
+			} else {
+				msg.reply(CtlReply::Error(format!("no owner of job id = {} answered with its output ({})", forwarded.job, problems.join("; "))));
+			}
+		});
 	}
 
 	fn _load(&mut self) {
@@ -139,8 +682,73 @@ impl Drone {
 		// Save this worker's state from the local db.
 	}
 
-	fn _start_job(&mut self) {}
-	
+	// Take on a job sent by another drone: record it as owned by this drone and run it when a thread is
+	// free. Over TLS, sender is the drone id the other drone's certificate names.
+	pub fn queue(&mut self, job: Job, sender: Option<Uuid>) {
+		// Over TLS, jobs are only taken from drones of the swarm this one knows.
+		if let Some(sender) = sender {
+			if !matches!(self.db.get_host(sender), Ok(Some(_))) {
+				error!(job_id:% = job.id, peer_id:% = sender; "Refused job id = {} from drone id = {}, which this drone doesn't know.", job.id, sender);
+				return;
+			}
+		}
+		if let Err(err) = signing::authorize(&job, &self.config) {
+			error!(job_id:% = job.id; "Rejected job id = {} submitted by \"{}\": {}", job.id, job.submitter, err);
+			return;
+		}
+
+		// Ownership references the drone table, which doesn't necessarily hold this drone yet.
+		let host = self.host();
+		let result = self.db.update_host(&host)
+			.and_then(|_| self.db.insert_job(&job))
+			.and_then(|_| self.db.set_job_owner(self.id, job.id));
+
+		if let Err(err) = result {
+			error!(job_id:% = job.id; "Failed to queue job id = {}: {}", job.id, err);
+			return;
+		}
+
+		match sender {
+			Some(sender) => debug!(job_id:% = job.id, peer_id:% = sender; "Queued job id = {} from drone id = {}.", job.id, sender),
+			None => debug!(job_id:% = job.id; "Queued job id = {}.", job.id),
+		}
+		self.workload.push(job);
+		self.start_jobs();
+	}
+
+	fn start_job(&mut self, job: Job) {
+		// Checked again, job_submitters or job_acl may have been reloaded since the job was queued.
+		let process = signing::authorize(&job, &self.config)
+			.map_err(|err| io::Error::new(io::ErrorKind::PermissionDenied, format!("submitted by \"{}\", {}", job.submitter, err)))
+			.and_then(|_| JobProcess::spawn(&job.command, &self.config.log_dir, job.id, self.config.job_log_max_size));
+
+		match process {
+			Ok(process) => {
+				if let Err(err) = self.db.update_job_status(job.id, JobStatus::Working) {
+					error!(job_id:% = job.id; "Failed to record job id = {} as Working: {}", job.id, err);
+				}
+				self.running.insert(job.id, process);
+
+				info!(job_id:% = job.id; "Started job id = {}: {}", job.id, job.command.join(" "));
+			},
+			Err(err) => {
+				if let Err(err) = self.db.update_job_status(job.id, JobStatus::Error) {
+					error!(job_id:% = job.id; "Failed to record job id = {} as Error: {}", job.id, err);
+				}
+
+				error!(job_id:% = job.id; "Failed to start job id = {}: {}", job.id, err);
+			},
+		}
+	}
+
+	// Start queued jobs, oldest first, while fewer than threads are running.
+	fn start_jobs(&mut self) {
+		while self.running.len() < self.threads && !self.workload.is_empty() {
+			let job = self.workload.remove(0);
+			self.start_job(job);
+		}
+	}
+
 	pub fn submit(&mut self) {
 		// Add a new job to the queue.
 		// This inlcudes passing the job details on to all known hosts.
@@ -148,3 +756,51 @@ impl Drone {
 
 	pub fn work(&mut self, _job_id: Uuid) {}
 }
+
+// Asks other drones questions from a thread of its own, see Drone::asker.
+pub struct Asker {
+	archive_tx:					Option<Sender<DroneCtl>>,
+	secret:						String,
+	tls:						Option<Arc<Tls>>,
+}
+
+impl Asker {
+	// Send a message that another drone answers on the same connection, and read the answer. Like
+	// Drone::send, expected is the drone's id if it's known.
+	pub fn ask(&self, peer: SocketAddr, expected: Option<Uuid>, message: &Message) -> io::Result<Message> {
+		let payload = bincode::serialize(message).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+		let result = connect(peer, expected, self.tls.as_deref(), self.secret.as_bytes()).and_then(|(mut stream, session)| {
+			stream.write_all(&session.seal(&payload))?;
+			stream.finish()?;
+			Ok((stream, session))
+		});
+
+		let outcome = if result.is_ok() { MessageOutcome::Sent } else { MessageOutcome::SendFailed };
+		if let Some(archive_tx) = &self.archive_tx {
+			let _ = archive_tx.send(DroneCtl::archive(MessageRecord::new(MessageDirection::Sent, peer.to_string(), &payload, outcome)));
+		}
+
+		let (stream, session) = result?;
+		stream.tcp().set_read_timeout(Some(ANSWER_TIMEOUT))?;
+
+		let mut answer = Vec::new();
+		stream.take(MAX_ANSWER_SIZE + TAG_SIZE as u64).read_to_end(&mut answer)?;
+
+		bincode::deserialize(session.open(&answer)?).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+	}
+}
+
+// Open an authenticated connection to another drone's external listener, over TLS if it's configured,
+// see auth::Session and tls::Tls. Over TLS, a drone that's expected has to show its own certificate.
+fn connect(peer: SocketAddr, expected: Option<Uuid>, tls: Option<&Tls>, secret: &[u8]) -> io::Result<(PeerStream, Session)> {
+	let stream = TcpStream::connect_timeout(&peer, SEND_TIMEOUT)?;
+	stream.set_read_timeout(Some(SEND_TIMEOUT))?;
+
+	let mut stream = match tls {
+		Some(tls) => tls.connect(stream, expected)?,
+		None => PeerStream::Plain(stream),
+	};
+	let session = Session::client(&mut stream, secret)?;
+
+	Ok((stream, session))
+}
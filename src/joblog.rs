@@ -0,0 +1,352 @@
+use log::warn;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::models::JobStream;
+
+
+// Chunks are cut down to this, so that an answer always fits in one message between drones.
+pub const MAX_CHUNK: usize = 256 * 1024;
+
+// How much of the end of each output stream is kept with the job in the database, see output.
+pub const MAX_OUTPUT: u64 = 64 * 1024;
+
+// How long after a job exits its output is waited for. A process the job left running in the background
+// may hold stdout or stderr open for much longer, its capture threads are then left to finish on their own.
+const CAPTURE_GRACE: Duration = Duration::from_secs(10);
+
+// A running job: its process, and a thread per output stream copying it to the job's log files.
+pub struct JobProcess {
+	captures:						Vec<JoinHandle<io::Result<u64>>>,
+	child:							Child,
+	// The exit status and when it was seen, while the output is still being captured.
+	exited:							Option<(ExitStatus, Instant)>,
+	grace:							Duration,
+}
+
+impl JobProcess {
+	// Start a job's command with stdout and stderr captured under log_dir/jobs, see capture.
+	pub fn spawn(command: &[String], log_dir: &Path, job_id: Uuid, max_size: u64) -> io::Result<Self> {
+		let (program, args) = command.split_first().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "the job has no command"))?;
+
+		let mut child = Command::new(program)
+			.args(args)
+			.stdin(Stdio::null())
+			.stdout(Stdio::piped())
+			.stderr(Stdio::piped())
+			.spawn()?;
+
+		let mut captures = Vec::new();
+		let stdout = child.stdout.take().map(|stdout| capture(path(log_dir, job_id, JobStream::Stdout), stdout, max_size));
+		let stderr = child.stderr.take().map(|stderr| capture(path(log_dir, job_id, JobStream::Stderr), stderr, max_size));
+		for handle in stdout.into_iter().chain(stderr) {
+			match handle {
+				Ok(handle) => captures.push(handle),
+				Err(err) => {
+					let _ = child.kill();
+					let _ = child.wait();
+					return Err(err);
+				},
+			}
+		}
+
+		Ok(JobProcess {
+			captures,
+			child,
+			exited: None,
+			grace: CAPTURE_GRACE,
+		})
+	}
+
+	// The exit status once the job has stopped and all of its output is written, None while it runs. Never
+	// waits: capture threads are only joined once they are done, and given up on after the grace period.
+	pub fn try_finish(&mut self) -> io::Result<Option<ExitStatus>> {
+		let (status, exited) = match self.exited {
+			Some(exited) => exited,
+			None => match self.child.try_wait()? {
+				Some(status) => *self.exited.insert((status, Instant::now())),
+				None => return Ok(None),
+			},
+		};
+
+		let (done, running): (Vec<_>, Vec<_>) = self.captures.drain(..).partition(|handle| handle.is_finished());
+		self.captures = running;
+		for handle in done {
+			match handle.join() {
+				Ok(Ok(_)) => {},
+				Ok(Err(err)) => return Err(err),
+				Err(_) => return Err(io::Error::other("output capture thread panicked")),
+			}
+		}
+
+		if !self.captures.is_empty() {
+			if exited.elapsed() < self.grace {
+				return Ok(None);
+			}
+			warn!("Job process {} exited {:?} ago but its output is still open, not waiting for the rest of it.", self.child.id(), self.grace);
+			self.captures.clear();
+		}
+
+		Ok(Some(status))
+	}
+}
+
+// Where a job's output is kept: log_dir/jobs/<job id>.<stdout|stderr>.
+pub fn path(log_dir: &Path, job_id: Uuid, stream: JobStream) -> PathBuf {
+	log_dir.join("jobs").join(format!("{}.{}", job_id, stream.as_str()))
+}
+
+// The end of a job's captured output, kept with the job in the database for search and the job archive:
+// the last MAX_OUTPUT bytes of stdout, then of stderr if there is any.
+pub fn output(log_dir: &Path, job_id: Uuid) -> io::Result<String> {
+	let mut output = tail(&path(log_dir, job_id, JobStream::Stdout), MAX_OUTPUT)?;
+	let stderr = tail(&path(log_dir, job_id, JobStream::Stderr), MAX_OUTPUT)?;
+
+	if !stderr.is_empty() {
+		if !output.is_empty() && !output.ends_with('\n') {
+			output.push('\n');
+		}
+		output.push_str("[stderr]\n");
+		output.push_str(&stderr);
+	}
+
+	Ok(output)
+}
+
+// Delete a job's output files, once it's archived (with its output) or gone.
+pub fn remove(log_dir: &Path, job_id: Uuid) -> io::Result<()> {
+	for stream in [JobStream::Stdout, JobStream::Stderr].iter() {
+		match fs::remove_file(path(log_dir, job_id, *stream)) {
+			Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+			_ => {},
+		}
+	}
+
+	Ok(())
+}
+
+// The ids of the jobs that have output files under log_dir/jobs.
+pub fn job_ids(log_dir: &Path) -> io::Result<Vec<Uuid>> {
+	let entries = match fs::read_dir(log_dir.join("jobs")) {
+		Ok(entries) => entries,
+		Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+		Err(err) => return Err(err),
+	};
+
+	let mut ids: Vec<Uuid> = entries.flatten()
+		.filter_map(|entry| entry.path().file_stem().and_then(|stem| stem.to_str()).and_then(|stem| Uuid::parse_str(stem).ok()))
+		.collect();
+	ids.sort();
+	ids.dedup();
+
+	Ok(ids)
+}
+
+// Copy a job's output stream to a file in a thread, until the job closes it. Past max_size MiB (0 for no
+// limit) a note is written and the rest of the output is read and dropped, so the job never blocks on a
+// full pipe. The thread returns how much the job wrote.
+pub fn capture<R: Read + Send + 'static>(path: PathBuf, mut output: R, max_size: u64) -> io::Result<JoinHandle<io::Result<u64>>> {
+	if let Some(dir) = path.parent() {
+		fs::create_dir_all(dir)?;
+	}
+	let mut file = File::create(&path)?;
+	let max_size = max_size * 1024 * 1024;
+
+	Ok(thread::spawn(move || {
+		let mut buffer = [0; 8192];
+		let mut capped = false;
+		let mut failed = None;
+		let mut written = 0;
+
+		loop {
+			let read = match output.read(&mut buffer) {
+				Ok(0) => break,
+				Ok(read) => read,
+				Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+				Err(err) => return Err(err),
+			};
+
+			if !capped {
+				let keep = if max_size == 0 { read } else { max_size.saturating_sub(written).min(read as u64) as usize };
+				let mut result = file.write_all(&buffer[..keep]);
+
+				if keep < read {
+					capped = true;
+					result = result.and_then(|_| writeln!(file, "\n[output truncated at {} bytes]", max_size));
+				}
+				if let Err(err) = result {
+					warn!("Failed to write job output to {}, dropping the rest: {}", path.display(), err);
+					capped = true;
+					failed = Some(err);
+				}
+			}
+
+			written += read as u64;
+		}
+
+		match failed {
+			Some(err) => Err(err),
+			None => Ok(written),
+		}
+	}))
+}
+
+// Read up to limit bytes of captured output from offset, and the offset the next read starts at. A chunk
+// never ends inside a UTF-8 character, the rest of it is read with the next chunk. Nothing captured yet
+// (no file) reads as no output.
+pub fn read(path: &Path, offset: u64, limit: usize) -> io::Result<(String, u64)> {
+	let mut file = match File::open(path) {
+		Ok(file) => file,
+		Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok((String::new(), offset)),
+		Err(err) => return Err(err),
+	};
+
+	let mut data = Vec::new();
+	file.seek(SeekFrom::Start(offset))?;
+	file.take(limit.clamp(4, MAX_CHUNK) as u64).read_to_end(&mut data)?;
+
+	// The last character starts at the last byte that isn't a continuation byte (10xxxxxx).
+	let mut end = data.len();
+	if let Some(start) = data.iter().rev().take(4).position(|byte| byte & 0xC0 != 0x80).map(|back| data.len() - 1 - back) {
+		let width = match data[start] {
+			0xC0..=0xDF => 2,
+			0xE0..=0xEF => 3,
+			0xF0..=0xF7 => 4,
+			_ => 1,
+		};
+		if start + width > data.len() {
+			end = start;
+		}
+	}
+
+	Ok((String::from_utf8_lossy(&data[..end]).into_owned(), offset + end as u64))
+}
+
+// The last limit bytes of a file, from the first whole UTF-8 character on. No file reads as nothing.
+fn tail(path: &Path, limit: u64) -> io::Result<String> {
+	let mut file = match File::open(path) {
+		Ok(file) => file,
+		Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(String::new()),
+		Err(err) => return Err(err),
+	};
+
+	let size = file.metadata()?.len();
+	let mut data = Vec::new();
+	file.seek(SeekFrom::Start(size.saturating_sub(limit)))?;
+	file.take(limit).read_to_end(&mut data)?;
+
+	let start = if size > limit { data.iter().take(4).take_while(|byte| *byte & 0xC0 == 0x80).count() } else { 0 };
+
+	Ok(String::from_utf8_lossy(&data[start..]).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn job_log_capture_test() {
+		let dir = std::env::temp_dir().join(format!("swarm_joblog_test_{}", Uuid::new_v4()));
+		let job_id = Uuid::new_v4();
+		let file = path(&dir, job_id, JobStream::Stdout);
+
+		// Everything up to the cap is kept, the rest is counted but dropped.
+		let output = vec![b'x'; 1024 * 1024 + 10];
+		let written = capture(file.clone(), io::Cursor::new(output), 1).unwrap().join().unwrap().unwrap();
+		assert_eq!(written, 1024 * 1024 + 10);
+
+		let content = fs::read_to_string(&file).unwrap();
+		assert!(content.starts_with(&"x".repeat(1024 * 1024)));
+		assert!(content.ends_with("\n[output truncated at 1048576 bytes]\n"));
+
+		// Reads are cut to MAX_CHUNK and carry on where the last one stopped.
+		let (data, offset) = read(&file, 0, usize::MAX).unwrap();
+		assert_eq!((data.len(), offset), (MAX_CHUNK, MAX_CHUNK as u64));
+		let (data, offset) = read(&file, offset, 10).unwrap();
+		assert_eq!((data.as_str(), offset), ("xxxxxxxxxx", MAX_CHUNK as u64 + 10));
+
+		// Nothing captured (yet) is no output.
+		let missing = path(&dir, job_id, JobStream::Stderr);
+		assert_eq!(read(&missing, 0, 100).unwrap(), (String::new(), 0));
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn job_log_read_utf8_test() {
+		let dir = std::env::temp_dir().join(format!("swarm_joblog_test_{}", Uuid::new_v4()));
+		let file = path(&dir, Uuid::new_v4(), JobStream::Stderr);
+		capture(file.clone(), io::Cursor::new("aé€😀".as_bytes().to_vec()), 0).unwrap().join().unwrap().unwrap();
+
+		// A character split by the end of a chunk is left for the next one.
+		let mut text = String::new();
+		let mut offset = 0;
+		for limit in [5, 4, 4].iter() {
+			let (data, next) = read(&file, offset, *limit).unwrap();
+			text.push_str(&data);
+			offset = next;
+		}
+		assert_eq!((text.as_str(), offset), ("aé€😀", 10));
+		assert_eq!(read(&file, 0, 5).unwrap(), (String::from("aé"), 3));
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn job_log_background_test() {
+		let dir = std::env::temp_dir().join(format!("swarm_joblog_test_{}", Uuid::new_v4()));
+		let job_id = Uuid::new_v4();
+		let command: Vec<String> = ["sh", "-c", "echo started; sleep 5 &"].iter().map(|arg| arg.to_string()).collect();
+
+		// The job exits at once, but the sleep it left behind keeps stdout open: try_finish never waits for
+		// it, and gives up on the output after the grace period.
+		let mut process = JobProcess::spawn(&command, &dir, job_id, 0).unwrap();
+		process.grace = Duration::from_millis(500);
+		let started = Instant::now();
+		let status = loop {
+			let checked = Instant::now();
+			let status = process.try_finish().unwrap();
+			assert!(checked.elapsed() < Duration::from_millis(100));
+			if let Some(status) = status {
+				break status;
+			}
+			thread::sleep(Duration::from_millis(20));
+		};
+		assert!(status.success());
+		assert!(started.elapsed() >= Duration::from_millis(500) && started.elapsed() < Duration::from_secs(4));
+		assert_eq!(fs::read_to_string(path(&dir, job_id, JobStream::Stdout)).unwrap(), "started\n");
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn job_log_output_test() {
+		let dir = std::env::temp_dir().join(format!("swarm_joblog_test_{}", Uuid::new_v4()));
+		let job_id = Uuid::new_v4();
+		let mut stdout = "é".repeat(MAX_OUTPUT as usize);
+		stdout.push_str("end\n");
+		capture(path(&dir, job_id, JobStream::Stdout), io::Cursor::new(stdout.into_bytes()), 0).unwrap().join().unwrap().unwrap();
+		capture(path(&dir, job_id, JobStream::Stderr), io::Cursor::new(b"oops\n".to_vec()), 0).unwrap().join().unwrap().unwrap();
+
+		// The end of each stream, from a whole character on.
+		let kept = output(&dir, job_id).unwrap();
+		assert!(kept.len() <= 2 * MAX_OUTPUT as usize);
+		assert!(kept.starts_with('é') && kept.ends_with("end\n[stderr]\noops\n"));
+		assert_eq!(job_ids(&dir).unwrap(), vec![job_id]);
+
+		remove(&dir, job_id).unwrap();
+		assert!(job_ids(&dir).unwrap().is_empty());
+		assert_eq!(output(&dir, job_id).unwrap(), "");
+		remove(&dir, job_id).unwrap();
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
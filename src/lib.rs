@@ -1,12 +1,20 @@
+pub mod auth;
+pub mod config;
+pub mod control;
 pub mod db;
 pub mod drone;
+pub mod joblog;
 pub mod models;
 pub mod log;
+pub mod signing;
+pub mod snapshot;
+pub mod tls;
 
 #[cfg(test)]
 mod tests {
 
 	#[test]
+	#[allow(clippy::assertions_on_constants)]
 	fn unit_tests_work() {
 		assert!(true);
 	}
@@ -1,100 +1,1195 @@
-use chrono::Local;
+use ::log::{kv, LevelFilter, Metadata, Record, SetLoggerError};
+use chrono::{DateTime, Local, NaiveDateTime, SecondsFormat, TimeZone, Utc};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use regex::Regex;
+use serde::de::IntoDeserializer;
+use serde::{Deserialize, Serialize};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
 use std::fs;
-use std::fs::OpenOptions;
-use std::io::Write;
-use std::sync::mpsc::Receiver;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
 use uuid::Uuid;
 
-use crate::models::{LogType, LogMessage, MessageType};
+use crate::config::Config;
+use crate::models::{LogLevel, LogLine, LogMessage, LogQuery, MessageType};
 
 
-const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-// @TODO - log levels Warn, Fatal, Info, etc
+// Most lines search returns, whatever the query's limit.
+pub const MAX_SEARCH_LINES: usize = 10_000;
+
+// Lines from search are cut down to this much JSON, so that an answer always fits in one message
+// between drones.
+pub const MAX_SEARCH_SIZE: usize = 768 * 1024;
+
+// The log process, once install made it the backend of the log facade.
+static LOG_TX: OnceLock<LogSender> = OnceLock::new();
+
+// Messages that didn't make it to where they were going, shared by the senders and the log process.
+#[derive(Debug, Default)]
+pub struct LogCounters {
+	// Dropped because the log queue was full (log_overflow = drop).
+	pub dropped:					AtomicU64,
+	// Written to stderr instead, because their destination failed or the log process was gone.
+	pub fallback:					AtomicU64,
+}
+
+// The sending end of the log process' queue, see channel.
+#[derive(Clone)]
+pub struct LogSender {
+	counters:						Arc<LogCounters>,
+	overflow:						LogOverflow,
+	tx:								SyncSender<LogMessage>,
+}
+
+impl LogSender {
+	// Queue a message for the log process, without ever failing the caller. When the queue is full the
+	// message is dropped and counted (reported by the log process later on), unless log_overflow is
+	// block; reloads and the like always wait for room. Once the log process is gone, messages are
+	// written to stderr.
+	pub fn send(&self, msg: LogMessage) {
+		let overflow = if msg.message_type == MessageType::Message { self.overflow } else { LogOverflow::Block };
+
+		let result = match overflow {
+			LogOverflow::Block => self.tx.send(msg).map_err(|err| err.0),
+			LogOverflow::Drop => match self.tx.try_send(msg) {
+				Ok(()) => Ok(()),
+				Err(TrySendError::Full(_)) => {
+					self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+					Ok(())
+				},
+				Err(TrySendError::Disconnected(msg)) => Err(msg),
+			},
+		};
+
+		if let Err(msg) = result {
+			if msg.message_type == MessageType::Message {
+				self.counters.fallback.fetch_add(1, Ordering::Relaxed);
+				let _ = io::stderr().write_all(Log::format_msg(&msg).as_bytes());
+			}
+		}
+	}
+}
+
+// The receiving end of the log process' queue, see Log::run.
+pub struct LogReceiver {
+	counters:						Arc<LogCounters>,
+	rx:								Receiver<LogMessage>,
+}
+
+// The queue to the log process, holding up to log_queue_size messages.
+pub fn channel(config: &Config) -> (LogSender, LogReceiver) {
+	let counters = Arc::new(LogCounters::default());
+	let (tx, rx) = mpsc::sync_channel(config.log_queue_size.max(1));
+
+	let sender = LogSender {
+		counters: counters.clone(),
+		overflow: config.log_overflow,
+		tx,
+	};
+
+	(sender, LogReceiver {
+		counters,
+		rx,
+	})
+}
+
+// Backend for the log facade: records from this crate (and anything else using the facade in the same
+// process) are passed on to the log process, which filters, formats and routes them. The job_id,
+// peer_id and message_type key/values become the matching LogMessage context, other key/values its
+// free-form fields. Applications embedding swarm can install their own logger instead.
+pub struct Logger {
+	tx:								LogSender,
+}
+
+impl ::log::Log for Logger {
+	// Filtering by target is left to the log process, which has the current config.
+	fn enabled(&self, _metadata: &Metadata) -> bool {
+		true
+	}
+
+	fn log(&self, record: &Record) {
+		let level = match record.level() {
+			::log::Level::Error => LogLevel::Error,
+			::log::Level::Warn => LogLevel::Warn,
+			::log::Level::Info => LogLevel::Info,
+			::log::Level::Debug => LogLevel::Debug,
+			::log::Level::Trace => LogLevel::Trace,
+		};
+
+		let mut msg = LogMessage::new(level, record.target(), record.args().to_string());
+		let _ = record.key_values().visit(&mut msg);
+
+		self.tx.send(msg);
+	}
+
+	fn flush(&self) {}
+}
+
+impl<'kvs> kv::VisitSource<'kvs> for LogMessage {
+	fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+		let value = value.to_string();
+
+		match key.as_str() {
+			"job_id" => self.job_id = Uuid::parse_str(&value).ok(),
+			"message_type" => self.peer_message_type = MessageType::deserialize(IntoDeserializer::<serde::de::value::Error>::into_deserializer(value.as_str())).ok(),
+			"peer_id" => self.peer_id = Uuid::parse_str(&value).ok(),
+			key => {
+				self.fields.insert(key.to_string(), value);
+			},
+		}
+
+		Ok(())
+	}
+}
+
+// Make the log process (see Log::run) the backend of the log facade, fails if a logger is already set.
+pub fn install(tx: LogSender, config: &Config) -> Result<(), SetLoggerError> {
+	::log::set_boxed_logger(Box::new(Logger {
+		tx: tx.clone(),
+	}))?;
+	::log::set_max_level(max_level(config));
+	let _ = LOG_TX.set(tx);
+
+	Ok(())
+}
+
+// Have the installed log process apply a reloaded config, a no-op when swarm logs through another logger.
+pub fn reload(config: &Config) {
+	if let Some(tx) = LOG_TX.get() {
+		::log::set_max_level(max_level(config));
+		tx.send(LogMessage::reload(config.clone()));
+	}
+}
+
+// Have the installed log process reopen its files.
+pub fn reopen() {
+	if let Some(tx) = LOG_TX.get() {
+		tx.send(LogMessage::reopen());
+	}
+}
+
+// The most verbose level any target is configured for, the facade drops anything below it before
+// formatting the message.
+fn max_level(config: &Config) -> LevelFilter {
+	let level = config.log_targets.iter().map(|target| target.level).fold(config.log_level, LogLevel::min);
+
+	match level {
+		LogLevel::Trace => LevelFilter::Trace,
+		LogLevel::Debug => LevelFilter::Debug,
+		LogLevel::Info => LevelFilter::Info,
+		LogLevel::Warn => LevelFilter::Warn,
+		LogLevel::Error | LogLevel::Fatal => LevelFilter::Error,
+	}
+}
+
+// The newest query.limit (at most MAX_SEARCH_LINES) lines of this drone's log files (and their rotated
+// copies) written since query.since and matching query.grep, oldest first. A message routed to several
+// files is found once (a line repeated within a second counts as often as the file repeating it most).
+// Only the newest lines are kept while reading, and older ones are left out past MAX_SEARCH_SIZE.
+pub fn search(config: &Config, query: &LogQuery) -> Result<Vec<LogLine>, String> {
+	let limit = query.limit.min(MAX_SEARCH_LINES);
+	let grep = match &query.grep {
+		Some(grep) => Some(Regex::new(grep).map_err(|err| format!("invalid --grep pattern: {}", err))?),
+		None => None,
+	};
+	let since = query.since.as_deref().unwrap_or("");
+
+	let mut paths = vec![config.log_dir.join(&config.error_log), config.log_dir.join(&config.system_log)];
+	for route in config.log_routes.iter() {
+		if let LogDestination::File(file) = &route.destination {
+			let path = config.log_dir.join(file);
+			if !paths.contains(&path) {
+				paths.push(path);
+			}
+		}
+	}
+
+	let mut found: BTreeMap<(String, String), usize> = BTreeMap::new();
+	for path in paths.iter() {
+		let mut files = Log::rotated_files(path).unwrap_or_default();
+		files.push(path.clone());
+
+		for file in files.iter() {
+			// Nothing in a file last written before since can match.
+			let modified = fs::metadata(file).and_then(|metadata| metadata.modified()).map(|modified| DateTime::<Utc>::from(modified).format("%Y-%m-%d %H:%M:%S").to_string());
+			match modified {
+				Ok(modified) if modified.as_str() >= since => {},
+				_ => continue,
+			}
+
+			let reader: Box<dyn Read> = match File::open(file) {
+				Ok(reader) if file.extension().is_some_and(|extension| extension == "gz") => Box::new(GzDecoder::new(reader)),
+				Ok(reader) => Box::new(reader),
+				Err(_) => continue,
+			};
+
+			// Lines without a timestamp continue a multi-line message.
+			let mut lines: BTreeMap<(String, String), usize> = BTreeMap::new();
+			let mut kept = 0;
+			let mut timestamp = String::new();
+			for line in BufReader::new(reader).split(b'\n') {
+				let line = match line {
+					Ok(line) => String::from_utf8_lossy(&line).into_owned(),
+					Err(_) => break,
+				};
+				let line = match line_time(&line) {
+					Some((time, rest)) => {
+						timestamp = time.format("%Y-%m-%d %H:%M:%S%.3f").to_string();
+						rest.to_string()
+					},
+					None => line,
+				};
+
+				if !timestamp.is_empty() && timestamp.as_str() >= since && grep.as_ref().is_none_or(|grep| grep.is_match(&line)) {
+					*lines.entry((timestamp.clone(), line)).or_insert(0) += 1;
+					kept += 1;
+					if kept > limit {
+						drop_oldest(&mut lines);
+						kept -= 1;
+					}
+				}
+			}
+
+			for (key, count) in lines {
+				let found = found.entry(key).or_insert(0);
+				*found = count.max(*found);
+			}
+			let mut total: usize = found.values().sum();
+			while total > limit {
+				drop_oldest(&mut found);
+				total -= 1;
+			}
+		}
+	}
+
+	let lines: Vec<LogLine> = found.into_iter()
+		.flat_map(|((timestamp, line), count)| std::iter::repeat_n(LogLine { drone: config.id, line, timestamp }, count))
+		.collect();
+
+	let mut size = 0;
+	let skip = lines.iter().rev()
+		.position(|line| {
+			size += serde_json::to_vec(line).map_or(MAX_SEARCH_SIZE, |json| json.len() + 1);
+			size > MAX_SEARCH_SIZE
+		})
+		.map_or(0, |kept| lines.len() - kept);
+
+	Ok(lines.into_iter().skip(skip).collect())
+}
+
+// One occurrence less of the oldest line.
+fn drop_oldest(lines: &mut BTreeMap<(String, String), usize>) {
+	if let Some(mut oldest) = lines.first_entry() {
+		if *oldest.get() > 1 {
+			*oldest.get_mut() -= 1;
+		} else {
+			oldest.remove();
+		}
+	}
+}
+
+// When a log line was written, from the local time starting a text line or the timestamp of a JSON
+// line, and the line without a text timestamp.
+fn line_time(line: &str) -> Option<(DateTime<Utc>, &str)> {
+	if line.starts_with('{') {
+		let record: serde_json::Value = serde_json::from_str(line).ok()?;
+		let time = DateTime::parse_from_rfc3339(record.get("timestamp")?.as_str()?).ok()?;
+
+		return Some((time.with_timezone(&Utc), line));
+	}
+
+	let (stamp, rest) = line.strip_prefix('[')?.split_once("] ")?;
+	let time = NaiveDateTime::parse_from_str(stamp, "%Y-%m-%d %H:%M:%S").ok()?;
+
+	Some((Local.from_local_datetime(&time).earliest()?.with_timezone(&Utc), rest))
+}
+
+// Where a routed message is written.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LogDestination {
+	// The files named by error_log and system_log.
+	Error,
+	System,
+	Stderr,
+	Stdout,
+	// Any other file name inside log_dir.
+	File(PathBuf),
+}
+
+impl LogDestination {
+	pub fn from_name(name: &str) -> Option<Self> {
+		match name.trim() {
+			"" => None,
+			"error" => Some(LogDestination::Error),
+			"stderr" => Some(LogDestination::Stderr),
+			"stdout" => Some(LogDestination::Stdout),
+			"system" => Some(LogDestination::System),
+			file => Some(LogDestination::File(PathBuf::from(file))),
+		}
+	}
+
+	pub fn name(&self) -> String {
+		match self {
+			LogDestination::Error => String::from("error"),
+			LogDestination::Stderr => String::from("stderr"),
+			LogDestination::Stdout => String::from("stdout"),
+			LogDestination::System => String::from("system"),
+			LogDestination::File(file) => file.display().to_string(),
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+	// One JSON object per line, see LogRecord.
+	Json,
+	Text,
+}
+
+// Picks the format of one destination, written "<destination>=<format>" in the config, e.g.
+// system=json. Destinations without a rule are written as text.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct LogFormatRule {
+	pub destination:				LogDestination,
+	pub format:						LogFormat,
+}
+
+impl TryFrom<String> for LogFormatRule {
+	type Error = String;
+
+	fn try_from(rule: String) -> Result<Self, String> {
+		let (destination, format) = rule.split_once('=')
+			.ok_or_else(|| format!("invalid log format \"{}\", expected <destination>=<format>, e.g. system=json", rule))?;
+		let destination = LogDestination::from_name(destination).ok_or_else(|| format!("log format \"{}\" has no destination", rule))?;
+
+		let format = match format.trim() {
+			"json" => LogFormat::Json,
+			"text" => LogFormat::Text,
+			other => return Err(format!("unknown log format \"{}\" in \"{}\", expected json or text", other, rule)),
+		};
+
+		Ok(LogFormatRule {
+			destination,
+			format,
+		})
+	}
+}
+
+impl From<LogFormatRule> for String {
+	fn from(rule: LogFormatRule) -> Self {
+		let format = match rule.format {
+			LogFormat::Json => "json",
+			LogFormat::Text => "text",
+		};
+
+		format!("{}={}", rule.destination.name(), format)
+	}
+}
+
+// A log line in the JSON format.
+#[derive(Serialize)]
+struct LogRecord<'a> {
+	timestamp:						String,
+	level:							&'a str,
+	target:							&'a str,
+	drone_id:						Uuid,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	job_id:							Option<Uuid>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	peer_id:						Option<Uuid>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	message_type:					Option<MessageType>,
+	message:						&'a str,
+	#[serde(skip_serializing_if = "BTreeMap::is_empty")]
+	fields:							&'a BTreeMap<String, String>,
+}
+
+// A routing rule, written "<levels>=<destination>" in the config. Levels is a single level ("info"), a
+// level and everything above it ("warn+") or a range ("debug-info"), destination is error, system,
+// stdout, stderr or a file name inside log_dir. A message is written by every route it matches.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct LogRoute {
+	pub destination:				LogDestination,
+	pub max:						LogLevel,
+	pub min:						LogLevel,
+}
+
+impl LogRoute {
+	pub fn new(min: LogLevel, max: LogLevel, destination: LogDestination) -> Self {
+		LogRoute {
+			destination,
+			max,
+			min,
+		}
+	}
+
+	// Everything up to Info goes to system.log, Warn and above to error.log.
+	pub fn defaults() -> Vec<LogRoute> {
+		vec![
+			LogRoute::new(LogLevel::Trace, LogLevel::Info, LogDestination::System),
+			LogRoute::new(LogLevel::Warn, LogLevel::Fatal, LogDestination::Error),
+		]
+	}
+
+	pub fn matches(&self, level: LogLevel) -> bool {
+		self.min <= level && level <= self.max
+	}
+}
+
+impl TryFrom<String> for LogRoute {
+	type Error = String;
+
+	fn try_from(rule: String) -> Result<Self, String> {
+		let (levels, destination) = rule.split_once('=')
+			.ok_or_else(|| format!("invalid log route \"{}\", expected <levels>=<destination>, e.g. warn+=error", rule))?;
+		let level = |name: &str| LogLevel::from_name(name.trim()).ok_or_else(|| format!("unknown log level \"{}\" in log route \"{}\"", name.trim(), rule));
+
+		let levels = levels.trim();
+		let (min, max) = if let Some(min) = levels.strip_suffix('+') {
+			(level(min)?, LogLevel::Fatal)
+		} else if let Some((min, max)) = levels.split_once('-') {
+			(level(min)?, level(max)?)
+		} else {
+			(level(levels)?, level(levels)?)
+		};
+		if min > max {
+			return Err(format!("log route \"{}\" has its levels the wrong way round", rule));
+		}
+
+		let destination = LogDestination::from_name(destination).ok_or_else(|| format!("log route \"{}\" has no destination", rule))?;
+		// Route files are served to other drones by search, so they can't be anywhere but log_dir.
+		if let LogDestination::File(file) = &destination {
+			let mut components = file.components();
+			if !matches!((components.next(), components.next()), (Some(Component::Normal(_)), None)) {
+				return Err(format!("log route \"{}\" needs a plain file name inside log_dir, not a path", rule));
+			}
+		}
+
+		Ok(LogRoute::new(min, max, destination))
+	}
+}
+
+impl From<LogRoute> for String {
+	fn from(route: LogRoute) -> Self {
+		let min = route.min.as_str().to_lowercase();
+		let levels = if route.min == route.max {
+			min
+		} else if route.max == LogLevel::Fatal {
+			format!("{}+", min)
+		} else {
+			format!("{}-{}", min, route.max.as_str().to_lowercase())
+		};
+
+		format!("{}={}", levels, route.destination.name())
+	}
+}
+
+// What happens to a message when the log queue is full.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogOverflow {
+	// Wait until the log process has caught up.
+	Block,
+	// Drop the message, the log process reports how many were dropped.
+	Drop,
+}
+
+// How often log files are rotated, regardless of their size.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+	Never,
+	Hourly,
+	Daily,
+	Weekly,
+}
+
+impl LogRotation {
+	// Files are rotated when the period of the time they were started in is over.
+	fn period(&self, time: DateTime<Local>) -> String {
+		match self {
+			LogRotation::Never => String::new(),
+			LogRotation::Hourly => time.format("%Y-%m-%d %H").to_string(),
+			LogRotation::Daily => time.format("%Y-%m-%d").to_string(),
+			LogRotation::Weekly => time.format("%G-W%V").to_string(),
+		}
+	}
+}
+
+// Overrides log_level for one module and everything below it, written "<module>=<level>" in the
+// config, e.g. swarm::db=debug. The longest matching module wins.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct LogTarget {
+	pub level:						LogLevel,
+	pub target:						String,
+}
+
+impl LogTarget {
+	pub fn matches(&self, target: &str) -> bool {
+		target == self.target || (target.starts_with(&self.target) && target[self.target.len()..].starts_with("::"))
+	}
+}
+
+impl TryFrom<String> for LogTarget {
+	type Error = String;
+
+	fn try_from(rule: String) -> Result<Self, String> {
+		let (target, level) = rule.split_once('=')
+			.ok_or_else(|| format!("invalid log target \"{}\", expected <module>=<level>, e.g. swarm::db=debug", rule))?;
+		let level = LogLevel::from_name(level.trim()).ok_or_else(|| format!("unknown log level \"{}\" in log target \"{}\"", level.trim(), rule))?;
+
+		if target.trim().is_empty() {
+			return Err(format!("log target \"{}\" has no module", rule));
+		}
+
+		Ok(LogTarget {
+			level,
+			target: target.trim().to_string(),
+		})
+	}
+}
+
+impl From<LogTarget> for String {
+	fn from(target: LogTarget) -> Self {
+		format!("{}={}", target.target, target.level.as_str().to_lowercase())
+	}
+}
+
+// An open log file, with what rotation needs to know about it.
+struct LogFile {
+	file:							File,
+	// The rotation period the file was started in.
+	period:							String,
+	size:							u64,
+}
 
 pub struct Log {
-	pub error_log:					String,
+	compress:						bool,
+	counters:						Arc<LogCounters>,
+	drone_id:						Uuid,
+	pub error_log:					PathBuf,
+	// Destinations that failed, written to stderr until they work again.
+	failing:						BTreeSet<String>,
+	// Open log files by path, opened on first use.
+	files:							BTreeMap<PathBuf, LogFile>,
+	formats:						Vec<LogFormatRule>,
+	level:							LogLevel,
+	log_dir:						PathBuf,
+	max_files:						usize,
+	// In bytes, 0 for no limit.
+	max_size:						u64,
 	pub online:						bool,
-	pub system_log:					String,
+	rotation:						LogRotation,
+	routes:							Vec<LogRoute>,
+	pub system_log:					PathBuf,
+	targets:						Vec<LogTarget>,
 }
 
 impl Log {
-	
-	fn format_msg(message: String) -> String {
+
+	fn format_msg(msg: &LogMessage) -> String {
 		let now = Local::now();
 		// YEAR-MM-DD HH-mm-ss
 		let timestamp = now.format("[%Y-%m-%d %H:%M:%S]");
 
-		if message.ends_with('\n') {
-			return format!("{} - {}", timestamp, message);
+		let mut context = Vec::new();
+		if let Some(job_id) = msg.job_id {
+			context.push(format!("job_id={}", job_id));
+		}
+		if let Some(peer_id) = msg.peer_id {
+			context.push(format!("peer_id={}", peer_id));
+		}
+		if let Some(message_type) = msg.peer_message_type {
+			context.push(format!("message_type={:?}", message_type));
+		}
+		for (key, value) in msg.fields.iter() {
+			context.push(format!("{}={}", key, value));
 		}
 
-		return format!("{} - {}\n", timestamp, message);
+		let message = msg.message.trim_end_matches('\n');
+		if context.is_empty() {
+			return format!("{} {:<5} {} - {}\n", timestamp, msg.level.as_str(), msg.target, message);
+		}
+
+		format!("{} {:<5} {} - {} [{}]\n", timestamp, msg.level.as_str(), msg.target, message, context.join(" "))
 	}
-	
-	pub fn init(id: Uuid, log_dir: String, error_log_file: String, system_log_file: String) -> Self {
-		let startup_msg = Log::format_msg(format!("Starting swarm drone v.{}. id = {}", VERSION, id));
 
-		match fs::create_dir_all(&log_dir) {
-			Err(err) => {
-				println!("failed to create log dir because {:?}", err);
-			},
-			_ => {},
-		}
+	fn format_json(&self, msg: &LogMessage) -> String {
+		let record = LogRecord {
+			timestamp: Local::now().to_rfc3339_opts(SecondsFormat::Millis, false),
+			level: msg.level.as_str(),
+			target: &msg.target,
+			drone_id: self.drone_id,
+			job_id: msg.job_id,
+			peer_id: msg.peer_id,
+			message_type: msg.peer_message_type,
+			message: msg.message.trim_end_matches('\n'),
+			fields: &msg.fields,
+		};
 
-		let mut error_log: String = log_dir.to_owned();
-		error_log.push('/');
-		error_log.push_str(&error_log_file);
+		format!("{}\n", serde_json::to_string(&record).unwrap())
+	}
+
+	fn open(path: &Path, rotation: LogRotation) -> io::Result<LogFile> {
+		if let Some(log_dir) = path.parent() {
+			fs::create_dir_all(log_dir)?;
+		}
 
-		let mut system_log: String = log_dir.to_owned();
-		system_log.push('/');
-		system_log.push_str(&system_log_file);
+		let file = OpenOptions::new().create(true).append(true).open(path)?;
 
-		let mut errlog = OpenOptions::new().create(true).append(true).open(&error_log).unwrap();
-		errlog.write(startup_msg.as_bytes()).expect("Failed to write startup message to error_log.");
+		// A file left over from an earlier run belongs to the period it was last written in.
+		let metadata = file.metadata().ok();
+		let size = metadata.as_ref().map_or(0, |metadata| metadata.len());
+		let started = match metadata.and_then(|metadata| metadata.modified().ok()) {
+			Some(modified) if size > 0 => DateTime::<Local>::from(modified),
+			_ => Local::now(),
+		};
 
-		let mut syslog = OpenOptions::new().create(true).append(true).open(&system_log).unwrap();
-		syslog.write(startup_msg.as_bytes()).expect("Failed to write startup message to system_log.");
+		Ok(LogFile {
+			file,
+			period: rotation.period(started),
+			size,
+		})
+	}
 
-		Log {
-			error_log,
+	fn new(config: &Config) -> Self {
+		let mut log = Log {
+			compress: false,
+			counters: Arc::new(LogCounters::default()),
+			drone_id: config.id,
+			error_log: PathBuf::new(),
+			failing: BTreeSet::new(),
+			files: BTreeMap::new(),
+			formats: Vec::new(),
+			level: config.log_level,
+			log_dir: PathBuf::new(),
+			max_files: 0,
+			max_size: 0,
 			online: false,
-			system_log,
+			rotation: LogRotation::Never,
+			routes: Vec::new(),
+			system_log: PathBuf::new(),
+			targets: Vec::new(),
+		};
+		log.configure(config);
+
+		log
+	}
+
+	pub fn init(config: &Config) -> Self {
+		let mut log = Log::new(config);
+
+		let startup_msg = LogMessage::new(LogLevel::Info, module_path!(), format!("Starting swarm drone v.{}. id = {}", VERSION, config.id));
+		for destination in [LogDestination::Error, LogDestination::System].iter() {
+			log.emit(destination, &startup_msg);
 		}
+
+		log
 	}
 
-	pub fn run(&mut self, rx: Receiver<LogMessage>) {
+	// Take the log paths, levels, routes, formats and rotation settings from a (re)loaded config. Open
+	// files are closed, so that they are reopened on the next write; triggered by SIGHUP so that external
+	// tools (logrotate, etc) can move the files out from under us.
+	fn configure(&mut self, config: &Config) {
+		self.compress = config.log_compress;
+		self.drone_id = config.id;
+		self.error_log = config.log_dir.join(&config.error_log);
+		self.files.clear();
+		self.formats = config.log_formats.clone();
+		self.level = config.log_level;
+		self.log_dir = config.log_dir.clone();
+		self.max_files = config.log_max_files;
+		self.max_size = config.log_max_size * 1024 * 1024;
+		self.rotation = config.log_rotate;
+		self.routes = config.log_routes.clone();
+		self.system_log = config.log_dir.join(&config.system_log);
+		self.targets = config.log_targets.clone();
+	}
+
+	// Whether a message is at or above the minimum level for its target.
+	fn enabled(&self, level: LogLevel, target: &str) -> bool {
+		let min = self.targets.iter()
+			.filter(|rule| rule.matches(target))
+			.max_by_key(|rule| rule.target.len())
+			.map_or(self.level, |rule| rule.level);
+
+		level >= min
+	}
+
+	// Write queued messages until told to go offline, or every sender is gone.
+	pub fn run(&mut self, rx: LogReceiver) {
+		self.counters = rx.counters.clone();
 		self.online = true;
 
+		let mut reported = 0;
 		while self.online {
-			let msg = rx.recv().unwrap();
+			let msg = match rx.rx.recv() {
+				Ok(msg) => msg,
+				Err(_) => break,
+			};
+
+			let dropped = self.counters.dropped.load(Ordering::Relaxed);
+			if dropped > reported {
+				let report = format!("Dropped {} log messages because the log queue was full, {} since start.", dropped - reported, dropped);
+				self.write(&LogMessage::new(LogLevel::Warn, module_path!(), report));
+				reported = dropped;
+			}
 
 			match msg.message_type {
 				MessageType::Message => {
-					self.write(msg.log_type, msg.message);
+					self.write(&msg);
 				},
 				MessageType::Offline => {
 					// stop log process
+					self.online = false;
+				},
+				MessageType::Reload => {
+					// Without a config (SIGUSR1, e.g. from logrotate) the files are only reopened.
+					match msg.config {
+						Some(c) => self.configure(&c),
+						None => self.files.clear(),
+					}
+					self.write(&LogMessage::new(LogLevel::Info, module_path!(), "Log files reopened.".to_string()));
 				},
 				_ => {},
 			}
 		}
+
+		self.online = false;
+	}
+
+	fn write(&mut self, msg: &LogMessage) {
+		if !self.enabled(msg.level, &msg.target) {
+			return;
+		}
+
+		let destinations: Vec<LogDestination> = self.routes.iter()
+			.filter(|route| route.matches(msg.level))
+			.map(|route| route.destination.clone())
+			.collect();
+
+		for destination in destinations.iter() {
+			self.emit(destination, msg);
+		}
 	}
 
-	fn write(&self, log_type: LogType, message: String) {
-		let msg = Log::format_msg(message);
+	// Write a message to one destination, in the format configured for it (the last matching rule).
+	fn emit(&mut self, destination: &LogDestination, msg: &LogMessage) {
+		let format = self.formats.iter().rev()
+			.find(|rule| rule.destination == *destination)
+			.map_or(LogFormat::Text, |rule| rule.format);
+		let line = match format {
+			LogFormat::Json => self.format_json(msg),
+			LogFormat::Text => Log::format_msg(msg),
+		};
 
-		match log_type {
-			LogType::ErrorLog => {
-				let mut errlog = OpenOptions::new().create(true).append(true).open(&self.error_log).unwrap();
-				errlog.write(msg.as_bytes()).expect("Failed to write message to error_log.");
+		let result = match destination {
+			LogDestination::Error => self.append(&self.error_log.clone(), &line),
+			LogDestination::File(file) => self.append(&self.log_dir.join(file), &line),
+			LogDestination::Stderr => io::stderr().write_all(line.as_bytes()),
+			LogDestination::Stdout => io::stdout().write_all(line.as_bytes()),
+			LogDestination::System => self.append(&self.system_log.clone(), &line),
+		};
+
+		match result {
+			Ok(()) => {
+				if self.failing.remove(&destination.name()) {
+					eprintln!("swarm: log {} is writable again.", destination.name());
+				}
 			},
-			LogType::SystemLog => {
-				let mut syslog = OpenOptions::new().create(true).append(true).open(&self.system_log).unwrap();
-				syslog.write(msg.as_bytes()).expect("Failed to write message to system_log");
+			Err(err) => {
+				// Written to stderr and counted, the first failure of a destination says why.
+				self.counters.fallback.fetch_add(1, Ordering::Relaxed);
+				if self.failing.insert(destination.name()) {
+					eprintln!("swarm: failed to write to log {}, using stderr until it works again: {}", destination.name(), err);
+				}
+				if *destination != LogDestination::Stderr {
+					let _ = io::stderr().write_all(line.as_bytes());
+				}
 			},
 		}
+	}
 
+	// Append a line to a log file. A failed write is retried once on a freshly opened file, in case the
+	// file was removed or its handle went bad.
+	fn append(&mut self, path: &Path, msg: &str) -> io::Result<()> {
+		self.append_once(path, msg).or_else(|_| {
+			self.files.remove(path);
+			self.append_once(path, msg)
+		})
 	}
 
+	fn append_once(&mut self, path: &Path, msg: &str) -> io::Result<()> {
+		let max_size = self.max_size;
+		let period = self.rotation.period(Local::now());
+
+		let log_file = self.file(path)?;
+		let full = max_size > 0 && log_file.size > 0 && log_file.size + msg.len() as u64 > max_size;
+		if full || log_file.period != period {
+			self.files.remove(path);
+			if let Err(err) = self.rotate(path) {
+				eprintln!("failed to rotate log file {}: {}", path.display(), err);
+			}
+		}
+
+		let log_file = self.file(path)?;
+		log_file.file.write_all(msg.as_bytes())?;
+		log_file.size += msg.len() as u64;
+
+		Ok(())
+	}
+
+	// The open log file at path, opened on first use.
+	fn file(&mut self, path: &Path) -> io::Result<&mut LogFile> {
+		match self.files.entry(path.to_path_buf()) {
+			Entry::Occupied(entry) => Ok(entry.into_mut()),
+			Entry::Vacant(entry) => Ok(entry.insert(Log::open(path, self.rotation)?)),
+		}
+	}
+
+	// Move a log file aside as <file>.<timestamp>, gzip it if configured and remove the oldest rotated
+	// copies beyond log_max_files.
+	fn rotate(&self, path: &Path) -> io::Result<()> {
+		let name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+		let stamp = Local::now().format("%Y%m%dT%H%M%S").to_string();
+
+		// Several rotations within a second are numbered.
+		let mut rotated = format!("{}.{}", name, stamp);
+		let mut n = 1;
+		while path.with_file_name(&rotated).exists() || path.with_file_name(format!("{}.gz", rotated)).exists() {
+			rotated = format!("{}.{}-{}", name, stamp, n);
+			n += 1;
+		}
+		let rotated = path.with_file_name(rotated);
+		fs::rename(path, &rotated)?;
+
+		if self.compress {
+			let mut gz_path = rotated.clone().into_os_string();
+			gz_path.push(".gz");
+
+			let mut encoder = GzEncoder::new(File::create(&gz_path)?, Compression::default());
+			io::copy(&mut File::open(&rotated)?, &mut encoder)?;
+			encoder.finish()?;
+			fs::remove_file(&rotated)?;
+		}
+
+		if self.max_files > 0 {
+			let copies = Log::rotated_files(path)?;
+			let excess = copies.len().saturating_sub(self.max_files);
+			for copy in copies.iter().take(excess) {
+				fs::remove_file(copy)?;
+			}
+		}
+
+		Ok(())
+	}
+
+	// Rotated copies of a log file, named <file>.<YYYYmmddTHHMMSS>[-n][.gz], oldest first.
+	fn rotated_files(path: &Path) -> io::Result<Vec<PathBuf>> {
+		let name = path.file_name().map(|name| format!("{}.", name.to_string_lossy())).unwrap_or_default();
+		let dir = path.parent().unwrap_or_else(|| Path::new("."));
+
+		let mut copies = Vec::new();
+		for entry in fs::read_dir(dir)? {
+			let entry = entry?;
+			let file_name = entry.file_name().to_string_lossy().to_string();
+
+			let suffix = match file_name.strip_prefix(&name) {
+				Some(suffix) => suffix.trim_end_matches(".gz"),
+				None => continue,
+			};
+			let (stamp, n) = match suffix.split_once('-') {
+				Some((stamp, n)) => (stamp, n.parse::<u32>().ok()),
+				None => (suffix, Some(0)),
+			};
+
+			let bytes = stamp.as_bytes();
+			if let (15, Some(n)) = (bytes.len(), n) {
+				if bytes[8] == b'T' && bytes[..8].iter().chain(bytes[9..].iter()).all(u8::is_ascii_digit) {
+					copies.push(((stamp.to_string(), n), entry.path()));
+				}
+			}
+		}
+		copies.sort();
+
+		Ok(copies.into_iter().map(|(_, path)| path).collect())
+	}
+
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn log_route_test() {
+		let route = LogRoute::try_from(String::from("warn+=error")).unwrap();
+		assert_eq!(route, LogRoute::new(LogLevel::Warn, LogLevel::Fatal, LogDestination::Error));
+		assert!(route.matches(LogLevel::Fatal) && !route.matches(LogLevel::Info));
+
+		let route = LogRoute::try_from(String::from("debug-info=debug.log")).unwrap();
+		assert_eq!(route.destination, LogDestination::File(PathBuf::from("debug.log")));
+		assert!(route.matches(LogLevel::Debug) && route.matches(LogLevel::Info) && !route.matches(LogLevel::Warn));
+
+		// Routes are written back the way they are read.
+		for rule in ["info=stdout", "trace-info=system", "warn+=error", "debug-info=debug.log"].iter() {
+			assert_eq!(String::from(LogRoute::try_from(rule.to_string()).unwrap()), *rule);
+		}
+
+		assert!(LogRoute::try_from(String::from("error-info=error")).is_err());
+		assert!(LogRoute::try_from(String::from("loud+=error")).is_err());
+		assert!(LogRoute::try_from(String::from("warn+")).is_err());
+		assert!(LogRoute::try_from(String::from("debug=/etc/passwd")).is_err());
+		assert!(LogRoute::try_from(String::from("debug=../debug.log")).is_err());
+		assert!(LogRoute::try_from(String::from("debug=jobs/debug.log")).is_err());
+	}
+
+	#[test]
+	fn log_target_test() {
+		let config = Config {
+			log_dir: std::env::temp_dir(),
+			log_level: LogLevel::Warn,
+			log_targets: vec![
+				LogTarget::try_from(String::from("swarm::db=debug")).unwrap(),
+				LogTarget::try_from(String::from("swarm::db::sqlite=error")).unwrap(),
+			],
+			..Default::default()
+		};
+		let log = Log::new(&config);
+
+		assert!(!log.enabled(LogLevel::Info, "swarm::drone"));
+		assert!(log.enabled(LogLevel::Warn, "swarm::drone"));
+		assert!(log.enabled(LogLevel::Debug, "swarm::db"));
+		assert!(log.enabled(LogLevel::Debug, "swarm::db::memory"));
+		assert!(!log.enabled(LogLevel::Warn, "swarm::db::sqlite"));
+		// Module boundaries, not string prefixes.
+		assert!(!log.enabled(LogLevel::Debug, "swarm::dbx"));
+	}
+
+	#[test]
+	fn log_rotation_test() {
+		let dir = std::env::temp_dir().join(format!("swarm_log_test_{}", uuid::Uuid::new_v4()));
+		let config = Config {
+			log_compress: true,
+			log_dir: dir.clone(),
+			log_max_files: 2,
+			log_rotate: LogRotation::Never,
+			..Default::default()
+		};
+		let mut log = Log::new(&config);
+		// A few lines per file.
+		log.max_size = 200;
+
+		for i in 0..20 {
+			log.write(&LogMessage::new(LogLevel::Info, "swarm::test", format!("message number {}", i)));
+		}
+
+		// Only the newest rotated copies are kept, all compressed.
+		let copies = Log::rotated_files(&log.system_log).unwrap();
+		assert_eq!(copies.len(), 2);
+		assert!(copies.iter().all(|copy| copy.extension().is_some_and(|extension| extension == "gz")));
+		assert!(fs::metadata(&log.system_log).unwrap().len() <= 200);
+
+		let mut content = String::new();
+		io::Read::read_to_string(&mut flate2::read::GzDecoder::new(File::open(&copies[0]).unwrap()), &mut content).unwrap();
+		assert!(content.contains("message number"));
+
+		// A new period starts a new file.
+		log.rotation = LogRotation::Daily;
+		log.files.get_mut(&log.system_log).unwrap().period = String::from("2000-01-01");
+		log.write(&LogMessage::new(LogLevel::Info, "swarm::test", "next day".to_string()));
+		assert_eq!(fs::read_to_string(&log.system_log).unwrap().lines().count(), 1);
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn log_json_test() {
+		let dir = std::env::temp_dir().join(format!("swarm_log_test_{}", Uuid::new_v4()));
+		let config = Config {
+			id: Uuid::new_v4(),
+			log_dir: dir.clone(),
+			log_formats: vec![LogFormatRule::try_from(String::from("system=json")).unwrap()],
+			..Default::default()
+		};
+		let mut log = Log::new(&config);
+
+		let job_id = Uuid::new_v4();
+		log.write(&LogMessage::new(LogLevel::Info, "swarm::test", "queued".to_string()).job(job_id).peer_message(MessageType::QueueJob).field("tag", "gpu"));
+		log.write(&LogMessage::new(LogLevel::Error, "swarm::test", "failed".to_string()).job(job_id));
+
+		let line: serde_json::Value = serde_json::from_str(fs::read_to_string(&log.system_log).unwrap().trim()).unwrap();
+		assert_eq!(line["level"], "INFO");
+		assert_eq!(line["drone_id"], config.id.to_string());
+		assert_eq!(line["job_id"], job_id.to_string());
+		assert_eq!(line["message_type"], "QueueJob");
+		assert_eq!(line["message"], "queued");
+		assert_eq!(line["fields"]["tag"], "gpu");
+		assert!(line.get("peer_id").is_none());
+
+		// Other destinations stay text.
+		let error_log = fs::read_to_string(&log.error_log).unwrap();
+		assert!(error_log.contains(&format!("ERROR swarm::test - failed [job_id={}]", job_id)));
+
+		assert!(LogFormatRule::try_from(String::from("system=xml")).is_err());
+		assert_eq!(String::from(LogFormatRule::try_from(String::from("debug.log=json")).unwrap()), "debug.log=json");
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn log_facade_test() {
+		let (tx, rx) = channel(&Config::default());
+		let logger = Logger {
+			tx,
+		};
+
+		let job_id = Uuid::new_v4();
+		let job = job_id.to_string();
+		let kvs = [("job_id", job.as_str()), ("message_type", "QueueJob"), ("tag", "gpu")];
+		::log::Log::log(&logger, &Record::builder()
+			.args(format_args!("queued {}", 1))
+			.key_values(&kvs)
+			.level(::log::Level::Warn)
+			.target("swarm::drone")
+			.build());
+
+		let msg = rx.rx.try_recv().unwrap();
+		assert_eq!(msg.level, LogLevel::Warn);
+		assert_eq!(msg.target, "swarm::drone");
+		assert_eq!(msg.message, "queued 1");
+		assert_eq!(msg.job_id, Some(job_id));
+		assert_eq!(msg.peer_message_type, Some(MessageType::QueueJob));
+		assert_eq!(msg.fields.get("tag").map(String::as_str), Some("gpu"));
+
+		let config = Config {
+			log_level: LogLevel::Warn,
+			log_targets: vec![LogTarget::try_from(String::from("swarm::db=debug")).unwrap()],
+			..Default::default()
+		};
+		assert_eq!(max_level(&config), LevelFilter::Debug);
+	}
+
+	#[test]
+	fn log_resilience_test() {
+		let dir = std::env::temp_dir().join(format!("swarm_log_test_{}", Uuid::new_v4()));
+		let config = Config {
+			log_dir: dir.clone(),
+			log_queue_size: 1,
+			..Default::default()
+		};
+
+		// A full queue drops (and counts) messages instead of blocking, control messages wait.
+		let (tx, rx) = channel(&config);
+		tx.send(LogMessage::new(LogLevel::Info, "swarm::drone", String::from("first")));
+		tx.send(LogMessage::new(LogLevel::Info, "swarm::drone", String::from("second")));
+		assert_eq!(rx.counters.dropped.load(Ordering::Relaxed), 1);
+
+		// The log process reports the drops and stops once every sender is gone.
+		drop(tx);
+		let mut log = Log::new(&config);
+		log.run(rx);
+		assert!(!log.online);
+
+		let system_log = fs::read_to_string(dir.join("system.log")).unwrap();
+		assert!(system_log.contains("first"));
+		assert!(!system_log.contains("second"));
+		let error_log = fs::read_to_string(dir.join("error.log")).unwrap();
+		assert!(error_log.contains("Dropped 1 log messages because the log queue was full, 1 since start."));
+
+		// A log file that can't be written falls back to stderr.
+		let config = Config {
+			log_dir: dir.join("system.log"),
+			..Default::default()
+		};
+		let mut log = Log::new(&config);
+		log.write(&LogMessage::new(LogLevel::Error, "swarm::drone", String::from("disk full")));
+		assert_eq!(log.counters.fallback.load(Ordering::Relaxed), 1);
+		assert!(log.failing.contains("error"));
+
+		// Without a log process messages go to stderr.
+		let (tx, rx) = channel(&config);
+		drop(rx);
+		tx.send(LogMessage::new(LogLevel::Info, "swarm::drone", String::from("orphaned")));
+		assert_eq!(tx.counters.fallback.load(Ordering::Relaxed), 1);
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn log_search_test() {
+		let dir = std::env::temp_dir().join(format!("swarm_log_test_{}", Uuid::new_v4()));
+		let config = Config {
+			id: Uuid::new_v4(),
+			log_dir: dir.clone(),
+			log_formats: vec![LogFormatRule::try_from(String::from("debug.log=json")).unwrap()],
+			log_routes: vec![
+				LogRoute::try_from(String::from("info+=system")).unwrap(),
+				LogRoute::try_from(String::from("warn+=error")).unwrap(),
+				LogRoute::try_from(String::from("info+=debug.log")).unwrap(),
+			],
+			..Default::default()
+		};
+		let mut log = Log::new(&config);
+
+		// Older lines in a rotated (compressed) copy, a multi-line message and a message that is in every file.
+		log.write(&LogMessage::new(LogLevel::Info, "swarm::drone", String::from("job A queued")));
+		log.files.clear();
+		log.rotate(&dir.join("system.log")).unwrap();
+		log.write(&LogMessage::new(LogLevel::Info, "swarm::drone", String::from("job B queued\nwith details")));
+		log.write(&LogMessage::new(LogLevel::Warn, "swarm::drone", String::from("job B failed")));
+
+		let query = LogQuery {
+			grep: Some(String::from("job|details")),
+			..Default::default()
+		};
+		let lines: Vec<String> = search(&config, &query).unwrap().into_iter().map(|line| line.line).collect();
+		assert_eq!(lines.iter().filter(|line| line.ends_with("job A queued")).count(), 1);
+		assert_eq!(lines.iter().filter(|line| *line == "with details").count(), 1);
+		assert_eq!(lines.iter().filter(|line| line.ends_with("job B failed")).count(), 1);
+		// The JSON lines of debug.log are found as they are.
+		assert_eq!(lines.iter().filter(|line| line.starts_with('{')).count(), 3);
+
+		let query = LogQuery {
+			grep: Some(String::from("failed")),
+			limit: 1,
+			..Default::default()
+		};
+		let lines = search(&config, &query).unwrap();
+		assert_eq!(lines.len(), 1);
+		assert_eq!(lines[0].drone, config.id);
+
+		let query = LogQuery {
+			since: Some((Utc::now() + chrono::Duration::minutes(1)).format("%Y-%m-%d %H:%M:%S").to_string()),
+			..Default::default()
+		};
+		assert!(search(&config, &query).unwrap().is_empty());
+
+		assert!(search(&config, &LogQuery { grep: Some(String::from("(")), ..Default::default() }).is_err());
+
+		// However long the lines, the answer fits in one message, with the newest lines in it.
+		for i in 0..100 {
+			log.write(&LogMessage::new(LogLevel::Info, "swarm::drone", format!("big {} {}", i, "x".repeat(20_000))));
+		}
+		let lines = search(&config, &LogQuery { grep: Some(String::from("big")), limit: usize::MAX, ..Default::default() }).unwrap();
+		assert!(serde_json::to_vec(&lines).unwrap().len() <= MAX_SEARCH_SIZE);
+		assert!(lines.last().unwrap().line.contains("big 99 "));
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
 }
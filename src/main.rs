@@ -1,97 +1,353 @@
-use bincode;
 use clap::{App, Arg};
+use ::log::{debug, error, info, warn};
 use procfs::process::Process;
-use std::io::{BufRead, BufReader, Read};
-use std::net::{TcpListener};
-use std::os::unix::net::{UnixStream, UnixListener};
-use std::path::Path;
+use signal_hook::consts::signal::{SIGHUP, SIGINT, SIGTERM, SIGUSR1};
+use signal_hook::iterator::Signals;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::sync::mpsc;
 use std::thread;
+use std::time::{Duration, Instant};
 
+use swarm::auth::{Session, TAG_SIZE};
+use swarm::config;
+use swarm::config::Config;
+use swarm::control;
+use swarm::control::{Access, ControlAccess, PeerCred};
 use swarm::db;
 use swarm::drone;
 use swarm::log;
 use swarm::models::*;
+use swarm::tls::{PeerStream, Tls};
 
-fn process_command(stream: UnixStream, tx: mpsc::Sender<DroneCtl>) {
-	let stream = BufReader::new(stream);
-	for line in stream.lines() {
-		println!("received command...");
+// Clean shutdown, shared by the dronectl SHUTDOWN command and SIGTERM/SIGINT.
+fn shutdown(tx: &mpsc::Sender<DroneCtl>, socket_path: &Path) {
+	let me = Process::myself().unwrap();
+	info!("Shutting down swarm drone (pid = {}).", me.pid);
 
-		match &line.unwrap()[..] {
-			"SHUTDOWN" => {
-				//shutdown signal
-				let me = Process::myself().unwrap();
-				println!("Shutting down swarm drone (pid = {}).", me.pid);
+	// clear pid file
+	if socket_path.exists() {
+		std::fs::remove_file(socket_path).unwrap();
+	}
 
-				// clear pid file
-				let socket_path = format!("/tmp/swarm_drone_{}.sock", me.pid);
-				let pid_path = Path::new(&socket_path);
-				let _ = std::fs::remove_file(&pid_path).unwrap();
+	tx.send(DroneCtl::new(DroneCtlType::Stop, None, None, None)).unwrap();
+}
+
+// Pass a dronectl command that expects an answer to the drone process, and write the (JSON) reply
+// back to dronectl as a single line.
+fn request(tx: &mpsc::Sender<DroneCtl>, mut stream: &UnixStream, dronectl_type: DroneCtlType, args: &str) {
+	let (reply_tx, reply_rx) = mpsc::channel::<CtlReply>();
+	tx.send(DroneCtl::with_reply(dronectl_type, Some(args.to_string()), reply_tx)).unwrap();
 
-				tx.send(DroneCtl::new(DroneCtlType::Stop, None, None, None)).unwrap();
+	let reply = reply_rx.recv().unwrap_or_else(|_| CtlReply::Error("drone process did not answer".to_string()));
+	if let Err(err) = writeln!(stream, "{}", serde_json::to_string(&reply).unwrap()) {
+		warn!("Failed to answer dronectl: {}", err);
+	}
+}
 
-				println!("Done");
+fn process_command(stream: UnixStream, access: Access, peer: PeerCred, socket_path: PathBuf, tx: mpsc::Sender<DroneCtl>) {
+	let reader = match stream.try_clone() {
+		Ok(reader) => BufReader::new(reader),
+		Err(err) => {
+			warn!("Failed to read dronectl commands from uid = {}, pid = {}: {}", peer.uid, peer.pid, err);
+			return;
+		},
+	};
+	for line in reader.lines() {
+		// A line that can't be read (e.g. isn't UTF-8) ends the connection, the client gets told why.
+		let line = match line {
+			Ok(line) => line,
+			Err(err) => {
+				warn!("Failed to read dronectl command from uid = {}, pid = {}: {}", peer.uid, peer.pid, err);
+				let reply = CtlReply::Error(format!("invalid command: {}", err));
+				let _ = writeln!(&stream, "{}", serde_json::to_string(&reply).unwrap());
+				return;
+			},
+		};
+		let (command, args) = match line.find(' ') {
+			Some(i) => (&line[..i], &line[i + 1..]),
+			None => (&line[..], ""),
+		};
+		debug!("Received dronectl command {}.", command);
+
+		if !access.allows(command) {
+			error!("Denied dronectl command {} to uid = {}, pid = {}, it may only use read-only commands.", command, peer.uid, peer.pid);
+			let reply = CtlReply::Error(format!("permission denied, {} needs control_users or control_groups", command));
+			let _ = writeln!(&stream, "{}", serde_json::to_string(&reply).unwrap());
+			continue;
+		}
+
+		match command {
+			"ARCHIVE_EXPORT" => {
+				request(&tx, &stream, DroneCtlType::ArchiveExport, args);
+			},
+			"JOB_LOG" => {
+				request(&tx, &stream, DroneCtlType::JobLog, args);
+			},
+			"SHUTDOWN" => {
+				//shutdown signal
+				shutdown(&tx, &socket_path);
 				thread::sleep(std::time::Duration::from_secs(2));
 			},
+			"RELOAD" => {
+				tx.send(DroneCtl::new(DroneCtlType::Reload, None, None, None)).unwrap();
+			},
 			"RESTART" => {},
+			"SEARCH" => {
+				request(&tx, &stream, DroneCtlType::Search, args);
+			},
+			"SNAPSHOT" => {
+				request(&tx, &stream, DroneCtlType::Snapshot, args);
+			},
+			"SWARM_LOGS" => {
+				request(&tx, &stream, DroneCtlType::Logs, args);
+			},
 			_ => {
-				// unrecognised command
-				// log and ignore
+				// unrecognised command, log and ignore
+				warn!("Ignored unknown dronectl command {}.", command);
 			}
 		}
 	}
 }
 
-fn process_message(address: String, port: u32, tx: mpsc::Sender<DroneCtl>) {
-	println!("starting external listener on port {}", port);
+// Messages are read until the sending drone closes the connection, up to this size.
+const MAX_MESSAGE_SIZE: u64 = 1024 * 1024;
+
+// How long a question from another drone waits for the drone process to answer it.
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long a drone that connected gets for the handshakes and its whole message. A read timeout only
+// bounds the wait for each read, a drone trickling its data would keep the connection much longer.
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(15);
+
+// How long sending an answer back to another drone may block.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Connections from other drones handled at once, each by a thread of the listener's pool. As many more
+// wait for a free thread, past that new connections are closed right away.
+const MAX_CONNECTIONS: usize = 16;
+
+// How long the listener pauses after failing to accept a connection, e.g. when out of file descriptors.
+const ACCEPT_RETRY: Duration = Duration::from_millis(100);
+
+// Pass a question from another drone on to the drone process, and send the (JSON) reply back on the
+// same connection as a message of the same type.
+fn answer(tx: &mpsc::Sender<DroneCtl>, stream: &mut PeerStream, session: &Session, message_type: MessageType, dronectl_type: DroneCtlType, args: &str) {
+	let (reply_tx, reply_rx) = mpsc::channel::<CtlReply>();
+	tx.send(DroneCtl::with_reply(dronectl_type, Some(args.to_string()), reply_tx)).unwrap();
+
+	let reply = reply_rx.recv_timeout(ANSWER_TIMEOUT).unwrap_or_else(|_| CtlReply::Error("drone process did not answer".to_string()));
+	let payload = bincode::serialize(&Message::with_payload(message_type, &reply)).unwrap();
+	if let Err(err) = stream.write_all(&session.seal(&payload)).and_then(|_| stream.finish()) {
+		let peer = stream.tcp().peer_addr().map(|peer| peer.to_string()).unwrap_or_else(|_| String::from("unknown"));
+		warn!(peer_address:% = peer, message_type:? = message_type; "Failed to answer {}: {}", peer, err);
+	}
+}
+
+// Pass a message from another drone on to the drone process, returns what became of it for the message archive.
+fn dispatch(payload: &[u8], stream: &mut PeerStream, session: &Session, tx: &mpsc::Sender<DroneCtl>) -> MessageOutcome {
+	let msg: Message = match bincode::deserialize(payload) {
+		Ok(msg) => msg,
+		Err(_) => return MessageOutcome::Invalid,
+	};
+
+	// Over TLS a drone only speaks for itself: a host it reports on, or the reporter of job ownership, has
+	// to be the one its certificate names. The work loop gets the certificate's id too, see DroneCtl::sender.
+	let sender = stream.peer_id();
+	let host_message = matches!(msg.message_type, MessageType::FinishJob | MessageType::Online | MessageType::Offline | MessageType::StartJob | MessageType::Restored);
+	let claimed = match msg.message_type {
+		_ if host_message => msg.payload::<Host>().ok().map(|host| host.id),
+		MessageType::Ownership => msg.payload::<OwnershipReport>().ok().map(|report| report.reporter),
+		_ => None,
+	};
+	if let (Some(peer_id), Some(claimed)) = (sender, claimed) {
+		if claimed != peer_id {
+			error!(peer_id:% = peer_id, message_type:? = msg.message_type; "Rejected message about drone id = {} from drone id = {}, its certificate is not issued to that drone.", claimed, peer_id);
+			return MessageOutcome::Unauthenticated;
+		}
+	}
+
+	let ctl = match msg.message_type {
+		MessageType::FinishJob => {
+			// Notification from a drone that a job has been finished.
+			msg.payload::<Host>().map(|host| DroneCtl::new(DroneCtlType::FinishJob, Some(host), None, None))
+		},
+		MessageType::Message => {
+			Ok(DroneCtl::new(DroneCtlType::Message, None, None, Some(msg.message)))
+		},
+		MessageType::Online => {
+			// Notification that a drone has come online.
+			msg.payload::<Host>().map(|host| DroneCtl::new(DroneCtlType::Online, Some(host), None, None))
+		},
+		MessageType::Offline => {
+			// Notification that a drone has gone offline.
+			msg.payload::<Host>().map(|host| DroneCtl::new(DroneCtlType::Offline, Some(host), None, None))
+		},
+		MessageType::StartJob => {
+			// Notification from a drone that a job has been started.
+			msg.payload::<Host>().map(|host| DroneCtl::new(DroneCtlType::StartJob, Some(host), None, None))
+		},
+		MessageType::QueueJob => {
+			// Notification of a new job to be queued.
+			msg.payload::<Job>().map(|job| DroneCtl::new(DroneCtlType::QueueJob, None, Some(job), None))
+		},
+		MessageType::Restored => {
+			// A drone restored from a snapshot re-announcing itself.
+			msg.payload::<Host>().map(|host| DroneCtl::new(DroneCtlType::Restored, Some(host), None, None))
+		},
+		MessageType::JobLog => {
+			// A drone asking for part of a job's output, answered on the same connection.
+			return match msg.payload::<JobLogQuery>() {
+				Ok(_) => {
+					answer(tx, stream, session, MessageType::JobLog, DroneCtlType::JobLog, &msg.message);
+					MessageOutcome::Accepted
+				},
+				Err(_) => MessageOutcome::Invalid,
+			};
+		},
+		MessageType::Logs => {
+			// A drone asking for matching log lines, answered on the same connection.
+			return match msg.payload::<LogQuery>() {
+				Ok(_) => {
+					answer(tx, stream, session, MessageType::Logs, DroneCtlType::Logs, &msg.message);
+					MessageOutcome::Accepted
+				},
+				Err(_) => MessageOutcome::Invalid,
+			};
+		},
+		MessageType::Ownership => {
+			// A peer's view of the jobs a drone owns.
+			msg.payload::<OwnershipReport>().map(|_| DroneCtl::new(DroneCtlType::Ownership, None, None, Some(msg.message.clone())))
+		},
+		_ => {
+			// Unknown message from another drone.
+			return MessageOutcome::Ignored;
+		},
+	};
+
+	match ctl {
+		Ok(ctl) => {
+			tx.send(ctl.sent_by(sender)).unwrap();
+			MessageOutcome::Accepted
+		},
+		Err(_) => MessageOutcome::Invalid,
+	}
+}
+
+// A message from another drone, and the connection it came on (to answer questions on).
+struct Received {
+	data:							Vec<u8>,
+	session:						Session,
+	stream:							PeerStream,
+}
+
+// Read a message from a drone that connected to the external listener. Only drones that know the swarm
+// secret (and with TLS, have a certificate from the swarm's CA) get as far as sending one, see
+// auth::Session and tls::Tls.
+fn receive(stream: TcpStream, tls: Option<&Tls>, secret: &[u8]) -> Result<Received, (io::Error, Vec<u8>)> {
+	let started = Instant::now();
+	stream.set_write_timeout(Some(WRITE_TIMEOUT)).map_err(|err| (err, Vec::new()))?;
+	let _deadline = deadline(&stream, RECEIVE_TIMEOUT).map_err(|err| (err, Vec::new()))?;
+
+	// A connection that doesn't get through the handshakes, whatever the reason, never authenticated.
+	let unauthenticated = |err: io::Error| match err.kind() {
+		io::ErrorKind::PermissionDenied => (err, Vec::new()),
+		_ => (io::Error::new(io::ErrorKind::PermissionDenied, format!("handshake failed: {}", err)), Vec::new()),
+	};
+	let mut stream = match tls {
+		Some(tls) => tls.accept(stream).map_err(unauthenticated)?,
+		None => PeerStream::Plain(stream),
+	};
+	let session = Session::server(&mut stream, secret).map_err(unauthenticated)?;
+
+	let mut sealed = Vec::new();
+	(&mut stream).take(MAX_MESSAGE_SIZE + TAG_SIZE as u64).read_to_end(&mut sealed).map_err(|err| (err, Vec::new()))?;
+	if started.elapsed() >= RECEIVE_TIMEOUT {
+		return Err((io::Error::new(io::ErrorKind::TimedOut, "the message took too long to arrive"), Vec::new()));
+	}
+
+	match session.open(&sealed) {
+		Ok(data) => {
+			let data = data.to_vec();
+			Ok(Received { data, session, stream })
+		},
+		Err(err) => Err((err, sealed.split_off(TAG_SIZE.min(sealed.len())))),
+	}
+}
+
+// Shut the connection down once timeout has passed, unless the returned sender is dropped first. Reads
+// blocked on it then return, however the connection is wrapped.
+fn deadline(stream: &TcpStream, timeout: Duration) -> io::Result<mpsc::Sender<()>> {
+	let watched = stream.try_clone()?;
+	let (done_tx, done_rx) = mpsc::channel::<()>();
+
+	thread::spawn(move || {
+		if let Err(mpsc::RecvTimeoutError::Timeout) = done_rx.recv_timeout(timeout) {
+			let _ = watched.shutdown(Shutdown::Both);
+		}
+	});
+
+	Ok(done_tx)
+}
+
+// Handle one connection from another drone, on a thread of the listener's pool so a slow one doesn't hold
+// up the others.
+fn process_connection(stream: TcpStream, tls: Option<Arc<Tls>>, secret: Arc<String>, tx: mpsc::Sender<DroneCtl>) {
+	let peer = stream.peer_addr().map(|peer| peer.to_string()).unwrap_or_else(|_| String::from("unknown"));
+
+	match receive(stream, tls.as_deref(), secret.as_bytes()) {
+		Ok(Received { data, session, mut stream }) => {
+			let outcome = dispatch(&data, &mut stream, &session, &tx);
+			tx.send(DroneCtl::archive(MessageRecord::new(MessageDirection::Received, peer, &data, outcome))).unwrap();
+		},
+		Err((e, data)) if e.kind() == io::ErrorKind::PermissionDenied => {
+			// Logged as an error, so it ends up in error.log.
+			error!(peer_address:% = peer; "Rejected unauthenticated message from {}: {}", peer, e);
+			tx.send(DroneCtl::archive(MessageRecord::new(MessageDirection::Received, peer, &data, MessageOutcome::Unauthenticated))).unwrap();
+		},
+		Err((e, _)) => {
+			warn!(peer_address:% = peer; "Failed to read message from {}: {}", peer, e);
+		}
+	}
+}
+
+fn process_message(address: SocketAddr, tls: Option<Arc<Tls>>, secret: String, tx: mpsc::Sender<DroneCtl>) {
+	info!("Listening for other drones on {}.", address);
+	let secret = Arc::new(secret);
+
+	let (connection_tx, connection_rx) = mpsc::sync_channel::<TcpStream>(MAX_CONNECTIONS);
+	let connection_rx = Arc::new(Mutex::new(connection_rx));
+	for _ in 0..MAX_CONNECTIONS {
+		let (connection_rx, tls, secret, tx) = (connection_rx.clone(), tls.clone(), secret.clone(), tx.clone());
+		thread::spawn(move || loop {
+			let stream = match connection_rx.lock().unwrap().recv() {
+				Ok(stream) => stream,
+				Err(_) => break,
+			};
+			process_connection(stream, tls.clone(), secret.clone(), tx.clone());
+		});
+	}
 
 	loop {
-		let tcp_listen_string = format!("{}:{}", address, port);
-		let listener = TcpListener::bind(tcp_listen_string).unwrap();
+		let listener = TcpListener::bind(address).unwrap();
 
 		for stream in listener.incoming() {
-			let mut data = [0 as u8; 128];
-			match stream.unwrap().read(&mut data) {
-				Ok(_) => {
-					let msg: Message = bincode::deserialize(&data).unwrap();
-					match msg.message_type {
-						MessageType::FinishJob => {
-							// Notification from a drone that a job has been finished.
-							let host: Host = bincode::deserialize(msg.message.as_bytes()).unwrap();
-							tx.send(DroneCtl::new(DroneCtlType::FinishJob, Some(host), None, None)).unwrap();
-						},
-						MessageType::Message => {
-							tx.send(DroneCtl::new(DroneCtlType::Message, None, None, Some(msg.message))).unwrap();
-						},
-						MessageType::Online => {
-							// Notification that a drone has come online.
-							let host: Host = bincode::deserialize(msg.message.as_bytes()).unwrap();
-							tx.send(DroneCtl::new(DroneCtlType::Online, Some(host), None, None)).unwrap();
-						},
-						MessageType::Offline => {
-							// Notification that a drone has gone offline.
-							let host: Host = bincode::deserialize(msg.message.as_bytes()).unwrap();
-							tx.send(DroneCtl::new(DroneCtlType::Offline, Some(host), None, None)).unwrap();
-						},
-						MessageType::StartJob => {
-							// Notification from a drone that a job has been started.
-							let host: Host = bincode::deserialize(msg.message.as_bytes()).unwrap();
-							tx.send(DroneCtl::new(DroneCtlType::StartJob, Some(host), None, None)).unwrap();
-						},
-						MessageType::QueueJob => {
-							// Notification of a new job to be queued.
-							let job: Job = bincode::deserialize(msg.message.as_bytes()).unwrap();
-							tx.send(DroneCtl::new(DroneCtlType::QueueJob, None, Some(job), None)).unwrap();
-						},
-						_ => {
-							// Unknown message from another drone.
-						},
-					}
+			let stream = match stream {
+				Ok(stream) => stream,
+				Err(err) => {
+					error!("Failed to accept a connection from another drone: {}", err);
+					thread::sleep(ACCEPT_RETRY);
+					continue;
 				},
-				Err(e) => {
-					println!("tcp streaming error: {}", e);
-				}
+			};
+
+			if let Err(mpsc::TrySendError::Full(stream)) = connection_tx.try_send(stream) {
+				let peer = stream.peer_addr().map(|peer| peer.to_string()).unwrap_or_else(|_| String::from("unknown"));
+				warn!(peer_address:% = peer; "Closed the connection from {}, {} others are waiting already.", peer, MAX_CONNECTIONS);
 			}
 		}
 
@@ -99,87 +355,216 @@ fn process_message(address: String, port: u32, tx: mpsc::Sender<DroneCtl>) {
 	}
 }
 
+fn process_signals(tx: mpsc::Sender<DroneCtl>, socket_path: PathBuf) {
+	let mut signals = Signals::new([SIGHUP, SIGINT, SIGTERM, SIGUSR1]).unwrap();
+
+	for signal in signals.forever() {
+		match signal {
+			SIGHUP => {
+				// The drone re-reads the config file and has the log process reopen its files.
+				info!("Received SIGHUP, reloading config.");
+				tx.send(DroneCtl::new(DroneCtlType::Reload, None, None, None)).unwrap();
+			},
+			SIGUSR1 => {
+				// Only reopen the log files, for logrotate's postrotate.
+				log::reopen();
+			},
+			SIGINT | SIGTERM => {
+				info!("Received signal {}, shutting down.", signal);
+				shutdown(&tx, &socket_path);
+			},
+			_ => {},
+		}
+	}
+}
+
 fn main() {
 	println!();
-	const VERSION: &'static str = env!("CARGO_PKG_VERSION");
-	const DEFAULT_CONFIG: &'static str = "data/etc/swarm/drone.cfg.toml";
+	const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-	let matches = App::new("swarm drone")
+	let mut app = App::new("swarm drone")
 		.version(VERSION)
-		.about("A simple framework to create a server-less swarm of worker drones.")
-		.arg(Arg::with_name("address")
-			.short("A")
-			.long("address")
-			.takes_value(true)
-			.help("Specify the adress to listen on for inter-drone communications (Default: 0.0.0.0)."))
+		.about("A simple framework to create a server-less swarm of worker drones.\nEvery setting can also be given as a SWARM_<SETTING> environment variable, which overrides the config file and is overridden by the command line.")
 		.arg(Arg::with_name("config")
 			.short("c")
 			.long("config")
 			.takes_value(true)
-			.help("Specify a config file (Default: /etc/swarm/drone.cfg.toml)."))
-		.arg(Arg::with_name("port")
-			.short("p")
-			.long("port")
+			.help("Specify a config file (Default: SWARM_CONFIG or /etc/swarm/drone.cfg.toml)."));
+
+	// The swarm_secret would show up in ps, it only comes from the config file or the environment.
+	for (key, flag, help) in config::FIELDS.iter().filter(|(key, _, _)| *key != "swarm_secret") {
+		let mut arg = Arg::with_name(key)
+			.long(flag)
 			.takes_value(true)
-			.help("Specify the port to listen on for inter-drone communications (Default: 9079)."))
-		.get_matches();
+			.help(help);
+
+		match *key {
+			"address" => arg = arg.short("A"),
+			"port" => arg = arg.short("p"),
+			_ => {},
+		}
+
+		app = app.arg(arg);
+	}
+
+	let matches = app.get_matches();
+
+	let config_file = match matches.value_of("config") {
+		Some(config_file) => config_file.to_string(),
+		None => Config::default_file(),
+	};
+
+	let cli: Vec<(String, String)> = config::FIELDS.iter()
+		.filter(|(key, _, _)| *key != "swarm_secret")
+		.filter_map(|(key, _, _)| matches.value_of(key).map(|value| (key.to_string(), value.to_string())))
+		.collect();
 
-	let mut c = Config::load_or_new(matches.value_of("config").unwrap_or(DEFAULT_CONFIG));
-	c.address = matches.value_of("address").unwrap_or(&c.address).to_string();
-	c.port = matches.value_of("port").unwrap_or(&c.port).to_string();
+	// Config::load logs through the facade, which isn't set up yet.
+	if !Path::new(&config_file).exists() {
+		println!("Config file {} not found, using defaults.", config_file);
+	}
+
+	let mut c = match Config::load(&config_file, &cli) {
+		Ok(c) => c,
+		Err(problems) => {
+			println!("Invalid config {}:", config_file);
+			for problem in problems {
+				println!("  {}", problem);
+			}
+			std::process::exit(0x0001);
+		}
+	};
+
+	if let Err(err) = c.load_or_create_id() {
+		println!("Could not create drone id file {}: {}", c.id_file().display(), err);
+		std::process::exit(0x0001);
+	}
+
+	let tls = match Tls::load(&c) {
+		Ok(tls) => tls.map(Arc::new),
+		Err(err) => {
+			println!("Could not set up TLS between drones: {}", err);
+			std::process::exit(0x0001);
+		}
+	};
 
 	// Start logging process.
-	let (log_tx, log_rx) = mpsc::channel::<LogMessage>();
-	let mut l = log::Log::init(c.id.clone(), c.log_dir.clone(), c.error_log.clone(), c.system_log.clone());
+	let (log_tx, log_rx) = log::channel(&c);
+	let mut l = log::Log::init(&c);
 	let log_handle = thread::spawn(move || {
 		l.run(log_rx);
 	});
+	// Everything logged through the log facade goes to the logging process.
+	log::install(log_tx.clone(), &c).unwrap();
 
 	// Database verification (or creation if needed.)
-	let db = db::Database::verify_or_init(c.id.clone(), c.db_dir.clone(), c.db_file.clone(), log_tx.clone());
+	let db = match db::open(&c) {
+		Ok(db) => db,
+		Err(err) => {
+			let exit_code = match err {
+				db::DatabaseError::Corruption(_) => 0x0100,
+				db::DatabaseError::SchemaMismatch { .. } => 0x0101,
+				db::DatabaseError::Io(_) => 0x0102,
+				db::DatabaseError::Sqlite(_) => 0x0103,
+				db::DatabaseError::Constraint(_) | db::DatabaseError::Unsupported(_) => 0x0104,
+			};
+
+			log_tx.send(LogMessage::new(LogLevel::Fatal, module_path!(), format!("Database validation error: {}. Exit from fatal error.", err)));
+			info!("Database validation failed. See error log.");
+			log_tx.send(LogMessage::offline());
+			log_handle.join().unwrap();
+
+			println!("Database validation error: {}. Exit from fatal error.", err);
+			std::process::exit(exit_code);
+		}
+	};
 
 	// Load additional config info from database.
 	// @TODO Should this be offloaded to the drone process?
 
 	let me = Process::myself().unwrap();
-	let socket_path = format!("/tmp/swarm_drone_{}.sock", me.pid);
-	let listener = UnixListener::bind(socket_path).unwrap();
+	let socket_path = control::socket_path(&c.control_dir, me.pid);
+	let control_access = match ControlAccess::from_config(&c) {
+		Ok(control_access) => control_access,
+		Err(err) => {
+			log_tx.send(LogMessage::new(LogLevel::Fatal, module_path!(), format!("Invalid control socket access settings: {}. Exit from fatal error.", err)));
+			log_tx.send(LogMessage::offline());
+			log_handle.join().unwrap();
+
+			println!("Invalid control socket access settings: {}. Exit from fatal error.", err);
+			std::process::exit(0x0001);
+		}
+	};
+	let listener = match control::bind(&c, me.pid as u32) {
+		Ok(listener) => listener,
+		Err(err) => {
+			log_tx.send(LogMessage::new(LogLevel::Fatal, module_path!(), format!("Could not create control socket {}: {}. Exit from fatal error.", socket_path.display(), err)));
+			log_tx.send(LogMessage::offline());
+			log_handle.join().unwrap();
+
+			println!("Could not create control socket {}: {}. Exit from fatal error.", socket_path.display(), err);
+			std::process::exit(0x0001);
+		}
+	};
 
-	// Start drone process.
 	let (drone_tx, drone_rx) = mpsc::channel::<DroneCtl>();
-	let mut d = drone::Drone::new(db.unwrap(), log_tx.clone());
-	let drone_handle = thread::spawn(move || {
-		d.start();
-		d.run(drone_rx);
-	});
 
 	// Start external listener (for messages from other drones).
 	// Start a thread to listen on the configured port, and pass messages to the drone process via a drone_tx clone.
 	// Similar to the listener that works on a local unix socket. Utilize the "process_command" function.
+	// Started before the drone process, so that answers to anything the drone sends on start aren't lost.
 
-	let listener_address = c.address.clone();
-	let listener_port = c.port.parse::<u32>().unwrap();
+	let listener_address = SocketAddr::new(c.address, c.port);
+	let listener_secret = c.swarm_secret.clone();
+	let listener_tls = tls.clone();
 	let listener_tx = drone_tx.clone();
 	let listener_handle = thread::spawn(move || {
-		process_message(listener_address, listener_port, listener_tx);
+		process_message(listener_address, listener_tls, listener_secret, listener_tx);
+	});
+
+	// Start drone process.
+	let mut d = drone::Drone::new(c.clone(), db);
+	d.archive_tx = Some(drone_tx.clone());
+	d.tls = tls;
+	let drone_handle = thread::spawn(move || {
+		d.start();
+		d.run(drone_rx);
 	});
 
-	// I think spawn a "process message" function into another thread?
+	// Handle unix signals: SIGTERM/SIGINT shut down cleanly, SIGHUP reloads config and reopens logs,
+	// SIGUSR1 only reopens logs.
+	let signal_tx = drone_tx.clone();
+	let signal_socket_path = socket_path.clone();
+	thread::spawn(move || {
+		process_signals(signal_tx, signal_socket_path);
+	});
 
-	println!("Start drone process v. {:?} (pid = {}).", VERSION, me.pid);
-	log_tx.send(LogMessage::new(LogType::SystemLog, format!("Drone process v.{}, id = {}, pid = {} is online.", VERSION, c.id, me.pid))).unwrap();
+	info!("Drone process v.{}, id = {}, pid = {} is online.", VERSION, c.id, me.pid);
 
 	// Listen for the local "commands" from the dronectl binary.
 	for stream in listener.incoming() {
 		match stream {
 			Ok(stream) => {
-				println!("doing some stream stuff in another thread ....");
+				// Checked by the kernel's account of who connected, see control::PeerCred.
+				let peer = match PeerCred::of(&stream) {
+					Ok(peer) => peer,
+					Err(err) => {
+						error!("Refused dronectl connection, could not get its peer credentials: {}", err);
+						continue;
+					},
+				};
+				let access = control_access.access(&peer);
+				if access == Access::Denied {
+					error!("Refused dronectl connection from uid = {}, pid = {}, not in control_users, control_groups or control_read_groups.", peer.uid, peer.pid);
+					continue;
+				}
 
 				let dtx = drone_tx.clone();
-				thread::spawn(|| process_command(stream, dtx));
+				let command_socket_path = socket_path.clone();
+				thread::spawn(move || process_command(stream, access, peer, command_socket_path, dtx));
 			},
 			Err(err) => {
-				println!("Error: {}", err);
+				error!("Control socket failed: {}", err);
 				break;
 			},
 		}
@@ -1,108 +1,56 @@
+use chrono::{Duration, NaiveDate, NaiveDateTime, Utc};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::fs;
-use toml::Value;
+use std::collections::BTreeMap;
+use std::sync::mpsc::Sender;
 use uuid::Uuid;
 
+use crate::config::Config;
 
-#[derive(Clone, Debug, Serialize)]
-pub struct Config {
-	pub address:						String,
-	pub db_dir:							String,
-	pub db_file:						String,
-	pub error_log:						String,
-	pub file:							String,
-	pub id:								Uuid,
-	pub log_dir:						String,
-	pub port:							String,
-	pub system_log:						String,
-}
-
-impl Config {
-	pub fn load_or_new(file: &str) -> Self {
-		// Create default values, which will be overwritten if values are found in a config file.
-		let mut address = String::from("0.0.0.0");
-		let mut db_dir = String::from("data/usr/local/swarm");
-		let mut db_file = String::from("drone.db");
-		let mut error_log = String::from("error.log");
-		let mut id = Uuid::new_v4();
-		let mut log_dir = String::from("data/var/log/swarm");
-		let mut port = String::from("9079");
-		let mut system_log = String::from("system.log");
-
-		let toml_content = fs::read_to_string(file);
-		match toml_content {
-			Ok(content) => {
-				let config_value: Value = toml::from_str(&content).expect("Failed to parse config values after file was found.\nFix or delete (for automatic recreation) config file.");
-				let config: &toml::map::Map<String, Value> = config_value["swarm"].as_table().unwrap();
-
-				for (k, v) in config.iter() {
-				let v_str = v.as_str().unwrap().to_string();
-					match k.as_str() {
-						"address" => {
-							address = v_str;
-						},
-						"db_dir" => {
-							db_dir = v_str;
-						},
-						"db_file" => {
-							db_file = v_str;
-						},
-						"error_log" => {
-							error_log = v_str;
-						},
-						"id" => {
-							id = Uuid::parse_str(&v_str).unwrap();
-						},
-						"log_dir" => {
-							log_dir = v_str;
-						},
-						"port" => {
-							port = v_str;
-						},
-						"system_log" => {
-							system_log = v_str;
-						},
-						_ => {
-							// Unrecognized items are irgnore and removed on "writeback".
-						},
-					}
-				}
-			},
-			Err(content_err) => {
-				if std::io::ErrorKind::NotFound == content_err.kind() {
-					// Log file not found issue, file will be created later by the config.save() call.
-					println!("Config file {} not found, creating a new one.", file);
-				}
 
-			}
-		}
-
-		let mut config = Config {
-			address,
-			db_dir,
-			db_file,
-			error_log,
-			file: file.to_string(),
-			id,
-			log_dir,
-			port,
-			system_log,
-		};
-
-		config.save();
+// Filters for exporting the job archive, by the time jobs finished.
+#[derive(Clone, Deserialize, Debug, Serialize)]
+#[serde(default)]
+pub struct ArchiveQuery {
+	pub limit:						usize,
+	pub offset:						usize,
+	pub since:						Option<String>,
+	pub until:						Option<String>,
+}
 
-		return config;
+impl Default for ArchiveQuery {
+	fn default() -> Self {
+		ArchiveQuery {
+			limit: 1000,
+			offset: 0,
+			since: None,
+			until: None,
+		}
 	}
+}
 
-	pub fn save(&mut self) -> bool {
-		let mut config_toml = String::from("[swarm]");
-		config_toml.push('\n');
-		config_toml = config_toml + &toml::to_string(&self).unwrap();
-
-		fs::write(self.file.to_string(), config_toml).expect("Failed to write config file.");
+// A finished job moved out of the job table, see Drone::archive_jobs.
+#[derive(Clone, Deserialize, Debug, Serialize)]
+pub struct ArchivedJob {
+	pub archived:					String,
+	pub created:					String,
+	pub finished:					Option<String>,
+	pub id:							Uuid,
+	pub output:						Option<String>,
+	pub owners:						Vec<Uuid>,
+	pub status:						JobStatus,
+	pub tags:						Vec<String>,
+}
 
-		true
-	}
+// Replies from the drone process to a dronectl command, sent back over the control socket as JSON.
+#[derive(Deserialize, Debug, Serialize)]
+pub enum CtlReply {
+	Archive(Vec<ArchivedJob>),
+	Error(String),
+	JobLog(JobLogChunk),
+	Logs(SwarmLogs),
+	Search(SearchPage),
+	Snapshot(SnapshotManifest),
 }
 
 #[derive(Deserialize, Debug, Serialize)]
@@ -110,7 +58,14 @@ pub struct DroneCtl {
 	pub dronectl_type:						DroneCtlType,
 	pub host_data:							Option<Host>,
 	pub job_data:							Option<Job>,
+	pub message_data:						Option<MessageRecord>,
 	pub msg:								Option<String>,
+	// Set for dronectl commands that expect an answer.
+	#[serde(skip)]
+	pub reply_tx:							Option<Sender<CtlReply>>,
+	// The drone id in the certificate of the peer that sent the message, only set by the listener over TLS.
+	#[serde(skip)]
+	pub sender:								Option<Uuid>,
 }
 
 impl DroneCtl {
@@ -119,23 +74,73 @@ impl DroneCtl {
 			dronectl_type,
 			host_data,
 			job_data,
+			message_data: None,
+			msg,
+			reply_tx: None,
+			sender: None,
+		}
+	}
+
+	// Ask the drone process to archive a message received by the external listener.
+	pub fn archive(message_data: MessageRecord) -> Self {
+		DroneCtl {
+			dronectl_type: DroneCtlType::ArchiveMessage,
+			host_data: None,
+			job_data: None,
+			message_data: Some(message_data),
+			msg: None,
+			reply_tx: None,
+			sender: None,
+		}
+	}
+
+	pub fn with_reply(dronectl_type: DroneCtlType, msg: Option<String>, reply_tx: Sender<CtlReply>) -> Self {
+		DroneCtl {
+			dronectl_type,
+			host_data: None,
+			job_data: None,
+			message_data: None,
 			msg,
+			reply_tx: Some(reply_tx),
+			sender: None,
+		}
+	}
+
+	// Mark a message from another drone with the id its certificate names, see PeerStream::peer_id.
+	pub fn sent_by(mut self, sender: Option<Uuid>) -> Self {
+		self.sender = sender;
+		self
+	}
+
+	// Answer a dronectl command, if anyone is still waiting for it.
+	pub fn reply(&self, reply: CtlReply) {
+		if let Some(reply_tx) = &self.reply_tx {
+			let _ = reply_tx.send(reply);
 		}
 	}
 }
 
 #[derive(Deserialize, Debug, Serialize)]
 pub enum DroneCtlType {
+	ArchiveExport,
+	ArchiveMessage,
 	FinishJob,
+	JobLog,
+	Logs,
 	Message,
 	Online,
 	Offline,
+	Ownership,
 	QueueJob,
+	Reload,
+	Restored,
+	Search,
+	Snapshot,
 	Stop,
 	StartJob,
 }
 
-#[derive(Deserialize, Debug, Serialize)]
+#[derive(Clone, Deserialize, Debug, Serialize)]
 pub enum HostStatus {
 	Online,
 	Offline,
@@ -143,7 +148,7 @@ pub enum HostStatus {
 	Working,
 }
 
-#[derive(Deserialize, Debug, Serialize)]
+#[derive(Clone, Deserialize, Debug, Serialize)]
 pub struct Host {
 	pub address:					String,
 	pub id:							Uuid,
@@ -182,43 +187,403 @@ impl Host {
 	}
 }
 
-#[derive(Deserialize, Debug, Serialize)]
+#[derive(Clone, Deserialize, Debug, Serialize)]
 pub struct Job {
-	id:								Uuid,
-	tags:							Vec<String>,
+	// The program and its arguments, run by the drone that works on the job.
+	#[serde(default)]
+	pub command:					Vec<String>,
+	pub id:							Uuid,
+	// The submitter's hex ed25519 signature of the job, see signing.rs.
+	#[serde(default)]
+	pub signature:					String,
+	// Who submitted the job, one of the drones' job_submitters.
+	#[serde(default)]
+	pub submitter:					String,
+	pub tags:						Vec<String>,
+}
+
+impl Default for Job {
+	fn default() -> Self {
+		Job::new()
+	}
 }
 
 impl Job {
 	pub fn new() -> Self {
+		let command = Vec::new();
 		let id = Uuid::new_v4();
+		let signature = String::new();
+		let submitter = String::new();
 		let tags = Vec::new();
 
 		Job {
+			command,
 			id,
+			signature,
+			submitter,
 			tags,
 		}
 	}
 }
 
+// Part of a job's captured stdout or stderr, see joblog.rs.
+#[derive(Deserialize, Debug, Serialize)]
+pub struct JobLogChunk {
+	pub data:						String,
+	// Set once the job has stopped and nothing follows data.
+	pub done:						bool,
+	// The drone the output was read from.
+	pub drone:						Uuid,
+	// Where the next chunk starts.
+	pub offset:						u64,
+}
+
+// Asks for the captured output of a job from offset, answered by whichever drone ran it.
+#[derive(Clone, Deserialize, Debug, Serialize)]
+#[serde(default)]
+pub struct JobLogQuery {
+	// Set when a drone passes the query on to the job owner, which then only answers from its own files.
+	pub forwarded:					bool,
+	pub job:						Uuid,
+	pub limit:						usize,
+	pub offset:						u64,
+	pub stream:						JobStream,
+}
+
+impl Default for JobLogQuery {
+	fn default() -> Self {
+		JobLogQuery {
+			forwarded: false,
+			job: Uuid::nil(),
+			limit: 64 * 1024,
+			offset: 0,
+			stream: JobStream::Stdout,
+		}
+	}
+}
+
+// Filters for searching the local job archive. Unset filters match everything.
+#[derive(Clone, Deserialize, Debug, Serialize)]
+#[serde(default)]
+pub struct JobQuery {
+	pub drone:						Option<Uuid>,
+	pub limit:						usize,
+	pub offset:						usize,
+	// UTC, "YYYY-MM-DD HH:MM:SS" like sqlite's CURRENT_TIMESTAMP (see parse_time).
+	pub since:						Option<String>,
+	pub status:						Option<JobStatus>,
+	pub tag:						Option<String>,
+	// Plain substring of the job output.
+	pub text:						Option<String>,
+	pub until:						Option<String>,
+}
+
+impl Default for JobQuery {
+	fn default() -> Self {
+		JobQuery {
+			drone: None,
+			limit: 20,
+			offset: 0,
+			since: None,
+			status: None,
+			tag: None,
+			text: None,
+			until: None,
+		}
+	}
+}
+
+// Accepts an age relative to now ("30s", "10m", "2h", "7d") or a UTC date/time
+// ("2020-10-10", "2020-10-10 10:10:10" or "2020-10-10T10:10:10") and returns it in sqlite's format.
+pub fn parse_time(time: &str) -> Result<String, String> {
+	const FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+	let time = time.trim();
+	// The unit is the last character, which needn't be a single byte.
+	let (amount, unit) = time.split_at(time.char_indices().last().map_or(0, |(start, _)| start));
+	if let Ok(amount) = amount.parse::<i64>() {
+		let unit_seconds = match unit {
+			"s" => Some(1),
+			"m" => Some(60),
+			"h" => Some(60 * 60),
+			"d" => Some(24 * 60 * 60),
+			_ => None,
+		};
+
+		if let Some(unit_seconds) = unit_seconds {
+			if amount < 0 {
+				return Err(format!("invalid time \"{}\", an age can't be negative", time));
+			}
+
+			// Duration::seconds panics past i64::MAX milliseconds, and so does subtracting too much from now.
+			let age = amount.checked_mul(unit_seconds).filter(|seconds| seconds.checked_mul(1000).is_some()).map(Duration::seconds);
+			return age.and_then(|age| Utc::now().checked_sub_signed(age))
+				.map(|since| since.format(FORMAT).to_string())
+				.ok_or_else(|| format!("invalid time \"{}\", the age is too large", time));
+		}
+	}
+
+	if let Ok(datetime) = NaiveDateTime::parse_from_str(time, FORMAT).or_else(|_| NaiveDateTime::parse_from_str(time, "%Y-%m-%dT%H:%M:%S")) {
+		return Ok(datetime.format(FORMAT).to_string());
+	}
+
+	if let Ok(date) = NaiveDate::parse_from_str(time, "%Y-%m-%d") {
+		return Ok(date.and_hms(0, 0, 0).format(FORMAT).to_string());
+	}
+
+	Err(format!("invalid time \"{}\", expected an age like 10m/2h/7d or a UTC date like 2020-10-10 10:10:10", time))
+}
+
+#[derive(Clone, Deserialize, Debug, Serialize)]
+pub struct JobRecord {
+	pub created:					String,
+	pub finished:					Option<String>,
+	pub id:							Uuid,
+	pub owners:						Vec<Uuid>,
+	pub status:						JobStatus,
+	pub tags:						Vec<String>,
+}
+
+// One page of search results, total counts every match.
+#[derive(Deserialize, Debug, Serialize)]
+pub struct SearchPage {
+	pub jobs:						Vec<JobRecord>,
+	pub limit:						usize,
+	pub offset:						usize,
+	pub total:						usize,
+}
+
+// Matches the rows of the job_status_enum table.
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq, Serialize)]
+pub enum JobStatus {
+	Canceled,
+	Error,
+	Finished,
+	New,
+	Working,
+}
+
+impl JobStatus {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			JobStatus::Canceled => "Canceled",
+			JobStatus::Error => "Error",
+			JobStatus::Finished => "Finished",
+			JobStatus::New => "New",
+			JobStatus::Working => "Working",
+		}
+	}
+
+	pub fn from_name(status: &str) -> Option<Self> {
+		match status {
+			"Canceled" => Some(JobStatus::Canceled),
+			"Error" => Some(JobStatus::Error),
+			"Finished" => Some(JobStatus::Finished),
+			"New" => Some(JobStatus::New),
+			"Working" => Some(JobStatus::Working),
+			_ => None,
+		}
+	}
+
+	// Canceled, Error and Finished jobs get a finished timestamp.
+	pub fn is_done(&self) -> bool {
+		matches!(self, JobStatus::Canceled | JobStatus::Error | JobStatus::Finished)
+	}
+}
+
+// The output streams of a job, each captured to its own file.
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStream {
+	Stdout,
+	Stderr,
+}
+
+impl JobStream {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			JobStream::Stdout => "stdout",
+			JobStream::Stderr => "stderr",
+		}
+	}
+
+	pub fn from_name(stream: &str) -> Option<Self> {
+		match stream {
+			"stdout" => Some(JobStream::Stdout),
+			"stderr" => Some(JobStream::Stderr),
+			_ => None,
+		}
+	}
+}
+
+// A line of a drone's log files, see log::search.
+#[derive(Clone, Deserialize, Debug, PartialEq, Serialize)]
+pub struct LogLine {
+	pub drone:						Uuid,
+	// The line without its timestamp, JSON lines as they are.
+	pub line:						String,
+	// UTC, "YYYY-MM-DD HH:MM:SS.mmm" so that lines from all drones sort by it.
+	pub timestamp:					String,
+}
+
+// Asks drones for the lines of their log files matching a pattern.
+#[derive(Clone, Deserialize, Debug, Serialize)]
+#[serde(default)]
+pub struct LogQuery {
+	// Set when a drone passes the query on to its peers, which then only answer for themselves.
+	pub forwarded:					bool,
+	// Regular expression, matched anywhere in a line.
+	pub grep:						Option<String>,
+	// Most lines per drone, the newest are kept.
+	pub limit:						usize,
+	// UTC, "YYYY-MM-DD HH:MM:SS" (see parse_time).
+	pub since:						Option<String>,
+}
+
+impl Default for LogQuery {
+	fn default() -> Self {
+		LogQuery {
+			forwarded: false,
+			grep: None,
+			limit: 1000,
+			since: None,
+		}
+	}
+}
+
+// Severity of a log message, lowest first. Which levels are written, and to which files, is set by
+// log_level, log_targets and log_routes in the config.
+#[derive(Clone, Copy, Deserialize, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+	Trace,
+	Debug,
+	Info,
+	Warn,
+	Error,
+	Fatal,
+}
+
+impl LogLevel {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			LogLevel::Trace => "TRACE",
+			LogLevel::Debug => "DEBUG",
+			LogLevel::Info => "INFO",
+			LogLevel::Warn => "WARN",
+			LogLevel::Error => "ERROR",
+			LogLevel::Fatal => "FATAL",
+		}
+	}
+
+	pub fn from_name(level: &str) -> Option<Self> {
+		match level.to_lowercase().as_str() {
+			"trace" => Some(LogLevel::Trace),
+			"debug" => Some(LogLevel::Debug),
+			"info" => Some(LogLevel::Info),
+			"warn" => Some(LogLevel::Warn),
+			"error" => Some(LogLevel::Error),
+			"fatal" => Some(LogLevel::Fatal),
+			_ => None,
+		}
+	}
+}
+
 pub struct LogMessage {
-	pub log_type:					LogType,
+	pub config:						Option<Config>,
+	// Free-form context, written as key/values by the JSON log format.
+	pub fields:						BTreeMap<String, String>,
+	pub job_id:						Option<Uuid>,
+	pub level:						LogLevel,
 	pub message:					String,
 	pub message_type:				MessageType,
+	pub peer_id:					Option<Uuid>,
+	// The type of the inter-drone message this is about, message_type is for the log process itself.
+	pub peer_message_type:			Option<MessageType>,
+	// Where the message comes from, usually module_path!() of the sender.
+	pub target:						String,
 }
 
 impl LogMessage {
-	pub fn new(log_type: LogType, message: String) -> Self {
+	pub fn new(level: LogLevel, target: &str, message: String) -> Self {
 		LogMessage {
-			log_type,
+			config: None,
+			fields: BTreeMap::new(),
+			job_id: None,
+			level,
 			message,
 			message_type: MessageType::Message,
+			peer_id: None,
+			peer_message_type: None,
+			target: target.to_string(),
+		}
+	}
+
+	// Attach a free-form key/value for structured log output.
+	pub fn field(mut self, key: &str, value: &str) -> Self {
+		self.fields.insert(key.to_string(), value.to_string());
+		self
+	}
+
+	pub fn job(mut self, job_id: Uuid) -> Self {
+		self.job_id = Some(job_id);
+		self
+	}
+
+	pub fn peer(mut self, peer_id: Uuid) -> Self {
+		self.peer_id = Some(peer_id);
+		self
+	}
+
+	pub fn peer_message(mut self, message_type: MessageType) -> Self {
+		self.peer_message_type = Some(message_type);
+		self
+	}
+
+	// Ask the log process to finish writing and stop.
+	pub fn offline() -> Self {
+		LogMessage {
+			config: None,
+			fields: BTreeMap::new(),
+			job_id: None,
+			level: LogLevel::Info,
+			message: String::new(),
+			message_type: MessageType::Offline,
+			peer_id: None,
+			peer_message_type: None,
+			target: String::new(),
+		}
+	}
+
+	// Ask the log process to close and reopen its files, after they were moved by an external logrotate.
+	pub fn reopen() -> Self {
+		LogMessage {
+			config: None,
+			fields: BTreeMap::new(),
+			job_id: None,
+			level: LogLevel::Info,
+			message: String::new(),
+			message_type: MessageType::Reload,
+			peer_id: None,
+			peer_message_type: None,
+			target: String::new(),
 		}
 	}
-}
 
-pub enum LogType {
-	ErrorLog,
-	SystemLog,
+	// Ask the log process to reopen its files and apply the log settings found in config.
+	pub fn reload(config: Config) -> Self {
+		LogMessage {
+			config: Some(config),
+			fields: BTreeMap::new(),
+			job_id: None,
+			level: LogLevel::Info,
+			message: String::new(),
+			message_type: MessageType::Reload,
+			peer_id: None,
+			peer_message_type: None,
+			target: String::new(),
+		}
+	}
 }
 
 #[derive(Deserialize, Debug, Serialize)]
@@ -240,9 +605,138 @@ impl Message {
 			message_type,
 		}
 	}
+
+	// A message carrying structured data (a Host, an OwnershipReport, ...) as JSON.
+	pub fn with_payload<T: Serialize>(message_type: MessageType, payload: &T) -> Self {
+		Message::new(Vec::new(), serde_json::to_string(payload).unwrap(), message_type)
+	}
+
+	pub fn payload<T: DeserializeOwned>(&self) -> serde_json::Result<T> {
+		serde_json::from_str(&self.message)
+	}
 }
 
+// The jobs a drone owns, as far as the sender knows. Sent in answer to MessageType::Restored.
 #[derive(Deserialize, Debug, Serialize)]
+pub struct OwnershipReport {
+	pub drone:						Uuid,
+	pub jobs:						Vec<Uuid>,
+	// The drone sending the report, over TLS it has to be the one its certificate names.
+	pub reporter:					Uuid,
+}
+
+// Log lines of every drone that answered a LogQuery, oldest first, and the drones that didn't.
+#[derive(Deserialize, Debug, Default, Serialize)]
+pub struct SwarmLogs {
+	pub failed:						Vec<String>,
+	pub lines:						Vec<LogLine>,
+}
+
+// Written next to the database and config in a snapshot directory, see snapshot.rs.
+#[derive(Clone, Deserialize, Debug, Serialize)]
+pub struct SnapshotManifest {
+	pub created:					String,
+	pub drone_id:					Uuid,
+	pub schema_version:				u32,
+	pub version:					String,
+}
+
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq, Serialize)]
+pub enum MessageDirection {
+	Received,
+	Sent,
+}
+
+impl MessageDirection {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			MessageDirection::Received => "Received",
+			MessageDirection::Sent => "Sent",
+		}
+	}
+
+	pub fn from_name(direction: &str) -> Option<Self> {
+		match direction {
+			"Received" => Some(MessageDirection::Received),
+			"Sent" => Some(MessageDirection::Sent),
+			_ => None,
+		}
+	}
+}
+
+// What became of an archived message.
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq, Serialize)]
+pub enum MessageOutcome {
+	// Received and passed on to the drone process.
+	Accepted,
+	// Received, but of a type this drone doesn't handle.
+	Ignored,
+	// Received, but could not be decoded.
+	Invalid,
+	Sent,
+	SendFailed,
+	// Rejected, the sender did not prove it knows the swarm secret.
+	Unauthenticated,
+}
+
+impl MessageOutcome {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			MessageOutcome::Accepted => "Accepted",
+			MessageOutcome::Ignored => "Ignored",
+			MessageOutcome::Invalid => "Invalid",
+			MessageOutcome::Sent => "Sent",
+			MessageOutcome::SendFailed => "SendFailed",
+			MessageOutcome::Unauthenticated => "Unauthenticated",
+		}
+	}
+
+	pub fn from_name(outcome: &str) -> Option<Self> {
+		match outcome {
+			"Accepted" => Some(MessageOutcome::Accepted),
+			"Ignored" => Some(MessageOutcome::Ignored),
+			"Invalid" => Some(MessageOutcome::Invalid),
+			"Sent" => Some(MessageOutcome::Sent),
+			"SendFailed" => Some(MessageOutcome::SendFailed),
+			"Unauthenticated" => Some(MessageOutcome::Unauthenticated),
+			_ => None,
+		}
+	}
+}
+
+// A row of the message archive. The raw payload is kept so that traffic can be replayed.
+#[derive(Clone, Deserialize, Debug, Serialize)]
+pub struct MessageRecord {
+	pub direction:					MessageDirection,
+	// Unset when the payload could not be decoded.
+	pub id:							Option<Uuid>,
+	pub message_type:				Option<String>,
+	pub outcome:					MessageOutcome,
+	pub payload:					Vec<u8>,
+	pub peer:						String,
+	pub size:						usize,
+	// UTC, "YYYY-MM-DD HH:MM:SS" like sqlite's CURRENT_TIMESTAMP.
+	pub timestamp:					String,
+}
+
+impl MessageRecord {
+	pub fn new(direction: MessageDirection, peer: String, payload: &[u8], outcome: MessageOutcome) -> Self {
+		let message = bincode::deserialize::<Message>(payload).ok();
+
+		MessageRecord {
+			direction,
+			id: message.as_ref().map(|message| message.id),
+			message_type: message.as_ref().map(|message| format!("{:?}", message.message_type)),
+			outcome,
+			payload: payload.to_vec(),
+			peer,
+			size: payload.len(),
+			timestamp: Utc::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+		}
+	}
+}
+
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq, Serialize)]
 pub enum MessageType {
 	FinishJob,
 	Message,
@@ -251,4 +745,35 @@ pub enum MessageType {
 	StartJob,
 	Unknown,
 	QueueJob,
+	Reload,
+	// A drone restored from a snapshot re-announcing itself, answered with an Ownership message.
+	Restored,
+	Ownership,
+	// Asks for part of a job's output (a JobLogQuery), answered on the same connection.
+	JobLog,
+	// Asks for matching log lines (a LogQuery), answered on the same connection.
+	Logs,
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse_time_test() {
+		assert_eq!(parse_time("2020-10-10"), Ok(String::from("2020-10-10 00:00:00")));
+		assert_eq!(parse_time("2020-10-10T10:10:10"), Ok(String::from("2020-10-10 10:10:10")));
+		assert!(parse_time("10m").is_ok());
+		assert!(parse_time("0s").is_ok());
+
+		// Ages reach back from now, never forward, and only as far as a date can.
+		assert!(parse_time("-10m").unwrap_err().contains("negative"));
+		assert!(parse_time("99999999999999d").unwrap_err().contains("too large"));
+		assert!(parse_time("9223372036854775807s").unwrap_err().contains("too large"));
+		assert!(parse_time("9999999999999s").unwrap_err().contains("too large"));
+
+		assert!(parse_time("5é").is_err());
+		assert!(parse_time("é").is_err());
+		assert!(parse_time("").is_err());
+	}
 }
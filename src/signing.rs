@@ -0,0 +1,288 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::config::Config;
+use crate::models::Job;
+
+
+// Someone allowed to submit jobs, written "<name>=<hex ed25519 public key>" in the config.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct JobSubmitter {
+	pub key:						VerifyingKey,
+	pub name:						String,
+}
+
+impl TryFrom<String> for JobSubmitter {
+	type Error = String;
+
+	fn try_from(rule: String) -> Result<Self, String> {
+		let (name, key) = rule.split_once('=')
+			.ok_or_else(|| format!("invalid job submitter \"{}\", expected <name>=<hex public key>", rule))?;
+		let key = from_hex::<32>(key.trim())
+			.and_then(|key| VerifyingKey::from_bytes(&key).map_err(|err| err.to_string()))
+			.map_err(|err| format!("invalid public key in job submitter \"{}\": {}", rule, err))?;
+
+		if name.trim().is_empty() || name.trim() == "*" {
+			return Err(format!("job submitter \"{}\" needs a name", rule));
+		}
+
+		Ok(JobSubmitter {
+			key,
+			name: name.trim().to_string(),
+		})
+	}
+}
+
+impl From<JobSubmitter> for String {
+	fn from(submitter: JobSubmitter) -> Self {
+		format!("{}={}", submitter.name, to_hex(submitter.key.as_bytes()))
+	}
+}
+
+// What a job_acl rule allows: running a program, or jobs with a tag.
+#[derive(Clone, Debug, PartialEq)]
+pub enum JobScope {
+	// A program path, or a prefix of one when it ends in *.
+	Command(String),
+	// A tag, * for any.
+	Tag(String),
+}
+
+// Allows a submitter (* for all of them) something, written "<submitter>=command:<program>" or
+// "<submitter>=tag:<tag>" in the config, e.g. alice=command:/opt/jobs/*.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct JobRule {
+	pub scope:						JobScope,
+	pub submitter:					String,
+}
+
+impl JobRule {
+	fn applies_to(&self, submitter: &str) -> bool {
+		self.submitter == "*" || self.submitter == submitter
+	}
+
+	// The program has to be a plain absolute path, or a prefix like /opt/jobs/* would let
+	// /opt/jobs/../../bin/sh through.
+	fn allows_command(&self, program: &str) -> bool {
+		if !plain_path(program) {
+			return false;
+		}
+
+		match &self.scope {
+			JobScope::Command(pattern) => match pattern.strip_suffix('*') {
+				Some(prefix) => program.starts_with(prefix),
+				None => program == pattern,
+			},
+			JobScope::Tag(_) => false,
+		}
+	}
+
+	fn allows_tag(&self, tag: &str) -> bool {
+		match &self.scope {
+			JobScope::Command(_) => false,
+			JobScope::Tag(allowed) => allowed == "*" || allowed == tag,
+		}
+	}
+}
+
+impl TryFrom<String> for JobRule {
+	type Error = String;
+
+	fn try_from(rule: String) -> Result<Self, String> {
+		let invalid = || format!("invalid job rule \"{}\", expected <submitter>=command:<absolute program path> or <submitter>=tag:<tag>", rule);
+
+		let (submitter, scope) = rule.split_once('=').ok_or_else(invalid)?;
+		let scope = match scope.trim().split_once(':') {
+			Some(("command", program)) if plain_path(program.trim()) => JobScope::Command(program.trim().to_string()),
+			Some(("tag", tag)) if !tag.trim().is_empty() => JobScope::Tag(tag.trim().to_string()),
+			_ => return Err(invalid()),
+		};
+
+		if submitter.trim().is_empty() {
+			return Err(format!("job rule \"{}\" has no submitter", rule));
+		}
+
+		Ok(JobRule {
+			scope,
+			submitter: submitter.trim().to_string(),
+		})
+	}
+}
+
+impl From<JobRule> for String {
+	fn from(rule: JobRule) -> Self {
+		match rule.scope {
+			JobScope::Command(program) => format!("{}=command:{}", rule.submitter, program),
+			JobScope::Tag(tag) => format!("{}=tag:{}", rule.submitter, tag),
+		}
+	}
+}
+
+// What a submitter signs: everything that decides what a job does and where it runs.
+pub fn job_data(job: &Job) -> Vec<u8> {
+	serde_json::to_vec(&("swarm job", job.id, &job.submitter, &job.command, &job.tags)).unwrap()
+}
+
+// An absolute path without . or .. components, so that what it names is what it says.
+fn plain_path(program: &str) -> bool {
+	program.starts_with('/') && !program.split('/').any(|part| part == "." || part == "..")
+}
+
+pub fn sign(job: &mut Job, submitter: &str, key: &SigningKey) {
+	job.submitter = submitter.to_string();
+	job.signature = to_hex(&key.sign(&job_data(job)).to_bytes());
+}
+
+// Whether this drone may run a job: it has to be signed by one of the job_submitters, and job_acl has
+// to allow that submitter its program and every one of its tags. Without job_submitters no job runs.
+pub fn authorize(job: &Job, config: &Config) -> Result<(), String> {
+	if config.job_submitters.is_empty() {
+		return Err(String::from("no job_submitters are set"));
+	}
+
+	let submitter = config.job_submitters.iter().find(|submitter| submitter.name == job.submitter)
+		.ok_or_else(|| format!("unknown submitter \"{}\"", job.submitter))?;
+	let signature = from_hex::<64>(&job.signature).map(|signature| Signature::from_bytes(&signature))
+		.map_err(|err| format!("invalid signature: {}", err))?;
+	submitter.key.verify(&job_data(job), &signature).map_err(|_| format!("the signature is not by submitter \"{}\"", job.submitter))?;
+
+	let rules: Vec<&JobRule> = config.job_acl.iter().filter(|rule| rule.applies_to(&job.submitter)).collect();
+	let program = job.command.first().ok_or_else(|| String::from("the job has no command"))?;
+	if !rules.iter().any(|rule| rule.allows_command(program)) {
+		return Err(format!("submitter \"{}\" may not run {}", job.submitter, program));
+	}
+	if let Some(tag) = job.tags.iter().find(|tag| !rules.iter().any(|rule| rule.allows_tag(tag))) {
+		return Err(format!("submitter \"{}\" may not run jobs tagged {}", job.submitter, tag));
+	}
+
+	Ok(())
+}
+
+// A new signing key, and the public key that goes into job_submitters.
+pub fn generate_key() -> io::Result<(SigningKey, String)> {
+	let mut secret = [0; 32];
+	getrandom::getrandom(&mut secret).map_err(|err| io::Error::other(err.to_string()))?;
+	let key = SigningKey::from_bytes(&secret);
+	let public = to_hex(key.verifying_key().as_bytes());
+
+	Ok((key, public))
+}
+
+// Key files hold the hex secret key on one line.
+pub fn read_key(path: &Path) -> io::Result<SigningKey> {
+	let content = fs::read_to_string(path)?;
+	let secret = from_hex::<32>(content.trim()).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), err)))?;
+
+	Ok(SigningKey::from_bytes(&secret))
+}
+
+pub fn key_file_content(key: &SigningKey) -> String {
+	format!("{}\n", to_hex(key.as_bytes()))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex<const N: usize>(hex: &str) -> Result<[u8; N], String> {
+	if hex.len() != N * 2 || !hex.is_ascii() {
+		return Err(format!("expected {} hex digits", N * 2));
+	}
+
+	let mut bytes = [0; N];
+	for (i, byte) in bytes.iter_mut().enumerate() {
+		*byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| format!("expected {} hex digits", N * 2))?;
+	}
+
+	Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn job(command: &[&str], tags: &[&str]) -> Job {
+		let mut job = Job::new();
+		job.command = command.iter().map(|arg| arg.to_string()).collect();
+		job.tags = tags.iter().map(|tag| tag.to_string()).collect();
+		job
+	}
+
+	#[test]
+	fn signing_authorize_test() {
+		let (alice, alice_public) = generate_key().unwrap();
+		let (mallory, _) = generate_key().unwrap();
+
+		let mut config = Config {
+			job_acl: vec![
+				JobRule::try_from(String::from("alice=command:/opt/jobs/*")).unwrap(),
+				JobRule::try_from(String::from("*=tag:gpu")).unwrap(),
+			],
+			job_submitters: vec![JobSubmitter::try_from(format!("alice={}", alice_public)).unwrap()],
+			..Config::default()
+		};
+
+		let mut allowed = job(&["/opt/jobs/render", "--fast"], &["gpu"]);
+		sign(&mut allowed, "alice", &alice);
+		assert_eq!(authorize(&allowed, &config), Ok(()));
+
+		// Anything the signature covers can't change afterwards.
+		let mut altered = allowed.clone();
+		altered.command[0] = String::from("/bin/sh");
+		assert!(authorize(&altered, &config).unwrap_err().contains("not by submitter"));
+
+		let mut forged = job(&["/opt/jobs/render"], &[]);
+		sign(&mut forged, "alice", &mallory);
+		assert!(authorize(&forged, &config).is_err());
+
+		let mut unsigned = job(&["/opt/jobs/render"], &[]);
+		unsigned.submitter = String::from("alice");
+		assert!(authorize(&unsigned, &config).unwrap_err().contains("invalid signature"));
+
+		// Signed, but not allowed by the ACL.
+		let mut program = job(&["/bin/sh", "-c", "true"], &[]);
+		sign(&mut program, "alice", &alice);
+		assert!(authorize(&program, &config).unwrap_err().contains("may not run /bin/sh"));
+
+		let mut tag = job(&["/opt/jobs/render"], &["gpu", "prod"]);
+		sign(&mut tag, "alice", &alice);
+		assert!(authorize(&tag, &config).unwrap_err().contains("tagged prod"));
+
+		// A prefix only covers plain paths below it.
+		let mut escape = job(&["/opt/jobs/../../bin/sh"], &[]);
+		sign(&mut escape, "alice", &alice);
+		assert!(authorize(&escape, &config).unwrap_err().contains("may not run"));
+		let mut relative = job(&["/opt/jobs/./render"], &[]);
+		sign(&mut relative, "alice", &alice);
+		assert!(authorize(&relative, &config).is_err());
+
+		// Without submitters no job runs, signed or not.
+		config.job_submitters.clear();
+		assert!(authorize(&allowed, &config).unwrap_err().contains("no job_submitters"));
+		assert!(authorize(&altered, &config).is_err());
+	}
+
+	#[test]
+	fn signing_rule_test() {
+		let rule = JobRule::try_from(String::from("alice = command:/usr/bin/make")).unwrap();
+		assert_eq!(rule.scope, JobScope::Command(String::from("/usr/bin/make")));
+		assert_eq!(String::from(rule), "alice=command:/usr/bin/make");
+
+		assert!(JobRule::try_from(String::from("alice=path:/usr/bin/make")).is_err());
+		assert!(JobRule::try_from(String::from("alice=tag:")).is_err());
+		assert!(JobRule::try_from(String::from("alice=command:make")).is_err());
+		assert!(JobRule::try_from(String::from("alice=command:/opt/jobs/../*")).is_err());
+		assert!(JobSubmitter::try_from(String::from("alice=abcd")).is_err());
+
+		let (key, public) = generate_key().unwrap();
+		let submitter = JobSubmitter::try_from(format!("alice={}", public)).unwrap();
+		assert_eq!(submitter.key, key.verifying_key());
+		assert_eq!(String::from(submitter), format!("alice={}", public));
+	}
+}
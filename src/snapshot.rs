@@ -0,0 +1,155 @@
+use chrono::{Local, Utc};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::db::{migrations, DatabaseError, Store};
+use crate::models::SnapshotManifest;
+
+// A snapshot is a directory holding a copy of the database, the effective config and a manifest.
+pub const CONFIG_FILE: &str = "drone.cfg.toml";
+pub const DB_FILE: &str = "drone.db";
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+// Left in db_dir by restore, so that the drone re-announces itself and reconciles job ownership
+// with its peers on the next start.
+pub const RESTORED_MARKER: &str = "restored";
+
+#[derive(Debug)]
+pub enum SnapshotError {
+	Database(DatabaseError),
+	Invalid(String),
+	Io(io::Error),
+}
+
+impl fmt::Display for SnapshotError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			SnapshotError::Database(err) => write!(f, "database error: {}", err),
+			SnapshotError::Invalid(message) => write!(f, "{}", message),
+			SnapshotError::Io(err) => write!(f, "i/o error: {}", err),
+		}
+	}
+}
+
+impl Error for SnapshotError {
+	fn source(&self) -> Option<&(dyn Error + 'static)> {
+		match self {
+			SnapshotError::Database(err) => Some(err),
+			SnapshotError::Invalid(_) => None,
+			SnapshotError::Io(err) => Some(err),
+		}
+	}
+}
+
+impl From<DatabaseError> for SnapshotError {
+	fn from(err: DatabaseError) -> Self {
+		SnapshotError::Database(err)
+	}
+}
+
+impl From<io::Error> for SnapshotError {
+	fn from(err: io::Error) -> Self {
+		SnapshotError::Io(err)
+	}
+}
+
+pub type Result<T> = std::result::Result<T, SnapshotError>;
+
+// Write a snapshot of a running drone into dir, which must be empty (or not exist yet).
+pub fn create(db: &dyn Store, config: &Config, dir: &Path) -> Result<SnapshotManifest> {
+	fs::create_dir_all(dir)?;
+	if fs::read_dir(dir)?.next().is_some() {
+		return Err(SnapshotError::Invalid(format!("snapshot directory {} is not empty", dir.display())));
+	}
+
+	db.backup_to(&dir.join(DB_FILE))?;
+
+	let snapshot_config = Config {
+		file: dir.join(CONFIG_FILE),
+		..config.clone()
+	};
+	snapshot_config.save()?;
+
+	let manifest = SnapshotManifest {
+		created: Utc::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+		drone_id: config.id,
+		schema_version: migrations::latest_version(),
+		version: env!("CARGO_PKG_VERSION").to_string(),
+	};
+	fs::write(dir.join(MANIFEST_FILE), serde_json::to_string_pretty(&manifest).unwrap())?;
+
+	Ok(manifest)
+}
+
+pub fn read_manifest(dir: &Path) -> Result<SnapshotManifest> {
+	let content = fs::read_to_string(dir.join(MANIFEST_FILE))?;
+
+	serde_json::from_str(&content).map_err(|err| SnapshotError::Invalid(format!("invalid snapshot manifest: {}", err)))
+}
+
+// Rebuild a (stopped) drone from a snapshot: its config is written to config_file, the database and
+// id file to the db_dir that config names. Existing files are only replaced with force, an existing
+// database is kept alongside as drone.db.pre-restore-<timestamp>.bak.
+pub fn restore(dir: &Path, config_file: &Path, force: bool) -> Result<SnapshotManifest> {
+	let manifest = read_manifest(dir)?;
+
+	if manifest.schema_version > migrations::latest_version() {
+		return Err(SnapshotError::Invalid(format!(
+			"snapshot has schema version {}, this drone supports up to {}", manifest.schema_version, migrations::latest_version()
+		)));
+	}
+
+	let content = fs::read_to_string(dir.join(CONFIG_FILE))?;
+	let config = Config::parse(&content).map_err(|problems| {
+		let problems: Vec<String> = problems.iter().map(|problem| problem.to_string()).collect();
+		SnapshotError::Invalid(format!("invalid snapshot config: {}", problems.join("; ")))
+	})?;
+
+	let db_path = config.db_dir.join(&config.db_file);
+	for path in [config_file, db_path.as_path()].iter() {
+		if path.exists() && !force {
+			return Err(SnapshotError::Invalid(format!("{} already exists, use --force to replace it", path.display())));
+		}
+	}
+
+	if let Some(parent) = config_file.parent() {
+		fs::create_dir_all(parent)?;
+	}
+	// Like the snapshot's copy, the restored config holds the swarm_secret.
+	Config { file: config_file.to_path_buf(), ..config.clone() }.save()?;
+
+	fs::create_dir_all(&config.db_dir)?;
+	if db_path.exists() {
+		set_aside(&db_path)?;
+	}
+	fs::copy(dir.join(DB_FILE), &db_path)?;
+
+	fs::write(config.id_file(), format!("{}\n", manifest.drone_id))?;
+	fs::write(config.db_dir.join(RESTORED_MARKER), format!("{}\n", dir.display()))?;
+
+	Ok(manifest)
+}
+
+// Move a database out of the way, together with its WAL and shared memory files so that they are
+// never applied to the restored copy.
+fn set_aside(db_path: &Path) -> Result<()> {
+	let suffix = format!(".pre-restore-{}.bak", Local::now().format("%Y%m%dT%H%M%S"));
+
+	for extension in ["", "-wal", "-shm"].iter() {
+		let mut path = db_path.as_os_str().to_os_string();
+		path.push(extension);
+		let path = PathBuf::from(path);
+
+		if path.exists() {
+			let mut backup = path.as_os_str().to_os_string();
+			backup.push(&suffix);
+			fs::rename(&path, PathBuf::from(backup))?;
+		}
+	}
+
+	Ok(())
+}
@@ -0,0 +1,328 @@
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::{Certificate, ClientConfig, ClientConnection, PrivateKey, RootCertStore, ServerConfig, ServerConnection, ServerName, StreamOwned};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+use uuid::Uuid;
+
+use crate::config::Config;
+
+
+// A drone's certificate names it <drone id>.drone.swarm (as a DNS subject alternative name), that is
+// the identity the other drones know it by.
+pub const DRONE_DOMAIN: &str = "drone.swarm";
+
+pub fn drone_name(id: Uuid) -> String {
+	format!("{}.{}", id, DRONE_DOMAIN)
+}
+
+// The drone id a certificate was issued to, if it was issued to a drone.
+pub fn drone_id(cert: &Certificate) -> Option<Uuid> {
+	let cert = webpki::EndEntityCert::try_from(cert.0.as_slice()).ok()?;
+	let suffix = format!(".{}", DRONE_DOMAIN);
+
+	let id = cert.dns_names().ok()?
+		.map(<&str>::from)
+		.find_map(|name| name.strip_suffix(suffix.as_str()).and_then(|id| Uuid::parse_str(id).ok()));
+
+	id
+}
+
+// TLS between drones, with a certificate on both ends signed by the swarm's CA. Either end knows the
+// other's drone id from its certificate, see PeerStream::peer_id.
+pub struct Tls {
+	client:							Arc<ClientConfig>,
+	server:							Arc<ServerConfig>,
+}
+
+impl Tls {
+	// None unless tls_ca, tls_cert and tls_key are set (config validation makes sure it's all or none).
+	pub fn load(config: &Config) -> io::Result<Option<Self>> {
+		if config.tls_cert.as_os_str().is_empty() {
+			return Ok(None);
+		}
+
+		let ca = read_certs(&config.tls_ca)?;
+		let certs = read_certs(&config.tls_cert)?;
+		let key = read_key(&config.tls_key)?;
+
+		Tls::new(&ca, certs, key, config.id).map(Some)
+	}
+
+	// The drone's own certificate comes first in certs, and has to be issued to its id.
+	pub fn new(ca: &[Certificate], certs: Vec<Certificate>, key: PrivateKey, id: Uuid) -> io::Result<Self> {
+		match certs.first().and_then(drone_id) {
+			Some(cert_id) if cert_id == id => {},
+			Some(cert_id) => return Err(invalid(&format!("the certificate is issued to drone id = {}, not to this drone ({})", cert_id, id))),
+			None => return Err(invalid(&format!("the certificate is not issued to a drone, it needs {} as a DNS name", drone_name(id)))),
+		}
+
+		let mut roots = RootCertStore::empty();
+		for cert in ca.iter() {
+			roots.add(cert).map_err(|err| invalid(&format!("bad CA certificate: {}", err)))?;
+		}
+		if roots.is_empty() {
+			return Err(invalid("no CA certificate"));
+		}
+
+		let server = ServerConfig::builder()
+			.with_safe_defaults()
+			.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots.clone()).boxed())
+			.with_single_cert(certs.clone(), key.clone())
+			.map_err(|err| invalid(&err.to_string()))?;
+
+		let client = ClientConfig::builder()
+			.with_safe_defaults()
+			.with_custom_certificate_verifier(Arc::new(DroneVerifier { webpki: WebPkiVerifier::new(roots, None) }))
+			.with_client_auth_cert(certs, key)
+			.map_err(|err| invalid(&err.to_string()))?;
+
+		Ok(Tls {
+			client: Arc::new(client),
+			server: Arc::new(server),
+		})
+	}
+
+	// Run the client side of the handshake on a connection to another drone. When the caller knows which
+	// drone it means to reach, the certificate has to be issued to that one rather than to any drone.
+	pub fn connect(&self, mut stream: TcpStream, expected: Option<Uuid>) -> io::Result<PeerStream> {
+		let name = ServerName::try_from(DRONE_DOMAIN).map_err(|err| invalid(&err.to_string()))?;
+		let mut connection = ClientConnection::new(self.client.clone(), name).map_err(|err| invalid(&err.to_string()))?;
+		while connection.is_handshaking() {
+			connection.complete_io(&mut stream)?;
+		}
+
+		let peer = PeerStream::Client(Box::new(StreamOwned::new(connection, stream)));
+		match (expected, peer.peer_id()) {
+			(Some(expected), Some(peer_id)) if peer_id != expected => {
+				Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("the peer certificate is issued to drone id = {}, not to drone id = {}", peer_id, expected)))
+			},
+			_ => Ok(peer),
+		}
+	}
+
+	// Run the server side of the handshake on a connection from another drone, fails unless it shows a
+	// certificate signed by the CA and issued to a drone.
+	pub fn accept(&self, mut stream: TcpStream) -> io::Result<PeerStream> {
+		let mut connection = ServerConnection::new(self.server.clone()).map_err(|err| invalid(&err.to_string()))?;
+		while connection.is_handshaking() {
+			connection.complete_io(&mut stream)?;
+		}
+
+		let peer = PeerStream::Server(Box::new(StreamOwned::new(connection, stream)));
+		if peer.peer_id().is_none() {
+			return Err(io::Error::new(io::ErrorKind::PermissionDenied, "the peer certificate is not issued to a drone"));
+		}
+
+		Ok(peer)
+	}
+}
+
+// A connection between two drones, over TLS if it's configured.
+pub enum PeerStream {
+	Client(Box<StreamOwned<ClientConnection, TcpStream>>),
+	Plain(TcpStream),
+	Server(Box<StreamOwned<ServerConnection, TcpStream>>),
+}
+
+impl PeerStream {
+	// The drone id in the other end's certificate, None without TLS.
+	pub fn peer_id(&self) -> Option<Uuid> {
+		let certs = match self {
+			PeerStream::Client(stream) => stream.conn.peer_certificates(),
+			PeerStream::Plain(_) => None,
+			PeerStream::Server(stream) => stream.conn.peer_certificates(),
+		};
+
+		certs.and_then(|certs| certs.first()).and_then(drone_id)
+	}
+
+	pub fn tcp(&self) -> &TcpStream {
+		match self {
+			PeerStream::Client(stream) => &stream.sock,
+			PeerStream::Plain(stream) => stream,
+			PeerStream::Server(stream) => &stream.sock,
+		}
+	}
+
+	// Tell the other end that nothing more is coming, it reads up to here and sees the end of the data.
+	pub fn finish(&mut self) -> io::Result<()> {
+		match self {
+			PeerStream::Client(stream) => {
+				stream.conn.send_close_notify();
+				stream.flush()
+			},
+			PeerStream::Plain(stream) => stream.shutdown(Shutdown::Write),
+			PeerStream::Server(stream) => {
+				stream.conn.send_close_notify();
+				stream.flush()
+			},
+		}
+	}
+}
+
+impl Read for PeerStream {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		match self {
+			PeerStream::Client(stream) => stream.read(buf),
+			PeerStream::Plain(stream) => stream.read(buf),
+			PeerStream::Server(stream) => stream.read(buf),
+		}
+	}
+}
+
+impl Write for PeerStream {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		match self {
+			PeerStream::Client(stream) => stream.write(buf),
+			PeerStream::Plain(stream) => stream.write(buf),
+			PeerStream::Server(stream) => stream.write(buf),
+		}
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		match self {
+			PeerStream::Client(stream) => stream.flush(),
+			PeerStream::Plain(stream) => stream.flush(),
+			PeerStream::Server(stream) => stream.flush(),
+		}
+	}
+}
+
+// Checks the certificate of the drone a client connected to. The client usually only has an address, so
+// instead of a name given up front the certificate has to be issued to some drone, and signed by the CA.
+struct DroneVerifier {
+	webpki:							WebPkiVerifier,
+}
+
+impl ServerCertVerifier for DroneVerifier {
+	fn verify_server_cert(&self, end_entity: &Certificate, intermediates: &[Certificate], _server_name: &ServerName, scts: &mut dyn Iterator<Item = &[u8]>, ocsp_response: &[u8], now: SystemTime) -> Result<ServerCertVerified, rustls::Error> {
+		let id = drone_id(end_entity).ok_or_else(|| rustls::Error::General(String::from("the certificate is not issued to a drone")))?;
+		let name = ServerName::try_from(drone_name(id).as_str()).map_err(|err| rustls::Error::General(err.to_string()))?;
+
+		self.webpki.verify_server_cert(end_entity, intermediates, &name, scts, ocsp_response, now)
+	}
+}
+
+fn invalid(reason: &str) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidInput, reason)
+}
+
+fn read_certs(path: &Path) -> io::Result<Vec<Certificate>> {
+	let mut reader = BufReader::new(File::open(path).map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))?);
+	let certs = rustls_pemfile::certs(&mut reader)?;
+	if certs.is_empty() {
+		return Err(invalid(&format!("{}: no PEM certificate", path.display())));
+	}
+
+	Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn read_key(path: &Path) -> io::Result<PrivateKey> {
+	let mut reader = BufReader::new(File::open(path).map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))?);
+
+	loop {
+		match rustls_pemfile::read_one(&mut reader)? {
+			Some(rustls_pemfile::Item::PKCS8Key(key)) | Some(rustls_pemfile::Item::RSAKey(key)) | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+			Some(_) => {},
+			None => return Err(invalid(&format!("{}: no PEM private key", path.display()))),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::net::TcpListener;
+	use std::thread;
+
+	struct Pki {
+		ca:							rcgen::Certificate,
+	}
+
+	impl Pki {
+		fn new() -> Self {
+			let mut params = rcgen::CertificateParams::new(vec![]);
+			params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+
+			Pki { ca: rcgen::Certificate::from_params(params).unwrap() }
+		}
+
+		fn ca(&self) -> Vec<Certificate> {
+			vec![Certificate(self.ca.serialize_der().unwrap())]
+		}
+
+		fn tls(&self, cert_id: Uuid, id: Uuid) -> io::Result<Tls> {
+			let cert = rcgen::Certificate::from_params(rcgen::CertificateParams::new(vec![drone_name(cert_id)])).unwrap();
+			let der = cert.serialize_der_with_signer(&self.ca).unwrap();
+
+			Tls::new(&self.ca(), vec![Certificate(der)], PrivateKey(cert.serialize_private_key_der()), id)
+		}
+	}
+
+	// Connect a client to a server over localhost, the ids each end sees and whether data got across.
+	fn handshake(server: Tls, client: Tls, expected: Option<Uuid>) -> (io::Result<Option<Uuid>>, io::Result<Option<Uuid>>) {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let address = listener.local_addr().unwrap();
+
+		let handle = thread::spawn(move || {
+			let (stream, _) = listener.accept().unwrap();
+			server.accept(stream).and_then(|mut peer| {
+				let mut data = Vec::new();
+				peer.read_to_end(&mut data)?;
+				peer.write_all(&data)?;
+				peer.finish()?;
+				Ok(peer.peer_id())
+			})
+		});
+
+		let client = client.connect(TcpStream::connect(address).unwrap(), expected).and_then(|mut peer| {
+			peer.write_all(b"ping")?;
+			peer.finish()?;
+			let mut data = Vec::new();
+			peer.read_to_end(&mut data)?;
+			assert_eq!(data, b"ping");
+			Ok(peer.peer_id())
+		});
+
+		(handle.join().unwrap(), client)
+	}
+
+	#[test]
+	fn tls_handshake_test() {
+		let pki = Pki::new();
+		let (server_id, client_id) = (Uuid::new_v4(), Uuid::new_v4());
+
+		// Both ends know who they talk to.
+		let (server, client) = handshake(pki.tls(server_id, server_id).unwrap(), pki.tls(client_id, client_id).unwrap(), None);
+		assert_eq!(server.unwrap(), Some(client_id));
+		assert_eq!(client.unwrap(), Some(server_id));
+
+		// A client that knows whom it means to reach accepts only that drone's certificate.
+		let (_, client) = handshake(pki.tls(server_id, server_id).unwrap(), pki.tls(client_id, client_id).unwrap(), Some(server_id));
+		assert_eq!(client.unwrap(), Some(server_id));
+		let (_, client) = handshake(pki.tls(server_id, server_id).unwrap(), pki.tls(client_id, client_id).unwrap(), Some(Uuid::new_v4()));
+		assert_eq!(client.err().unwrap().kind(), io::ErrorKind::PermissionDenied);
+
+		// A drone's certificate has to be issued to it.
+		let err = pki.tls(client_id, server_id).err().unwrap();
+		assert!(err.to_string().contains("not to this drone"));
+	}
+
+	#[test]
+	fn tls_other_ca_test() {
+		let (pki, other) = (Pki::new(), Pki::new());
+		let (server_id, client_id) = (Uuid::new_v4(), Uuid::new_v4());
+
+		// Neither end accepts a certificate from another CA.
+		let (server, client) = handshake(pki.tls(server_id, server_id).unwrap(), other.tls(client_id, client_id).unwrap(), None);
+		assert!(server.is_err());
+		assert!(client.is_err());
+	}
+}
@@ -0,0 +1,312 @@
+use rusqlite::{Connection, NO_PARAMS};
+use std::fs;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+use swarm::db::{migrations, sql, Database, MemoryStore, Store};
+use swarm::models::{ArchiveQuery, Host, Job, JobQuery, JobStatus, Message, MessageDirection, MessageOutcome, MessageRecord, MessageType};
+
+// A fresh directory under the system temp dir, removed when dropped.
+struct TempDir(PathBuf);
+
+impl TempDir {
+	fn new() -> Self {
+		let dir = std::env::temp_dir().join(format!("swarm_db_test_{}", Uuid::new_v4()));
+		fs::create_dir_all(&dir).unwrap();
+
+		TempDir(dir)
+	}
+}
+
+impl Drop for TempDir {
+	fn drop(&mut self) {
+		let _ = fs::remove_dir_all(&self.0);
+	}
+}
+
+fn open(dir: &TempDir) -> Database {
+	Database::verify_or_init(Uuid::new_v4(), dir.0.clone(), PathBuf::from("drone.db")).unwrap()
+}
+
+fn schema_version(dir: &TempDir) -> u32 {
+	let conn = Connection::open(dir.0.join("drone.db")).unwrap();
+	conn.query_row(sql::SELECT_SCHEMA_VERSION, NO_PARAMS, |row| row.get(0)).unwrap()
+}
+
+// The store tests run against both backends, which must behave the same.
+fn each_store(check: impl Fn(&mut dyn Store)) {
+	let dir = TempDir::new();
+	check(&mut open(&dir));
+	check(&mut MemoryStore::new());
+}
+
+#[test]
+fn init_creates_latest_schema() {
+	let dir = TempDir::new();
+	drop(open(&dir));
+
+	assert_eq!(schema_version(&dir), migrations::latest_version());
+
+	let conn = Connection::open(dir.0.join("drone.db")).unwrap();
+	let statuses: i64 = conn.query_row("SELECT count(*) FROM job_status_enum;", NO_PARAMS, |row| row.get(0)).unwrap();
+	assert_eq!(statuses as usize, sql::JOB_STATUS_VALUES.len());
+
+	// Opening an up to date database again is a no-op.
+	drop(open(&dir));
+	assert_eq!(schema_version(&dir), migrations::latest_version());
+}
+
+#[test]
+fn host_round_trip() {
+	each_store(|db| {
+		let mut host = Host::new(Uuid::new_v4(), String::from("10.0.0.2"), String::from("9079"));
+		host.online();
+		db.update_host(&host).unwrap();
+
+		let stored = db.get_host(host.id).unwrap().unwrap();
+		assert_eq!(stored.address, "10.0.0.2");
+		assert_eq!(stored.port, "9079");
+		assert!(stored.online);
+
+		// A second update for the same id takes the ON CONFLICT path.
+		host.address = String::from("10.0.0.3");
+		host.offline();
+		db.update_host(&host).unwrap();
+
+		let stored = db.get_host(host.id).unwrap().unwrap();
+		assert_eq!(stored.address, "10.0.0.3");
+		assert!(!stored.online);
+
+		assert!(db.get_host(Uuid::new_v4()).unwrap().is_none());
+	});
+}
+
+#[test]
+fn job_and_ownership_round_trip() {
+	each_store(|db| {
+		let host = Host::new(Uuid::new_v4(), String::from("10.0.0.2"), String::from("9079"));
+		db.update_host(&host).unwrap();
+
+		let job = Job::new();
+		db.insert_job(&job).unwrap();
+		assert_eq!(db.get_job_status(job.id).unwrap(), Some(JobStatus::New));
+
+		db.update_job_status(job.id, JobStatus::Finished).unwrap();
+		assert_eq!(db.get_job_status(job.id).unwrap(), Some(JobStatus::Finished));
+
+		db.set_job_owner(host.id, job.id).unwrap();
+		db.set_job_owner(host.id, job.id).unwrap();
+		assert_eq!(db.job_owners(job.id).unwrap(), vec![host.id]);
+
+		db.remove_job_owner(host.id, job.id).unwrap();
+		assert!(db.job_owners(job.id).unwrap().is_empty());
+
+		// Ownership must reference a known drone and job.
+		assert!(db.set_job_owner(Uuid::new_v4(), job.id).is_err());
+		assert!(db.set_job_owner(host.id, Uuid::new_v4()).is_err());
+	});
+}
+
+#[test]
+fn search_jobs_filters_and_pages() {
+	each_store(|db| {
+		let host = Host::new(Uuid::new_v4(), String::from("10.0.0.2"), String::from("9079"));
+		db.update_host(&host).unwrap();
+
+		let mut jobs = Vec::new();
+		for i in 0..5 {
+			let mut job = Job::new();
+			job.tags.push(if i % 2 == 0 { String::from("even") } else { String::from("odd") });
+			db.insert_job(&job).unwrap();
+			jobs.push(job);
+		}
+
+		db.update_job_status(jobs[0].id, JobStatus::Finished).unwrap();
+		db.update_job_output(jobs[1].id, "compiling... error: disk full").unwrap();
+		db.set_job_owner(host.id, jobs[2].id).unwrap();
+
+		let search = |query: JobQuery| db.search_jobs(&query).unwrap();
+
+		let all = search(JobQuery::default());
+		assert_eq!(all.total, 5);
+		assert_eq!(all.jobs.len(), 5);
+
+		let finished = search(JobQuery { status: Some(JobStatus::Finished), ..Default::default() });
+		assert_eq!(finished.jobs.iter().map(|job| job.id).collect::<Vec<Uuid>>(), vec![jobs[0].id]);
+		assert!(finished.jobs[0].finished.is_some());
+
+		let even = search(JobQuery { tag: Some(String::from("even")), ..Default::default() });
+		assert_eq!(even.total, 3);
+		assert!(even.jobs.iter().all(|job| job.tags == vec![String::from("even")]));
+
+		let owned = search(JobQuery { drone: Some(host.id), ..Default::default() });
+		assert_eq!(owned.jobs.len(), 1);
+		assert_eq!(owned.jobs[0].owners, vec![host.id]);
+
+		let text = search(JobQuery { text: Some(String::from("disk full")), ..Default::default() });
+		assert_eq!(text.jobs[0].id, jobs[1].id);
+
+		let future = search(JobQuery { since: Some(String::from("2999-01-01 00:00:00")), ..Default::default() });
+		assert_eq!(future.total, 0);
+
+		// Pages don't overlap and together cover every match.
+		let first = search(JobQuery { limit: 3, ..Default::default() });
+		let second = search(JobQuery { limit: 3, offset: 3, ..Default::default() });
+		assert_eq!(first.jobs.len(), 3);
+		assert_eq!(second.jobs.len(), 2);
+		assert_eq!(second.total, 5);
+		assert!(second.jobs.iter().all(|job| !first.jobs.iter().any(|other| other.id == job.id)));
+	});
+}
+
+#[test]
+fn finished_jobs_are_archived_and_pruned() {
+	each_store(|db| {
+		let host = Host::new(Uuid::new_v4(), String::from("10.0.0.2"), String::from("9079"));
+		db.update_host(&host).unwrap();
+
+		let mut finished = Job::new();
+		finished.tags = vec![String::from("gpu"), String::from("render,fast")];
+		let working = Job::new();
+		let errored = Job::new();
+		for job in [&finished, &working, &errored].iter() {
+			db.insert_job(job).unwrap();
+		}
+
+		db.update_job_output(finished.id, "done").unwrap();
+		db.update_job_status(finished.id, JobStatus::Finished).unwrap();
+		db.set_job_owner(host.id, finished.id).unwrap();
+		db.update_job_status(working.id, JobStatus::Working).unwrap();
+		db.update_job_status(errored.id, JobStatus::Error).unwrap();
+
+		// Jobs that aren't done stay in the job table.
+		assert_eq!(db.archive_jobs(Some(working.id), None).unwrap(), 0);
+		assert_eq!(db.archive_jobs(None, Some("2000-01-01 00:00:00")).unwrap(), 0);
+		assert_eq!(db.archive_jobs(Some(errored.id), None).unwrap(), 1);
+		assert_eq!(db.archive_jobs(None, Some("2999-01-01 00:00:00")).unwrap(), 1);
+
+		assert_eq!(db.get_job_status(working.id).unwrap(), Some(JobStatus::Working));
+		assert_eq!(db.get_job_status(finished.id).unwrap(), None);
+		assert!(db.job_owners(finished.id).unwrap().is_empty());
+		assert!(db.job_tags(finished.id).unwrap().is_empty());
+
+		let archived = db.archived_jobs(&ArchiveQuery::default()).unwrap();
+		assert_eq!(archived.len(), 2);
+		let job = archived.iter().find(|job| job.id == finished.id).unwrap();
+		assert_eq!(job.status, JobStatus::Finished);
+		assert_eq!(job.output.as_deref(), Some("done"));
+		assert_eq!(job.owners, vec![host.id]);
+		assert_eq!(job.tags, vec![String::from("gpu"), String::from("render,fast")]);
+
+		let future = ArchiveQuery { since: Some(String::from("2999-01-01 00:00:00")), ..Default::default() };
+		assert!(db.archived_jobs(&future).unwrap().is_empty());
+
+		assert_eq!(db.prune_job_archive(365, 0).unwrap(), 0);
+		assert_eq!(db.prune_job_archive(0, 1).unwrap(), 1);
+		assert_eq!(db.archived_jobs(&ArchiveQuery::default()).unwrap().len(), 1);
+	});
+}
+
+#[test]
+fn message_archive_round_trip_and_retention() {
+	each_store(|db| {
+		let message = Message::new(Vec::new(), String::from("hello"), MessageType::Message);
+		let payload = bincode::serialize(&message).unwrap();
+
+		db.archive_message(&MessageRecord::new(MessageDirection::Received, String::from("10.0.0.2:40000"), b"garbage", MessageOutcome::Invalid)).unwrap();
+		db.archive_message(&MessageRecord::new(MessageDirection::Sent, String::from("10.0.0.2:9079"), &payload, MessageOutcome::Sent)).unwrap();
+
+		// Newest first, the payload is kept as sent so it can be replayed.
+		let records = db.archived_messages(10).unwrap();
+		assert_eq!(records.len(), 2);
+		assert_eq!(records[0].direction, MessageDirection::Sent);
+		assert_eq!(records[0].id, Some(message.id));
+		assert_eq!(records[0].message_type.as_deref(), Some("Message"));
+		assert_eq!(records[0].size, payload.len());
+		let replayed: Message = bincode::deserialize(&records[0].payload).unwrap();
+		assert_eq!(replayed.message, "hello");
+		assert_eq!(records[1].outcome, MessageOutcome::Invalid);
+		assert_eq!(records[1].id, None);
+
+		// Row limit keeps the newest messages.
+		assert_eq!(db.prune_messages(0, 1).unwrap(), 1);
+		assert_eq!(db.archived_messages(10).unwrap()[0].id, Some(message.id));
+
+		// Age limit.
+		let mut old = MessageRecord::new(MessageDirection::Received, String::from("10.0.0.3:40000"), &payload, MessageOutcome::Accepted);
+		old.timestamp = String::from("2000-01-01 00:00:00");
+		db.archive_message(&old).unwrap();
+		assert_eq!(db.prune_messages(7, 0).unwrap(), 1);
+		assert_eq!(db.archived_messages(10).unwrap().len(), 1);
+	});
+}
+
+#[test]
+fn unversioned_database_is_migrated() {
+	let dir = TempDir::new();
+	let job_id = Uuid::new_v4();
+
+	// A database as created by drones from before schema versioning.
+	{
+		let conn = Connection::open(dir.0.join("drone.db")).unwrap();
+		for create_table_stmt in sql::CREATE_TABLES.iter() {
+			conn.execute(create_table_stmt, NO_PARAMS).unwrap();
+		}
+		for job_status in sql::JOB_STATUS_VALUES.iter() {
+			conn.execute(sql::INSERT_JOB_STATUS_VALUES, &[job_status]).unwrap();
+		}
+		conn.execute(sql::INSERT_DATABASE_VERSION, &["0.1.2"]).unwrap();
+		conn.execute("INSERT INTO job (id, status) VALUES(?1, 'Working');", &[job_id.to_string()]).unwrap();
+	}
+
+	let db = open(&dir);
+	assert_eq!(schema_version(&dir), migrations::latest_version());
+	assert_eq!(db.get_job_status(job_id).unwrap(), Some(JobStatus::Working));
+
+	// The version 1 database was backed up before migrating.
+	let backups = fs::read_dir(&dir.0).unwrap()
+		.filter_map(|entry| entry.ok())
+		.filter(|entry| entry.file_name().to_string_lossy().starts_with("drone.db.v1-"))
+		.count();
+	assert_eq!(backups, 1);
+}
+
+#[test]
+fn comma_separated_archive_is_migrated() {
+	let dir = TempDir::new();
+	let (job_id, owner) = (Uuid::new_v4(), Uuid::new_v4());
+
+	// Archived owners and tags as written before migration 6.
+	drop(open(&dir));
+	{
+		let conn = Connection::open(dir.0.join("drone.db")).unwrap();
+		conn.execute(
+			"INSERT INTO job_archive (created, finished, id, owners, status, tags) VALUES('2020-10-10 10:10:10', '2020-10-10 10:10:11', ?1, ?2, 'Finished', 'gpu,say \"hi\"');",
+			&[job_id.to_string(), owner.to_string()],
+		).unwrap();
+		conn.execute("INSERT INTO job_archive (created, id, status) VALUES('2020-10-10 10:10:10', ?1, 'Error');", &[Uuid::new_v4().to_string()]).unwrap();
+		conn.execute("PRAGMA user_version = 5;", NO_PARAMS).unwrap();
+	}
+
+	let db = open(&dir);
+	assert_eq!(schema_version(&dir), migrations::latest_version());
+
+	let archived = db.archived_jobs(&ArchiveQuery::default()).unwrap();
+	let job = archived.iter().find(|job| job.id == job_id).unwrap();
+	assert_eq!(job.owners, vec![owner]);
+	assert_eq!(job.tags, vec![String::from("gpu"), String::from("say \"hi\"")]);
+	assert!(archived.iter().any(|job| job.owners.is_empty() && job.tags.is_empty()));
+}
+
+#[test]
+fn partial_database_is_rejected() {
+	let dir = TempDir::new();
+
+	{
+		let conn = Connection::open(dir.0.join("drone.db")).unwrap();
+		conn.execute(sql::CREATE_TABLE_DRONE, NO_PARAMS).unwrap();
+	}
+
+	let result = Database::verify_or_init(Uuid::new_v4(), dir.0.clone(), PathBuf::from("drone.db"));
+	assert!(matches!(result, Err(swarm::db::DatabaseError::Corruption(_))));
+}
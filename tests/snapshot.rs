@@ -0,0 +1,85 @@
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+use swarm::config::Config;
+use swarm::db::{Database, Store};
+use swarm::models::{Job, JobStatus};
+use swarm::snapshot;
+
+// A fresh directory under the system temp dir, removed when dropped.
+struct TempDir(PathBuf);
+
+impl TempDir {
+	fn new() -> Self {
+		let dir = std::env::temp_dir().join(format!("swarm_snapshot_test_{}", Uuid::new_v4()));
+		fs::create_dir_all(&dir).unwrap();
+
+		TempDir(dir)
+	}
+}
+
+impl Drop for TempDir {
+	fn drop(&mut self) {
+		let _ = fs::remove_dir_all(&self.0);
+	}
+}
+
+fn open(config: &Config) -> Database {
+	Database::verify_or_init(config.id, config.db_dir.clone(), config.db_file.clone()).unwrap()
+}
+
+// The permission bits of a file, config files hold the swarm_secret and are kept to their owner.
+fn mode(path: &Path) -> u32 {
+	fs::metadata(path).unwrap().permissions().mode() & 0o777
+}
+
+#[test]
+fn snapshot_create_and_restore() {
+	let dir = TempDir::new();
+	let config = Config {
+		db_dir: dir.0.join("db"),
+		id: Uuid::new_v4(),
+		swarm_secret: String::from("0123456789abcdef"),
+		..Default::default()
+	};
+
+	let job = Job::new();
+	{
+		let mut db = open(&config);
+		db.insert_job(&job).unwrap();
+		db.update_job_status(job.id, JobStatus::Working).unwrap();
+
+		let manifest = snapshot::create(&db, &config, &dir.0.join("snap")).unwrap();
+		assert_eq!(manifest.drone_id, config.id);
+		assert_eq!(mode(&dir.0.join("snap").join(snapshot::CONFIG_FILE)), 0o600);
+
+		// Snapshots are only written to empty directories.
+		assert!(snapshot::create(&db, &config, &dir.0.join("snap")).is_err());
+	}
+
+	// Lose the drone, then rebuild it from the snapshot.
+	fs::remove_dir_all(&config.db_dir).unwrap();
+	let config_file = dir.0.join("etc").join("drone.cfg.toml");
+	let manifest = snapshot::restore(&dir.0.join("snap"), &config_file, false).unwrap();
+	assert_eq!(manifest.drone_id, config.id);
+
+	let restored = Config::load(&config_file, &[]).unwrap();
+	assert_eq!(restored.id, config.id);
+	assert_eq!(restored.swarm_secret, config.swarm_secret);
+	assert_eq!(mode(&config_file), 0o600);
+	assert_eq!(restored.db_dir, config.db_dir);
+	assert_eq!(fs::read_to_string(restored.id_file()).unwrap().trim(), config.id.to_string());
+	assert!(restored.db_dir.join(snapshot::RESTORED_MARKER).exists());
+	assert_eq!(open(&restored).get_job_status(job.id).unwrap(), Some(JobStatus::Working));
+
+	// Existing files are only replaced with force, the old database is kept.
+	assert!(snapshot::restore(&dir.0.join("snap"), &config_file, false).is_err());
+	snapshot::restore(&dir.0.join("snap"), &config_file, true).unwrap();
+	let set_aside = fs::read_dir(&restored.db_dir).unwrap()
+		.filter_map(|entry| entry.ok())
+		.filter(|entry| entry.file_name().to_string_lossy().starts_with("drone.db.pre-restore-"))
+		.count();
+	assert!(set_aside >= 1);
+}